@@ -11,11 +11,19 @@ from typing import TypedDict
 
 import argparse
 
+PROTOCOL_VERSION = 2
+
 class Response(TypedDict):
     type: str # "response", "control"
     value: str
 
 
+def write_msg(writer, msg):
+    msg = json.dumps(msg).encode()
+    writer.write(struct.pack("!i", len(msg)))
+    writer.write(msg)
+
+
 async def client_connected_cb(reader, writer):
     while True:
         print("!");
@@ -39,12 +47,17 @@ async def client_connected_cb(reader, writer):
         except json.decoder.JSONDecodeError:
             break
 
+        if line["type"] in ("query", "start"):
+            write_msg(writer, {"type": "hello", "version": PROTOCOL_VERSION})
+
         if line["type"] == "query":
             response = "A simple echo app"
         elif line["type"] == "start":
             response = "Started echo!"
-        else:
+        elif line["type"] == "msg":
             response = line["data"]
+        else:
+            continue
 
         response = json.dumps(
             Response({"type": "response", "value": response})
@@ -0,0 +1,25 @@
+# The echo app over stdio: set "transport": "stdio" and
+# "command": ["python3", "echo_stdio.py"] for it under "apps" in the config.
+import json
+import struct
+import sys
+
+
+def read_msg():
+    length = sys.stdin.buffer.read(4)
+    if len(length) < 4:
+        return None
+    return json.loads(sys.stdin.buffer.read(struct.unpack("!I", length)[0]))
+
+
+def write_msg(msg):
+    msg = json.dumps(msg).encode()
+    sys.stdout.buffer.write(struct.pack("!I", len(msg)) + msg)
+    sys.stdout.buffer.flush()
+
+
+replies = {"query": "A simple echo app", "start": "Started echo!"}
+while (msg := read_msg()) is not None:
+    reply = msg["data"] if msg["type"] == "msg" else replies.get(msg["type"])
+    if reply is not None:
+        write_msg({"type": "response", "value": reply})
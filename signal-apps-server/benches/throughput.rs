@@ -0,0 +1,162 @@
+//! How many messages the server gets through with many users at once, with
+//! and without one user stuck on a slow app. Run with `cargo bench`.
+use std::io;
+use std::time::Instant;
+
+use async_trait::async_trait;
+use tokio::sync::mpsc;
+use tokio::time::{sleep, Duration};
+
+use signal_apps::app::App;
+use signal_apps::appstate::{AppMsg, AppState};
+use signal_apps::comm::{SendError, Sender};
+use signal_apps::protocol::ToApp;
+
+const USERS: usize = 500;
+const MSGS_PER_USER: usize = 20;
+const SLOW_USER: &str = "+slow";
+
+/// How long the slow app takes to answer a message
+const SLOW_REPLY: Duration = Duration::from_millis(100);
+
+/// Reports every message sent to a user other than SLOW_USER
+struct CountingSender {
+    sent: mpsc::UnboundedSender<()>,
+}
+
+#[async_trait]
+impl Sender for CountingSender {
+    async fn send(&self, dest: &str, _msg: &str) -> Result<(), SendError> {
+        if dest != SLOW_USER {
+            let _ = self.sent.send(());
+        }
+        Ok(())
+    }
+}
+
+/// Echoes every message straight away, unless it is called `slow`
+struct EchoApp {
+    id: u64,
+    name: String,
+    user: String,
+    control: mpsc::Sender<AppMsg>,
+}
+
+#[async_trait]
+impl App for EchoApp {
+    type Shared = ();
+
+    async fn get_description(
+        _shared: &(),
+        _app_dir: &str,
+        name: &str,
+    ) -> io::Result<String> {
+        Ok(format!("{} app", name))
+    }
+
+    fn new(
+        id: u64,
+        name: &str,
+        user: &str,
+        control: mpsc::Sender<AppMsg>,
+    ) -> Self {
+        EchoApp {
+            id,
+            name: name.into(),
+            user: user.into(),
+            control,
+        }
+    }
+
+    fn get_id(&self) -> u64 {
+        self.id
+    }
+
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    async fn start(
+        &mut self,
+        _shared: &(),
+        _app_dir: &str,
+        _name: &str,
+    ) -> io::Result<()> {
+        Ok(())
+    }
+
+    async fn send(&mut self, msg: &ToApp) {
+        if let ToApp::Msg { data, .. } = msg {
+            if self.name == "slow" {
+                sleep(SLOW_REPLY).await;
+            }
+            let reply = AppMsg::OutMsg(
+                self.user.clone(),
+                self.id,
+                data.clone(),
+                vec![],
+            );
+            let _ = self.control.send(reply).await;
+        }
+    }
+
+    async fn stop(&mut self) {}
+}
+
+fn in_msg(source: &str, msg: &str) -> AppMsg {
+    AppMsg::InMsg(source.into(), source.into(), msg.into(), vec![])
+}
+
+/// Have USERS users each send MSGS_PER_USER messages to echo, and return how
+/// many replies per second they got back.
+async fn run(slow_user: bool) -> f64 {
+    let (sent, mut replies) = mpsc::unbounded_channel();
+    let config = serde_json::json!({ "appdir": "/tmp" });
+    let (mut state, queue): (AppState<EchoApp, _>, _) =
+        AppState::new(config, CountingSender { sent });
+    let server = tokio::spawn(async move { state.process_queue().await });
+
+    let start = Instant::now();
+    let users: Vec<String> = (0..USERS).map(|i| format!("+{}", i)).collect();
+    if slow_user {
+        queue
+            .send(in_msg(SLOW_USER, "startapp slow"))
+            .await
+            .unwrap();
+    }
+    for user in &users {
+        queue.send(in_msg(user, "startapp echo")).await.unwrap();
+    }
+    let input = tokio::spawn(async move {
+        for i in 0..MSGS_PER_USER {
+            if slow_user {
+                queue.send(in_msg(SLOW_USER, "hello")).await.unwrap();
+            }
+            for user in &users {
+                let msg = format!("message {}", i);
+                queue.send(in_msg(user, &msg)).await.unwrap();
+            }
+        }
+        queue
+    });
+
+    let total = USERS * MSGS_PER_USER;
+    for _ in 0..total {
+        replies.recv().await.expect("server went away");
+    }
+    let elapsed = start.elapsed();
+
+    let queue = input.await.unwrap();
+    queue.send(AppMsg::Finish).await.unwrap();
+    server.await.unwrap();
+    total as f64 / elapsed.as_secs_f64()
+}
+
+#[tokio::main]
+async fn main() {
+    for (name, slow_user) in [("all fast apps", false), ("one slow app", true)]
+    {
+        let rate = run(slow_user).await;
+        println!("{} users, {}: {:.0} msgs/s", USERS, name, rate);
+    }
+}
@@ -0,0 +1,50 @@
+//! The echo app from `signal-apps-clients/echo/echo.py`, built on this crate.
+//!
+//!     cargo run -p signal-apps-client --example echo -- config.json
+use std::env;
+use std::io;
+use std::process;
+
+use async_trait::async_trait;
+use signal_apps_client::{AppServer, AppSocket, FromApp, Handler, Message};
+
+/// Replies longer than this are refused, like the python version does
+const MAX_REPLY: usize = 1024;
+
+struct Echo;
+
+#[async_trait]
+impl Handler for Echo {
+    type Session = ();
+
+    fn description(&self) -> String {
+        "A simple echo app".into()
+    }
+
+    async fn start(&self, _user: &str, _group: bool) -> ((), Vec<FromApp>) {
+        ((), vec![FromApp::response("Started echo!")])
+    }
+
+    async fn msg(&self, _: &mut (), msg: &Message) -> Vec<FromApp> {
+        let reply = FromApp::response(&msg.data);
+        if reply.encode().len() > MAX_REPLY {
+            return vec![FromApp::response("Sorry that's too long!")];
+        }
+        vec![reply]
+    }
+}
+
+#[tokio::main]
+async fn main() -> io::Result<()> {
+    let config = match env::args().nth(1) {
+        Some(config) => config,
+        None => {
+            eprintln!("Usage: echo <config.json>");
+            process::exit(1);
+        }
+    };
+
+    let socket = AppSocket::from_config(&config, "echo")?;
+    println!("{}", socket.path().display());
+    AppServer::new(Echo).configure(&config)?.run(socket).await
+}
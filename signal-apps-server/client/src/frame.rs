@@ -0,0 +1,151 @@
+use std::io;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// The largest frame the server accepts from an app unless `maxframesize` is
+/// set in its config, and the largest `AppServer` reads unless told otherwise
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 16 << 20;
+
+fn invalid_data<E>(e: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+fn check_size(size: usize, max: usize) -> io::Result<()> {
+    if size > max {
+        return Err(invalid_data(format!(
+            "frame of {} bytes is larger than the maximum of {}",
+            size, max
+        )));
+    }
+    Ok(())
+}
+
+/// The next frame of at most `max` bytes, or None once the stream closed
+/// between two frames. Every frame is a 4 byte big endian length followed by
+/// that many bytes of utf-8.
+pub async fn read_frame<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    max: usize,
+) -> io::Result<Option<String>> {
+    let mut prefix = [0; 4];
+    let mut received = 0;
+    while received < prefix.len() {
+        match reader.read(&mut prefix[received..]).await? {
+            0 if received == 0 => return Ok(None),
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => received += n,
+        }
+    }
+
+    let size = u32::from_be_bytes(prefix) as usize;
+    check_size(size, max)?;
+    let mut frame = vec![0; size];
+    reader.read_exact(&mut frame).await?;
+    String::from_utf8(frame).map(Some).map_err(invalid_data)
+}
+
+/// The next frame parsed as json, or None once the stream closed
+pub async fn read_msg<R, T>(reader: &mut R, max: usize) -> io::Result<Option<T>>
+where
+    R: AsyncRead + Unpin,
+    T: DeserializeOwned,
+{
+    match read_frame(reader, max).await? {
+        Some(frame) => {
+            serde_json::from_str(&frame).map(Some).map_err(invalid_data)
+        }
+        None => Ok(None),
+    }
+}
+
+/// Write `msg` to `writer` as a single frame, of at most `max` bytes
+pub async fn write_frame<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    msg: &str,
+    max: usize,
+) -> io::Result<()> {
+    check_size(msg.len(), max)?;
+    let mut frame = (msg.len() as u32).to_be_bytes().to_vec();
+    frame.extend_from_slice(msg.as_bytes());
+    writer.write_all(&frame).await?;
+    writer.flush().await
+}
+
+/// Write `msg` to `writer` as a json frame, of at most `max` bytes
+pub async fn write_msg<W, T>(
+    writer: &mut W,
+    msg: &T,
+    max: usize,
+) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+    T: Serialize,
+{
+    let msg = serde_json::to_string(msg).map_err(invalid_data)?;
+    write_frame(writer, &msg, max).await
+}
+
+#[cfg(test)]
+mod test {
+    use signal_apps::frame::{self as server, FrameCodec, FrameReader};
+
+    use super::*;
+
+    const MAX: usize = DEFAULT_MAX_FRAME_SIZE;
+
+    #[tokio::test]
+    async fn test_server_reads_frames() {
+        let mut stream = vec![];
+        write_frame(&mut stream, "hello", MAX).await.unwrap();
+        write_frame(&mut stream, "", MAX).await.unwrap();
+        write_msg(&mut stream, &serde_json::json!({"type": "response"}), MAX)
+            .await
+            .unwrap();
+
+        let mut reader = FrameReader::new(&stream[..], FrameCodec::default());
+        assert_eq!(Some("hello".into()), reader.read_frame().await.unwrap());
+        assert_eq!(Some("".into()), reader.read_frame().await.unwrap());
+        let msg: serde_json::Value = reader.read_msg().await.unwrap().unwrap();
+        assert_eq!("response", msg["type"]);
+        assert!(reader.read_frame().await.unwrap().is_none());
+
+        assert!(write_frame(&mut vec![], "too long", 7).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_server_frames() {
+        let mut stream = vec![];
+        server::write_frame(&mut stream, "hello").await.unwrap();
+        server::write_frame(&mut stream, "not json").await.unwrap();
+        server::write_frame(&mut stream, "[]").await.unwrap();
+        let mut reader = &stream[..];
+
+        assert_eq!(
+            Some("hello".into()),
+            read_frame(&mut reader, MAX).await.unwrap()
+        );
+        let msg = read_msg::<_, serde_json::Value>(&mut reader, MAX).await;
+        assert!(msg.is_err());
+        let msg: serde_json::Value =
+            read_msg(&mut reader, MAX).await.unwrap().unwrap();
+        assert_eq!(serde_json::json!([]), msg);
+        assert!(read_frame(&mut reader, MAX).await.unwrap().is_none());
+
+        // Frames are checked the same way the server checks them
+        let mut oversize = &((MAX + 1) as u32).to_be_bytes()[..];
+        assert!(read_frame(&mut oversize, MAX).await.is_err());
+        let mut oversize = &[0, 0, 0, 5, b'h', b'e', b'l', b'l', b'o'][..];
+        assert!(read_frame(&mut oversize, 4).await.is_err());
+        let mut truncated = &[0, 0, 0, 5, b'h', b'i'][..];
+        assert!(read_frame(&mut truncated, MAX).await.is_err());
+        let mut truncated = &[0, 0][..];
+        assert!(read_frame(&mut truncated, MAX).await.is_err());
+        let mut invalid = &[0, 0, 0, 2, 0xff, 0xfe][..];
+        assert!(read_frame(&mut invalid, MAX).await.is_err());
+    }
+}
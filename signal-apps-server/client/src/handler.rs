@@ -0,0 +1,415 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use tokio::io::{split, AsyncRead, AsyncWrite};
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::frame::{read_frame, write_msg, DEFAULT_MAX_FRAME_SIZE};
+use crate::protocol::{Attachment, FromApp, ToApp, VERSION};
+use crate::socket::{read_config, AppSocket};
+
+/// A message from the user of a session, or anyone in it for a group
+#[derive(Clone, Debug, PartialEq)]
+pub struct Message {
+    pub author: String,
+    pub data: String,
+    pub attachments: Vec<Attachment>,
+}
+
+/// What an app does. Every session gets its own `Session` state, and
+/// whatever a method returns is sent back tagged with the right session.
+#[async_trait]
+pub trait Handler: Send + Sync + 'static {
+    /// The state kept for each session
+    type Session: Send;
+
+    /// What `listapps` shows for the app
+    fn description(&self) -> String;
+
+    /// A user started the app, returns the new session and the replies
+    async fn start(
+        &self,
+        user: &str,
+        group: bool,
+    ) -> (Self::Session, Vec<FromApp>);
+
+    async fn msg(
+        &self,
+        session: &mut Self::Session,
+        msg: &Message,
+    ) -> Vec<FromApp>;
+
+    /// A `send` to `to` could not be delivered
+    async fn undelivered(
+        &self,
+        _session: &mut Self::Session,
+        _to: &str,
+        _error: &str,
+    ) -> Vec<FromApp> {
+        vec![]
+    }
+
+    /// The session ended, either side may have ended it
+    async fn close(&self, _session: Self::Session) {}
+}
+
+/// Serves a `Handler` to the server, on as many connections as it opens
+pub struct AppServer<H> {
+    handler: Arc<H>,
+    multiplex: bool,
+    max_frame_size: usize,
+}
+
+impl<H> Clone for AppServer<H> {
+    fn clone(&self) -> Self {
+        AppServer {
+            handler: self.handler.clone(),
+            multiplex: self.multiplex,
+            max_frame_size: self.max_frame_size,
+        }
+    }
+}
+
+/// The sessions on one connection. Outside of multiplexed mode a connection
+/// carries a single session, which has no id.
+type Sessions<S> = HashMap<Option<u64>, S>;
+
+impl<H: Handler> AppServer<H> {
+    pub fn new(handler: H) -> Self {
+        AppServer {
+            handler: Arc::new(handler),
+            multiplex: false,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+        }
+    }
+
+    /// Read and write frames of up to `max` bytes. This should match
+    /// `maxframesize` in the server's config.
+    pub fn max_frame_size(mut self, max: usize) -> Self {
+        self.max_frame_size = max;
+        self
+    }
+
+    /// Take the settings the server's config has for apps
+    pub fn configure<P: AsRef<Path>>(self, config: P) -> io::Result<Self> {
+        match read_config(config)?["maxframesize"].as_u64() {
+            Some(max) => Ok(self.max_frame_size(max as usize)),
+            None => Ok(self),
+        }
+    }
+
+    /// Ask the server to carry every session over a single connection
+    pub fn multiplex(mut self, multiplex: bool) -> Self {
+        self.multiplex = multiplex;
+        self
+    }
+
+    /// Serve on `socket` until SIGINT or SIGTERM, then remove the socket
+    pub async fn run(&self, socket: AppSocket) -> io::Result<()> {
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            result = self.serve(&socket) => result,
+            _ = tokio::signal::ctrl_c() => Ok(()),
+            _ = terminate.recv() => Ok(()),
+        }
+    }
+
+    /// Serve every connection made to `socket`
+    pub async fn serve(&self, socket: &AppSocket) -> io::Result<()> {
+        loop {
+            let stream = socket.accept().await?;
+            let server = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = server.serve_connection(stream).await {
+                    eprintln!("Connection failed: {}", e);
+                }
+            });
+        }
+    }
+
+    /// Serve a single connection until the server closes it. Any sessions
+    /// still open then are closed.
+    pub async fn serve_connection<S>(&self, stream: S) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Send,
+    {
+        let (mut reader, mut writer) = split(stream);
+        let mut sessions = Sessions::new();
+        let result = async {
+            let max = self.max_frame_size;
+            while let Some(frame) = read_frame(&mut reader, max).await? {
+                let msg = match serde_json::from_str(&frame) {
+                    Ok(msg) => msg,
+                    Err(e) => {
+                        eprintln!("Ignoring message {:?}: {}", frame, e);
+                        continue;
+                    }
+                };
+                for reply in self.handle(&mut sessions, msg).await {
+                    write_msg(&mut writer, &reply, max).await?;
+                }
+            }
+            Ok(())
+        }
+        .await;
+
+        for (_, session) in sessions.drain() {
+            self.handler.close(session).await;
+        }
+        result
+    }
+
+    async fn handle(
+        &self,
+        sessions: &mut Sessions<H::Session>,
+        msg: ToApp,
+    ) -> Vec<FromApp> {
+        let id = msg.session();
+        let replies = match msg {
+            ToApp::Query { .. } => {
+                return vec![
+                    FromApp::Hello {
+                        version: VERSION,
+                        multiplex: self.multiplex,
+                    },
+                    FromApp::response(&self.handler.description()),
+                ];
+            }
+            ToApp::Start { user, group, .. } => {
+                let (session, replies) = self.handler.start(&user, group).await;
+                if let Some(old) = sessions.insert(id, session) {
+                    self.handler.close(old).await;
+                }
+                replies
+            }
+            ToApp::Msg {
+                author,
+                data,
+                attachments,
+                ..
+            } => {
+                let msg = Message {
+                    author,
+                    data,
+                    attachments,
+                };
+                match sessions.get_mut(&id) {
+                    Some(session) => self.handler.msg(session, &msg).await,
+                    None => vec![],
+                }
+            }
+            ToApp::Undelivered { to, error, .. } => {
+                match sessions.get_mut(&id) {
+                    Some(session) => {
+                        self.handler.undelivered(session, &to, &error).await
+                    }
+                    None => vec![],
+                }
+            }
+            ToApp::Close { .. } => {
+                if let Some(session) = sessions.remove(&id) {
+                    self.handler.close(session).await;
+                }
+                vec![]
+            }
+        };
+
+        let ended = replies
+            .iter()
+            .any(|reply| matches!(reply, FromApp::Terminate { .. }));
+        if ended {
+            if let Some(session) = sessions.remove(&id) {
+                self.handler.close(session).await;
+            }
+        }
+        replies
+            .into_iter()
+            .map(|reply| reply.with_session(id))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use signal_apps::app::{App, UnixStreamApp};
+    use signal_apps::appstate::AppMsg;
+    use signal_apps::frame::{write_frame, FrameCodec, FrameReader};
+    use signal_apps::mux::MuxRegistry;
+    use signal_apps::protocol as server;
+    use tempdir::TempDir;
+    use tokio::io::AsyncWriteExt;
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    const SOURCE: &str = "+15555555";
+
+    /// Counts the messages in each session, and ends it on "bye"
+    #[derive(Default)]
+    struct Counter {
+        closed: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl Handler for Counter {
+        type Session = usize;
+
+        fn description(&self) -> String {
+            "Counts messages".into()
+        }
+
+        async fn start(&self, user: &str, _: bool) -> (usize, Vec<FromApp>) {
+            (0, vec![FromApp::response(&format!("Hi {}", user))])
+        }
+
+        async fn msg(&self, count: &mut usize, msg: &Message) -> Vec<FromApp> {
+            *count += 1;
+            if msg.data == "bye" {
+                return vec![FromApp::terminate("bye")];
+            }
+            vec![FromApp::response(&count.to_string())]
+        }
+
+        async fn close(&self, _: usize) {
+            self.closed.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    async fn reply(recv: &mut mpsc::Receiver<AppMsg>) -> (u64, String) {
+        match recv.recv().await {
+            Some(AppMsg::OutMsg(user, id, msg, _)) => {
+                assert_eq!(SOURCE, user);
+                (id, msg)
+            }
+            msg => panic!("Unexpected control msg {:?}", msg),
+        }
+    }
+
+    // Run the app against the server's own client side of the protocol
+    async fn test_sessions(multiplex: bool) {
+        let tmp_dir = TempDir::new("apps").expect("create tempdir failed!");
+        let app_dir = tmp_dir.path().to_str().unwrap();
+        let socket = AppSocket::for_app(app_dir, "counter").unwrap();
+        let server = AppServer::new(Counter::default()).multiplex(multiplex);
+        let handler = server.handler.clone();
+        let serving = server.clone();
+        tokio::spawn(async move { serving.serve(&socket).await });
+
+        let shared = MuxRegistry::default();
+        assert_eq!(
+            "Counts messages",
+            UnixStreamApp::get_description(&shared, app_dir, "counter")
+                .await
+                .unwrap()
+        );
+        assert_eq!(multiplex, shared.is_multiplexed("counter").await);
+
+        let (control, mut recv) = mpsc::channel(100);
+        let mut apps = vec![];
+        for id in 0..2 {
+            let mut app =
+                UnixStreamApp::new(id, "counter", SOURCE, control.clone());
+            app.start(&shared, app_dir, "counter").await.unwrap();
+            app.send(&server::ToApp::start(SOURCE, false)).await;
+            assert_eq!((id, format!("Hi {}", SOURCE)), reply(&mut recv).await);
+            apps.push(app);
+        }
+
+        // Each session counts on its own
+        let hi = server::ToApp::msg(SOURCE, "hi");
+        apps[0].send(&hi).await;
+        assert_eq!((0, "1".into()), reply(&mut recv).await);
+        apps[0].send(&hi).await;
+        assert_eq!((0, "2".into()), reply(&mut recv).await);
+        apps[1].send(&hi).await;
+        assert_eq!((1, "1".into()), reply(&mut recv).await);
+
+        apps[1].send(&server::ToApp::msg(SOURCE, "bye")).await;
+        match recv.recv().await {
+            Some(AppMsg::EndMsg(_, 1, _)) => {}
+            msg => panic!("Unexpected control msg {:?}", msg),
+        }
+        assert_eq!(1, handler.closed.load(Ordering::SeqCst));
+
+        apps[0].stop().await;
+        drop(apps);
+        while handler.closed.load(Ordering::SeqCst) < 2 {
+            tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_single_sessions() {
+        test_sessions(false).await;
+    }
+
+    #[tokio::test]
+    async fn test_multiplexed_sessions() {
+        test_sessions(true).await;
+    }
+
+    #[tokio::test]
+    async fn test_malformed_messages() {
+        let (app, mut stream) = tokio::io::duplex(1024);
+        let serving = tokio::spawn(async move {
+            AppServer::new(Counter::default())
+                .serve_connection(app)
+                .await
+        });
+
+        write_frame(&mut stream, "not json").await.unwrap();
+        write_frame(&mut stream, &server::ToApp::query().encode())
+            .await
+            .unwrap();
+        let mut reader = FrameReader::new(&mut stream, FrameCodec::default());
+        let hello: server::FromApp = reader.read_msg().await.unwrap().unwrap();
+        assert!(matches!(
+            hello,
+            server::FromApp::Hello {
+                version: VERSION,
+                multiplex: false
+            }
+        ));
+        drop(reader);
+
+        // A frame the server would never send ends the connection
+        stream.write_all(&u32::MAX.to_be_bytes()).await.unwrap();
+        assert!(serving.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_configured_frame_size() {
+        let tmp_dir = TempDir::new("apps").expect("create tempdir failed!");
+        let config = tmp_dir.path().join("config.json");
+        std::fs::write(&config, r#"{"maxframesize": 64}"#).unwrap();
+        let server = AppServer::new(Counter::default());
+        assert_eq!(DEFAULT_MAX_FRAME_SIZE, server.max_frame_size);
+        let server = server.configure(&config).unwrap();
+        assert_eq!(64, server.max_frame_size);
+
+        let (app, mut stream) = tokio::io::duplex(1024);
+        let serving =
+            tokio::spawn(async move { server.serve_connection(app).await });
+        let codec = FrameCodec::new(64);
+        codec
+            .write(&mut stream, &server::ToApp::query().encode())
+            .await
+            .unwrap();
+        let mut reader = FrameReader::new(&mut stream, codec);
+        let hello: server::FromApp = reader.read_msg().await.unwrap().unwrap();
+        assert!(matches!(hello, server::FromApp::Hello { .. }));
+        drop(reader);
+
+        let long = server::ToApp::msg(SOURCE, &"x".repeat(64));
+        FrameCodec::default()
+            .write(&mut stream, &long.encode())
+            .await
+            .unwrap();
+        assert!(serving.await.unwrap().is_err());
+    }
+}
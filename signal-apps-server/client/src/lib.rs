@@ -0,0 +1,12 @@
+//! Everything an app needs to talk to the signal-apps server: the socket in
+//! the app directory, framing, the protocol's messages and a loop that keeps
+//! the state of each session. An app only implements `Handler`, see
+//! `examples/echo.rs`.
+pub mod frame;
+pub mod handler;
+pub mod protocol;
+pub mod socket;
+
+pub use handler::{AppServer, Handler, Message};
+pub use protocol::{FromApp, ToApp};
+pub use socket::AppSocket;
@@ -0,0 +1,215 @@
+use serde::{Deserialize, Serialize};
+
+/// The protocol version spoken by apps built on this crate
+pub const VERSION: u32 = 7;
+
+/// A file received from a user. `path` is where the server saved it locally.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Attachment {
+    pub content_type: String,
+    pub filename: Option<String>,
+    pub size: Option<u64>,
+    pub path: String,
+}
+
+/// A file to send to a user, either already on disk or inline as base64
+/// encoded `data`. Paths must be inside the server's `outboxdir`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum OutAttachment {
+    Path {
+        path: String,
+    },
+    Inline {
+        data: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        filename: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        content_type: Option<String>,
+    },
+}
+
+/// Messages sent from the server to an app. On a multiplexed connection every
+/// session message is tagged with the id of the session it belongs to.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ToApp {
+    Query {
+        version: u32,
+    },
+    Start {
+        version: u32,
+        user: String,
+        #[serde(default)]
+        group: bool,
+        #[serde(default)]
+        session: Option<u64>,
+    },
+    Msg {
+        data: String,
+        author: String,
+        #[serde(default)]
+        attachments: Vec<Attachment>,
+        #[serde(default)]
+        session: Option<u64>,
+    },
+    Close {
+        session: u64,
+    },
+    Undelivered {
+        to: String,
+        error: String,
+        reason: String,
+        #[serde(default)]
+        session: Option<u64>,
+    },
+}
+
+/// Messages sent from an app to the server
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum FromApp {
+    Hello {
+        version: u32,
+        multiplex: bool,
+    },
+    Response {
+        value: String,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        attachments: Vec<OutAttachment>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        session: Option<u64>,
+    },
+    Send {
+        to: String,
+        value: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        session: Option<u64>,
+    },
+    Terminate {
+        reason: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        session: Option<u64>,
+    },
+}
+
+impl ToApp {
+    /// The session a message is tagged with, if any
+    pub fn session(&self) -> Option<u64> {
+        match self {
+            ToApp::Query { .. } => None,
+            ToApp::Close { session } => Some(*session),
+            ToApp::Start { session, .. }
+            | ToApp::Msg { session, .. }
+            | ToApp::Undelivered { session, .. } => *session,
+        }
+    }
+}
+
+impl FromApp {
+    /// Reply to the user of the session
+    pub fn response(value: &str) -> Self {
+        FromApp::Response {
+            value: value.into(),
+            attachments: vec![],
+            session: None,
+        }
+    }
+
+    /// Message another user on behalf of the session
+    pub fn send(to: &str, value: &str) -> Self {
+        FromApp::Send {
+            to: to.into(),
+            value: value.into(),
+            session: None,
+        }
+    }
+
+    /// End the session
+    pub fn terminate(reason: &str) -> Self {
+        FromApp::Terminate {
+            reason: reason.into(),
+            session: None,
+        }
+    }
+
+    /// Tag a session message with the given session id
+    pub fn with_session(mut self, id: Option<u64>) -> Self {
+        match &mut self {
+            FromApp::Response { session, .. }
+            | FromApp::Send { session, .. }
+            | FromApp::Terminate { session, .. } => *session = id,
+            FromApp::Hello { .. } => {}
+        }
+        self
+    }
+
+    /// The session a message is tagged with, if any
+    pub fn session(&self) -> Option<u64> {
+        match self {
+            FromApp::Hello { .. } => None,
+            FromApp::Response { session, .. }
+            | FromApp::Send { session, .. }
+            | FromApp::Terminate { session, .. } => *session,
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        serde_json::to_string(self).expect("Serializing msg failed!")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use signal_apps::protocol as server;
+
+    use super::*;
+
+    // Both sides of the protocol have to agree on every message
+    #[test]
+    fn test_matches_server() {
+        let to_app = vec![
+            server::ToApp::query(),
+            server::ToApp::start("+1555", true).with_session(3),
+            server::ToApp::msg("+1555", "hi"),
+            server::ToApp::Close { session: 3 },
+            server::ToApp::Undelivered {
+                to: "+1666".into(),
+                error: "unregistered".into(),
+                reason: "no account".into(),
+                session: None,
+            },
+        ];
+        for msg in to_app {
+            let ours: ToApp = serde_json::from_str(&msg.encode()).unwrap();
+            let ours = serde_json::to_string(&ours).unwrap();
+            assert_eq!(msg, serde_json::from_str(&ours).unwrap());
+        }
+        assert_eq!(server::VERSION, VERSION);
+
+        let from_app = vec![
+            FromApp::Hello {
+                version: VERSION,
+                multiplex: true,
+            },
+            FromApp::response("hi").with_session(Some(3)),
+            FromApp::Response {
+                value: "".into(),
+                attachments: vec![OutAttachment::Inline {
+                    data: "aGk=".into(),
+                    filename: Some("hi.txt".into()),
+                    content_type: None,
+                }],
+                session: None,
+            },
+            FromApp::send("+1666", "hi"),
+            FromApp::terminate("bye"),
+        ];
+        for msg in from_app {
+            let theirs = server::FromApp::decode(&msg.encode()).unwrap();
+            assert_eq!(msg.session(), theirs.session());
+            let theirs = serde_json::to_string(&theirs).unwrap();
+            assert_eq!(msg, serde_json::from_str(&theirs).unwrap());
+        }
+    }
+}
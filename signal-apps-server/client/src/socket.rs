@@ -0,0 +1,125 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+
+use tokio::net::{UnixListener, UnixStream};
+
+/// Set by the server's launcher to where it expects the app's socket
+pub const SOCKET_ENV: &str = "SIGNAL_APPS_SOCKET";
+
+/// The server's config, which apps read their settings from too
+pub fn read_config<P: AsRef<Path>>(path: P) -> io::Result<serde_json::Value> {
+    Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+}
+
+/// The socket an app listens on. It's removed again when dropped.
+pub struct AppSocket {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl AppSocket {
+    /// Listen at `path`, replacing a socket left behind by an earlier run
+    pub fn bind<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        match fs::symlink_metadata(&path) {
+            Ok(meta) if meta.file_type().is_socket() => fs::remove_file(&path)?,
+            Ok(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} exists and is not a socket", path.display()),
+                ))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        let listener = UnixListener::bind(&path)?;
+        Ok(AppSocket { listener, path })
+    }
+
+    /// Listen where the server looks for app `name`: `SIGNAL_APPS_SOCKET` if
+    /// the app was started by the server, `<app_dir>/<name>` otherwise
+    pub fn for_app<P: AsRef<Path>>(app_dir: P, name: &str) -> io::Result<Self> {
+        match env::var_os(SOCKET_ENV) {
+            Some(path) => Self::bind(path),
+            None => Self::bind(app_dir.as_ref().join(name)),
+        }
+    }
+
+    /// Like `for_app`, with the app directory taken from the server's config
+    pub fn from_config<P: AsRef<Path>>(
+        config: P,
+        name: &str,
+    ) -> io::Result<Self> {
+        let config = read_config(config)?;
+        match config["appdir"].as_str() {
+            Some(app_dir) => Self::for_app(app_dir, name),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "config has no appdir",
+            )),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub async fn accept(&self) -> io::Result<UnixStream> {
+        let (stream, _) = self.listener.accept().await?;
+        Ok(stream)
+    }
+}
+
+impl Drop for AppSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tempdir::TempDir;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_bind_cleans_up() {
+        let tmp_dir = TempDir::new("apps").expect("create tempdir failed!");
+        let path = tmp_dir.path().join("app");
+
+        // A socket left behind is replaced, anything else is left alone
+        let stale = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        drop(stale);
+        let socket = AppSocket::bind(&path).unwrap();
+        assert_eq!(path, socket.path());
+        assert!(path.exists());
+        drop(socket);
+        assert!(!path.exists());
+
+        fs::write(&path, "not a socket").unwrap();
+        assert!(AppSocket::bind(&path).is_err());
+        assert!(path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_from_config() {
+        let tmp_dir = TempDir::new("apps").expect("create tempdir failed!");
+        let config = tmp_dir.path().join("config.json");
+        let app_dir = tmp_dir.path().to_str().unwrap();
+        fs::write(
+            &config,
+            serde_json::json!({ "appdir": app_dir }).to_string(),
+        )
+        .unwrap();
+
+        let socket = AppSocket::from_config(&config, "echo").unwrap();
+        assert_eq!(tmp_dir.path().join("echo"), socket.path());
+
+        fs::write(&config, "{}").unwrap();
+        assert!(AppSocket::from_config(&config, "echo").is_err());
+    }
+}
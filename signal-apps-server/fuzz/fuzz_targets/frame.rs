@@ -0,0 +1,43 @@
+//! Feeds arbitrary bytes to the frame codec, in chunks of arbitrary size, as
+//! an app could send them. Decoding must never panic or hand out a frame
+//! larger than the maximum, and every frame must encode back to the bytes it
+//! was read from.
+#![no_main]
+use bytes::{BufMut, BytesMut};
+use libfuzzer_sys::fuzz_target;
+
+use signal_apps::frame::FrameCodec;
+
+const MAX_FRAME_SIZE: usize = 1024;
+
+fuzz_target!(|data: &[u8]| {
+    // The first byte picks how many bytes arrive per read
+    let (chunk, data) = match data.split_first() {
+        Some((chunk, data)) => (*chunk as usize + 1, data),
+        None => return,
+    };
+
+    let codec = FrameCodec::new(MAX_FRAME_SIZE);
+    let mut buf = BytesMut::new();
+    let mut consumed = 0;
+    for bytes in data.chunks(chunk) {
+        buf.put_slice(bytes);
+        loop {
+            let before = buf.len();
+            match codec.decode(&mut buf) {
+                Ok(Some(frame)) => {
+                    assert!(frame.len() <= MAX_FRAME_SIZE);
+                    let mut encoded = BytesMut::new();
+                    codec.encode(&frame, &mut encoded).unwrap();
+                    let read = before - buf.len();
+                    assert_eq!(&data[consumed..consumed + read], &encoded[..]);
+                    consumed += read;
+                }
+                Ok(None) => break,
+                // The connection is dropped on any error
+                Err(_) => return,
+            }
+        }
+    }
+    let _ = codec.decode_eof(&mut buf);
+});
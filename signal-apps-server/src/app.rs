@@ -1,22 +1,39 @@
+use std::future::Future;
 use std::io;
-use std::ops::Deref;
-use std::ops::DerefMut;
 use std::path::Path;
 use std::sync::Arc;
-use std::time::Duration;
 
 use async_trait::async_trait;
-use futures::pin_mut;
-use serde_json;
-use tokio::io::{split, AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf};
+use bytes::BytesMut;
+use tokio::io::{split, AsyncRead, AsyncWrite, AsyncWriteExt, WriteHalf};
 use tokio::net::UnixStream;
-use tokio::sync::{mpsc, Mutex};
+use tokio::sync::{mpsc, oneshot};
 
-use crate::appstate::AppMsg;
+use crate::appstate::{AppMsg, EndReason};
+use crate::frame::{FrameCodec, FrameReader};
+use crate::mux::{MuxConnection, MuxRegistry};
+use crate::protocol::{self, FromApp, ToApp};
 
 #[async_trait]
-pub trait App {
-    async fn get_description(app_dir: &str, name: &str) -> io::Result<String>;
+pub trait App: Send + Sync + 'static {
+    /// State shared between every session of every app of this kind
+    type Shared: Default + Send + Sync;
+
+    /// The shared state for a server started with `config`
+    fn shared(_config: &serde_json::Value) -> Self::Shared {
+        Self::Shared::default()
+    }
+
+    /// Apps set up in the config rather than found in the app directory
+    fn configured(_shared: &Self::Shared) -> Vec<String> {
+        vec![]
+    }
+
+    async fn get_description(
+        shared: &Self::Shared,
+        app_dir: &str,
+        name: &str,
+    ) -> io::Result<String>;
 
     fn new(
         id: u64,
@@ -29,132 +46,311 @@ pub trait App {
 
     fn get_name(&self) -> &str;
 
-    async fn start(&mut self, app_dir: &str, name: &str) -> io::Result<()>;
+    async fn start(
+        &mut self,
+        shared: &Self::Shared,
+        app_dir: &str,
+        name: &str,
+    ) -> io::Result<()>;
 
-    async fn send(&mut self, msg: &str);
+    async fn send(&mut self, msg: &ToApp);
 
     async fn stop(&mut self);
 }
 
-pub struct UnixStreamApp {
+/// A session with an app over a stream written to with `W`. Every session
+/// gets its own stream, unless the app asked for its sessions to be
+/// multiplexed, in which case they all share one. Transports only differ in
+/// how they open the stream.
+pub struct StreamApp<W> {
     id: u64,
     name: String,
     user: String,
     control: mpsc::Sender<AppMsg>,
-    tx: Option<Arc<Mutex<mpsc::Sender<String>>>>,
-    writer: Option<WriteHalf<UnixStream>>,
+    // Sending on this, or dropping it, stops reading from the app
+    stop: Option<oneshot::Sender<()>>,
+    writer: Option<W>,
+    mux: Option<Arc<MuxConnection>>,
+    codec: FrameCodec,
 }
 
-impl UnixStreamApp {
-    async fn read_msg_from_stream(
-        stream: &mut ReadHalf<UnixStream>,
-    ) -> io::Result<String> {
-        let mut length = [0u8, 0, 0, 0];
-        let mut read = 0;
-        let mut failed = false;
-        loop {
-            match stream.read(&mut length[read..]).await {
-                Ok(0) => {
-                    failed = true;
-                    break;
-                }
-                Ok(n) => {
-                    eprintln!("l read {} bytes", n);
-                    read += n;
-                    if read == 4 {
-                        break;
-                    }
-                }
-                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                    continue;
-                }
-                Err(_) => {
-                    failed = true;
-                    break;
-                }
+/// An app listening on a Unix socket in the app directory
+pub type UnixStreamApp = StreamApp<WriteHalf<UnixStream>>;
+
+/// Pass a message from an app on to the server on behalf of session `id`
+/// belonging to `user`. Returns why the session ended if the message ends it.
+pub async fn forward_msg(
+    msg: FromApp,
+    id: u64,
+    name: &str,
+    user: &str,
+    control: &mpsc::Sender<AppMsg>,
+) -> Option<EndReason> {
+    let msg = match msg {
+        FromApp::Hello { version, .. } => {
+            if let Err(e) = protocol::check_version(version) {
+                eprintln!("App {} is incompatible: {}", name, e);
+                return Some(EndReason::Incompatible(version));
             }
+            eprintln!("App {} speaks protocol version {}", name, version);
+            return None;
         }
-        if failed {
-            return Err(io::Error::new(io::ErrorKind::Other, "Failed to read"));
+        FromApp::Response {
+            value, attachments, ..
+        } => AppMsg::OutMsg(user.into(), id, value, attachments),
+        FromApp::Send { to, value, .. } => AppMsg::SendMsg {
+            app: name.into(),
+            from: user.into(),
+            to,
+            msg: value,
+        },
+        FromApp::Terminate { reason, .. } => {
+            return Some(EndReason::Terminated(reason))
         }
+    };
 
-        let length = u32::from_be_bytes(length) as usize;
-        eprintln!("Expecting {} bytes", length);
+    control
+        .send(msg)
+        .await
+        .expect("Sending control msg failed!");
+    None
+}
 
-        let mut content = Vec::with_capacity(length);
-        loop {
-            match stream.read_buf(&mut content).await {
-                Ok(0) => {
-                    failed = true;
-                    break;
-                }
-                Ok(n) => {
-                    eprintln!("read {} bytes", n);
-                    if content.len() == length {
-                        break;
-                    }
-                    continue;
-                }
-                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                    continue;
-                }
-                Err(_) => {
-                    failed = true;
-                    break;
-                }
+/// Pass the messages an app answered with on to the server, and end session
+/// `id` if one of them says so. Returns whether the session ended.
+pub async fn forward_replies(
+    replies: Vec<FromApp>,
+    id: u64,
+    name: &str,
+    user: &str,
+    control: &mpsc::Sender<AppMsg>,
+) -> bool {
+    for msg in replies {
+        if let Some(reason) = forward_msg(msg, id, name, user, control).await {
+            eprintln!("App {} ended session {}", name, id);
+            control
+                .send(AppMsg::EndMsg(user.into(), id, reason))
+                .await
+                .expect("Sending control msg failed!");
+            return true;
+        }
+    }
+    false
+}
+
+/// Forward everything the app sends for session `id` until the session ends,
+/// and return why it did.
+pub async fn forward_responses<R: AsyncRead + Unpin>(
+    reader: &mut FrameReader<R>,
+    id: u64,
+    name: &str,
+    user: &str,
+    control: &mpsc::Sender<AppMsg>,
+) -> EndReason {
+    // Unless the app tells us otherwise, the session ending means that the
+    // app crashed or dropped the connection.
+    loop {
+        let msg = match reader.read_msg().await {
+            Ok(Some(msg)) => msg,
+            Ok(None) => return EndReason::Crashed,
+            Err(e) => {
+                eprintln!("App {} sent a bad frame: {}", name, e);
+                return EndReason::Crashed;
             }
+        };
+        if let Some(end) = forward_msg(msg, id, name, user, control).await {
+            return end;
         }
-        if failed {
-            return Err(io::Error::new(io::ErrorKind::Other, "Failed to read"));
+    }
+}
+
+/// Ask an app for its description over `reader` and `writer`, noting in
+/// `mux` whether it wants its sessions multiplexed.
+pub async fn query_app<R, W>(
+    mux: &MuxRegistry,
+    name: &str,
+    reader: R,
+    mut writer: W,
+) -> io::Result<String>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut reader = FrameReader::new(reader, mux.codec());
+    mux.codec()
+        .write(&mut writer, &ToApp::query().encode())
+        .await?;
+    eprintln!("queried app {}", name);
+
+    let mut reply = reader.read_msg().await;
+    if let Ok(Some(FromApp::Hello { version, multiplex })) = reply {
+        if let Err(e) = protocol::check_version(version) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, e));
         }
+        mux.set_multiplexed(name, multiplex).await;
+        reply = reader.read_msg().await;
+    }
 
-        String::from_utf8(content)
-            .map_err(|_| io::Error::new(io::ErrorKind::Other, "Invalid utf-8"))
+    if let Ok(Some(FromApp::Response { value, .. })) = reply {
+        return Ok(value);
     }
 
-    async fn open_app_socket(
-        app_dir: &str,
+    Ok("".into())
+}
+
+impl<W: AsyncWrite + Send + Unpin + 'static> StreamApp<W> {
+    pub fn new(
+        id: u64,
         name: &str,
-    ) -> io::Result<UnixStream> {
-        let path = Path::new(app_dir).join(name);
-        Ok(UnixStream::connect(path).await?)
+        user: &str,
+        control: mpsc::Sender<AppMsg>,
+    ) -> Self {
+        StreamApp {
+            id,
+            name: name.into(),
+            user: user.into(),
+            control,
+            stop: None,
+            writer: None,
+            mux: None,
+            codec: FrameCodec::default(),
+        }
     }
 
-    async fn send_bytes(&mut self, bytes: &[u8]) -> bool {
-        if let Err(_) = self.writer.as_mut().unwrap().write_all(bytes).await {
-            self.control
-                .send(AppMsg::EndMsg(self.user.clone(), self.id))
-                .await
-                .expect("Sending control msg failed!");
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Run the session on `mux`, the connection shared by every session of
+    /// the app
+    pub async fn open_mux(&mut self, mux: Arc<MuxConnection>) {
+        mux.open(self.id, &self.user, self.control.clone()).await;
+        self.mux = Some(mux);
+    }
+
+    /// Run the session on a stream of its own, forwarding what the app sends
+    /// on `reader` until either side ends the session. `cleanup` runs once
+    /// the app is no longer read from.
+    pub fn open<R, F>(
+        &mut self,
+        reader: R,
+        writer: W,
+        codec: FrameCodec,
+        cleanup: F,
+    ) where
+        R: AsyncRead + Send + Unpin + 'static,
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let mut reader = FrameReader::new(reader, codec);
+        let (stop, stopped) = oneshot::channel();
+        self.stop = Some(stop);
+        self.writer = Some(writer);
+        self.codec = codec;
+
+        let id = self.id;
+        let name = self.name.clone();
+        let user = self.user.clone();
+        let control = self.control.clone();
+        tokio::spawn(async move {
+            let forward =
+                forward_responses(&mut reader, id, &name, &user, &control);
+            let reason = tokio::select! {
+                reason = forward => Some(reason),
+                // The server ended the session, there's no one to tell
+                _ = stopped => None,
+            };
+
+            match reason {
+                Some(reason) => {
+                    eprintln!("App {} ended session {}", name, id);
+                    control
+                        .send(AppMsg::EndMsg(user, id, reason))
+                        .await
+                        .expect("Sending control msg failed!");
+                }
+                None => eprintln!("Stopped reading from app {}", name),
+            }
+            cleanup.await;
+        });
+    }
+
+    async fn end_session(&self, reason: EndReason) {
+        self.control
+            .send(AppMsg::EndMsg(self.user.clone(), self.id, reason))
+            .await
+            .expect("Sending control msg failed!");
+    }
+
+    pub async fn send(&mut self, msg: &ToApp) {
+        if let Some(mux) = &self.mux {
+            if mux.send(&msg.with_session(self.id)).await.is_err() {
+                self.end_session(EndReason::Crashed).await;
+            }
+            return;
+        }
+
+        let msg = msg.encode();
+        let mut frame = BytesMut::new();
+        if let Err(e) = self.codec.encode(&msg, &mut frame) {
+            eprintln!("Could not send to app {}: {}", self.name, e);
+            return;
+        }
+        let writer = match self.writer.as_mut() {
+            Some(writer) => writer,
+            None => return,
+        };
+        eprintln!("Sending msg {:?}", msg);
+        if writer.write_all(&frame).await.is_err() {
+            self.end_session(EndReason::Crashed).await;
+        }
+    }
 
-            return false;
+    pub async fn stop(&mut self) {
+        if let Some(mux) = self.mux.take() {
+            mux.close(self.id).await;
+        }
+        if let Some(mut writer) = self.writer.take() {
+            // Let the app know the session is over, e.g. by closing its
+            // stdin or ending a TLS session
+            let _ = writer.shutdown().await;
+        }
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
         }
-        true
+    }
+}
+
+impl UnixStreamApp {
+    pub async fn open_app_socket(
+        app_dir: &str,
+        name: &str,
+    ) -> io::Result<UnixStream> {
+        let path = Path::new(app_dir).join(name);
+        UnixStream::connect(path).await
     }
 }
 
 #[async_trait]
 impl App for UnixStreamApp {
-    async fn get_description(app_dir: &str, name: &str) -> io::Result<String> {
+    type Shared = MuxRegistry;
+
+    fn shared(config: &serde_json::Value) -> MuxRegistry {
+        MuxRegistry::from_config(config)
+    }
+
+    async fn get_description(
+        shared: &MuxRegistry,
+        app_dir: &str,
+        name: &str,
+    ) -> io::Result<String> {
         eprintln!("opened socket");
         let stream = Self::open_app_socket(app_dir, name).await?;
-        let (mut sr, mut sw) = split(stream);
-
-        // write 1 char
-        let query = serde_json::json!({ "type": "query" }).to_string();
-        sw.write_all(&(query.len() as u32).to_be_bytes()).await?;
-        sw.write_all(query.as_bytes()).await?;
-        eprintln!("queried socket");
-
-        if let Ok(desc) = Self::read_msg_from_stream(&mut sr).await {
-            if let Ok(desc) = serde_json::from_str::<serde_json::Value>(&desc) {
-                if let Some(desc) = desc["value"].as_str() {
-                    return Ok(desc.into());
-                }
-            }
-        }
-
-        Ok("".into())
+        let (sr, sw) = split(stream);
+        query_app(shared, name, sr, sw).await
     }
 
     fn new(
@@ -163,16 +359,7 @@ impl App for UnixStreamApp {
         user: &str,
         control: mpsc::Sender<AppMsg>,
     ) -> Self {
-        let name = name.into();
-        let user = user.into();
-        UnixStreamApp {
-            id,
-            name,
-            user,
-            control,
-            tx: None,
-            writer: None,
-        }
+        StreamApp::new(id, name, user, control)
     }
 
     fn get_id(&self) -> u64 {
@@ -183,107 +370,261 @@ impl App for UnixStreamApp {
         &self.name
     }
 
-    async fn start(&mut self, app_dir: &str, name: &str) -> io::Result<()> {
+    async fn start(
+        &mut self,
+        shared: &MuxRegistry,
+        app_dir: &str,
+        name: &str,
+    ) -> io::Result<()> {
+        if shared.is_multiplexed(name).await {
+            self.open_mux(shared.connect(app_dir, name).await?).await;
+            return Ok(());
+        }
+
         let stream = Self::open_app_socket(app_dir, name).await?;
-        let (mut sr, sw) = split(stream);
+        let (sr, sw) = split(stream);
+        self.open(sr, sw, shared.codec(), async {});
+        Ok(())
+    }
 
-        let (tx, mut rx) = mpsc::channel(100);
-        let tx = Arc::new(Mutex::new(tx));
+    async fn send(&mut self, msg: &ToApp) {
+        StreamApp::send(self, msg).await
+    }
 
-        self.tx = Some(tx.clone());
-        self.writer = Some(sw);
+    async fn stop(&mut self) {
+        StreamApp::stop(self).await
+    }
+}
 
-        let canceler = Arc::new(Mutex::new(false));
-        let id = self.id;
+#[cfg(test)]
+mod test {
+    use tempdir::TempDir;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::UnixListener;
+    use tokio::time::{timeout, Duration};
+
+    use super::*;
+
+    const SOURCE: &str = "+15555555";
+
+    async fn write_msg<W: AsyncWriteExt + Unpin>(
+        stream: &mut W,
+        msg: serde_json::Value,
+    ) {
+        let msg = msg.to_string();
+        stream
+            .write_all(&(msg.len() as u32).to_be_bytes())
+            .await
+            .unwrap();
+        stream.write_all(msg.as_bytes()).await.unwrap();
+    }
 
-        {
-            let user = self.user.clone();
-            let control = self.control.clone();
-            let canceler = canceler.clone();
-            let tx = tx.clone();
-            tokio::spawn(async move {
-                while let Ok(content) = loop {
-                    // Read, pausing every 500ms to check if we should cancel instead
-                    let reader = Self::read_msg_from_stream(&mut sr);
-                    pin_mut!(reader);
-                    break loop {
-                        match tokio::time::timeout(
-                            Duration::from_millis(500),
-                            &mut reader,
-                        )
-                        .await
-                        {
-                            Ok(content) => {
-                                break content;
-                            }
-                            Err(_) => {
-                                if *canceler.lock().await.deref() {
-                                    break Err(io::Error::new(
-                                        io::ErrorKind::Other,
-                                        "Cancelling producer",
-                                    ));
-                                }
-                                continue;
-                            }
-                        }
-                    };
-                } {
-                    if let Err(_) = tx.lock().await.send(content).await {
-                        break;
-                    }
-                }
-                eprintln!("Closed app response producer");
-                control
-                    .send(AppMsg::EndMsg(user, id))
-                    .await
-                    .expect("Sending control msg failed!");
-            });
+    async fn start_app(
+        tmp_dir: &TempDir,
+    ) -> (UnixStreamApp, UnixStream, mpsc::Receiver<AppMsg>) {
+        start_app_with(tmp_dir, MuxRegistry::default()).await
+    }
+
+    async fn start_app_with(
+        tmp_dir: &TempDir,
+        shared: MuxRegistry,
+    ) -> (UnixStreamApp, UnixStream, mpsc::Receiver<AppMsg>) {
+        let listener = UnixListener::bind(tmp_dir.path().join("app"))
+            .expect("bind failed!");
+        let (control, recv) = mpsc::channel(100);
+        let mut app = UnixStreamApp::new(0, "app", SOURCE, control);
+        app.start(&shared, tmp_dir.path().to_str().unwrap(), "app")
+            .await
+            .expect("start failed!");
+        let (stream, _) = listener.accept().await.expect("accept failed!");
+        (app, stream, recv)
+    }
+
+    #[tokio::test]
+    async fn test_terminate_ends_session() {
+        let tmp_dir = TempDir::new("apps").expect("create tempdir failed!");
+        let (_app, mut stream, mut recv) = start_app(&tmp_dir).await;
+
+        write_msg(
+            &mut stream,
+            serde_json::json!({"type": "response", "value": "bye!"}),
+        )
+        .await;
+        write_msg(
+            &mut stream,
+            serde_json::json!({"type": "terminate", "reason": "done"}),
+        )
+        .await;
+        drop(stream);
+
+        match recv.recv().await {
+            Some(AppMsg::OutMsg(user, 0, msg, _)) => {
+                assert_eq!(SOURCE, user);
+                assert_eq!("bye!", msg);
+            }
+            msg => panic!("Unexpected control msg {:?}", msg),
         }
+        match recv.recv().await {
+            Some(AppMsg::EndMsg(user, 0, reason)) => {
+                assert_eq!(SOURCE, user);
+                assert_eq!(EndReason::Terminated("done".into()), reason);
+            }
+            msg => panic!("Unexpected control msg {:?}", msg),
+        }
+    }
 
-        let user = self.user.clone();
-        let control = self.control.clone();
-        tokio::spawn(async move {
-            while let Some(msg) = rx.recv().await {
-                if msg.len() == 0 {
-                    break;
-                }
+    #[tokio::test]
+    async fn test_disconnect_is_crash() {
+        let tmp_dir = TempDir::new("apps").expect("create tempdir failed!");
+        let (_app, stream, mut recv) = start_app(&tmp_dir).await;
+        drop(stream);
 
-                if let Ok(msg) = serde_json::from_str::<serde_json::Value>(&msg)
-                {
-                    let msg = match msg["type"].as_str() {
-                        Some("response") => AppMsg::OutMsg(
-                            user.clone(),
-                            msg["value"].as_str().unwrap_or("").into(),
-                        ),
-                        _ => AppMsg::EndMsg(user.clone(), id),
-                    };
-                    control
-                        .send(msg)
-                        .await
-                        .expect("Sending control msg failed!");
-                }
+        match recv.recv().await {
+            Some(AppMsg::EndMsg(user, 0, reason)) => {
+                assert_eq!(SOURCE, user);
+                assert_eq!(EndReason::Crashed, reason);
             }
+            msg => panic!("Unexpected control msg {:?}", msg),
+        }
+    }
 
-            eprintln!("Closed app response consumer");
-            *canceler.lock().await.deref_mut() = true;
-        });
+    #[tokio::test]
+    async fn test_stop_closes_connection() {
+        let tmp_dir = TempDir::new("apps").expect("create tempdir failed!");
+        let (mut app, mut stream, mut recv) = start_app(&tmp_dir).await;
+
+        app.stop().await;
+        drop(app);
+
+        // The app sees the connection close straight away, and since the
+        // server ended the session nothing is reported back.
+        let mut buf = [0; 1];
+        let read = timeout(Duration::from_secs(1), stream.read(&mut buf))
+            .await
+            .expect("connection still open");
+        assert_eq!(0, read.unwrap());
+        assert!(recv.recv().await.is_none());
+    }
 
-        Ok(())
+    #[tokio::test]
+    async fn test_empty_msg_is_not_a_stop() {
+        let tmp_dir = TempDir::new("apps").expect("create tempdir failed!");
+        let (mut app, mut stream, mut recv) = start_app(&tmp_dir).await;
+
+        write_msg(
+            &mut stream,
+            serde_json::json!({"type": "response", "value": ""}),
+        )
+        .await;
+        match recv.recv().await {
+            Some(AppMsg::OutMsg(_, 0, msg, _)) => assert_eq!("", msg),
+            msg => panic!("Unexpected control msg {:?}", msg),
+        }
+
+        app.send(&ToApp::msg(SOURCE, "")).await;
+        let mut reader = FrameReader::new(stream, FrameCodec::default());
+        let msg = reader.read_frame().await.expect("read failed!");
+        assert_eq!(Some(ToApp::msg(SOURCE, "").encode()), msg);
     }
 
-    async fn send(&mut self, msg: &str) {
-        eprintln!("Sending msg {:?}", msg);
-        let _ = self.send_bytes(&(msg.len() as u32).to_be_bytes()).await
-            && self.send_bytes(msg.as_bytes()).await;
-        eprintln!("Sent msg {:?}", msg);
+    #[tokio::test]
+    async fn test_oversize_frame_ends_session() {
+        let tmp_dir = TempDir::new("apps").expect("create tempdir failed!");
+        let (_app, mut stream, mut recv) = start_app(&tmp_dir).await;
+
+        // Claim a 4GiB frame, which shouldn't be waited for or allocated
+        stream.write_all(&u32::MAX.to_be_bytes()).await.unwrap();
+
+        match recv.recv().await {
+            Some(AppMsg::EndMsg(user, 0, reason)) => {
+                assert_eq!(SOURCE, user);
+                assert_eq!(EndReason::Crashed, reason);
+            }
+            msg => panic!("Unexpected control msg {:?}", msg),
+        }
     }
 
-    async fn stop(&mut self) {
-        match self.tx.take() {
-            Some(tx) => {
-                let _ = tx.lock().await.send("".into()).await;
+    #[tokio::test]
+    async fn test_configured_frame_size() {
+        let tmp_dir = TempDir::new("apps").expect("create tempdir failed!");
+        let config = serde_json::json!({ "maxframesize": 64 });
+        let (_app, mut stream, mut recv) =
+            start_app_with(&tmp_dir, UnixStreamApp::shared(&config)).await;
+
+        let long = "x".repeat(64);
+        write_msg(
+            &mut stream,
+            serde_json::json!({"type": "response", "value": long}),
+        )
+        .await;
+
+        match recv.recv().await {
+            Some(AppMsg::EndMsg(_, 0, EndReason::Crashed)) => {}
+            msg => panic!("Unexpected control msg {:?}", msg),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_incompatible_version_ends_session() {
+        let tmp_dir = TempDir::new("apps").expect("create tempdir failed!");
+        let (_app, mut stream, mut recv) = start_app(&tmp_dir).await;
+
+        let version = protocol::VERSION + 1;
+        write_msg(
+            &mut stream,
+            serde_json::json!({"type": "hello", "version": version}),
+        )
+        .await;
+
+        match recv.recv().await {
+            Some(AppMsg::EndMsg(user, 0, reason)) => {
+                assert_eq!(SOURCE, user);
+                assert_eq!(EndReason::Incompatible(version), reason);
             }
-            None => {}
+            msg => panic!("Unexpected control msg {:?}", msg),
         }
     }
+
+    #[tokio::test]
+    async fn test_description_handshake() {
+        let tmp_dir = TempDir::new("apps").expect("create tempdir failed!");
+        let listener = UnixListener::bind(tmp_dir.path().join("app"))
+            .expect("bind failed!");
+        let app_dir = tmp_dir.path().to_str().unwrap().to_string();
+
+        let app = tokio::spawn(async move {
+            for version in [protocol::VERSION, protocol::VERSION + 1] {
+                let (stream, _) = listener.accept().await.unwrap();
+                let (sr, mut stream) = split(stream);
+                let mut reader = FrameReader::new(sr, FrameCodec::default());
+                let query = reader.read_frame().await.expect("read failed!");
+                assert_eq!(Some(ToApp::query().encode()), query);
+
+                write_msg(
+                    &mut stream,
+                    serde_json::json!({"type": "hello", "version": version}),
+                )
+                .await;
+                write_msg(
+                    &mut stream,
+                    serde_json::json!({"type": "response", "value": "desc"}),
+                )
+                .await;
+            }
+        });
+
+        let shared = MuxRegistry::default();
+        let desc =
+            UnixStreamApp::get_description(&shared, &app_dir, "app").await;
+        assert_eq!("desc", desc.expect("query failed!"));
+
+        let desc =
+            UnixStreamApp::get_description(&shared, &app_dir, "app").await;
+        assert_eq!(
+            io::ErrorKind::InvalidData,
+            desc.expect_err("query succeeded!").kind()
+        );
+
+        app.await.unwrap();
+    }
 }
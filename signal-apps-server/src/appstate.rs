@@ -1,92 +1,260 @@
 use std::collections::HashMap;
 use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 
 use async_std::fs;
-use futures::StreamExt;
-use serde_json;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use futures::{future, StreamExt};
 use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, Duration};
 
 use crate::app;
-use crate::comm::Sender;
+use crate::comm::{self, OutFile, SendError, Sender};
+use crate::command::{self, Command, Parsed};
+use crate::native::{NativeApps, NativeRunner};
+use crate::protocol::{Attachment, OutAttachment, ToApp};
+
+/// How many times to try sending a message that failed for a transient reason,
+/// and how long to wait before the first retry. The wait doubles every time.
+const SEND_ATTEMPTS: u32 = 3;
+const RETRY_DELAY: Duration = Duration::from_secs(1);
 
 #[derive(Debug)]
 pub enum AppMsg {
-    InMsg(String, String),
-    EndMsg(String, u64),    // ends the given appid
-    OutMsg(String, String), // Allows access to sender
+    InMsg(String, String, String, Vec<Attachment>), // session, author, msg
+    EndMsg(String, u64, EndReason),                 // ends the given appid
+    OutMsg(String, u64, String, Vec<OutAttachment>), // reply from the given appid
+    // Lets the app running for `from` message another user
+    SendMsg {
+        app: String,
+        from: String,
+        to: String,
+        msg: String,
+    },
+    // A SendMsg from the app running for `from` could not be delivered
+    Undelivered {
+        app: String,
+        from: String,
+        to: String,
+        error: SendError,
+    },
+    Idle(String, u64), // the session has no apps left after this many msgs
+    Alert(String),     // something the admin should know about
     Finish,
 }
 
+impl AppMsg {
+    /// The session whose task handles this message
+    fn session(&self) -> Option<&str> {
+        match self {
+            AppMsg::InMsg(source, ..)
+            | AppMsg::EndMsg(source, ..)
+            | AppMsg::OutMsg(source, ..) => Some(source),
+            AppMsg::SendMsg { to, .. } => Some(to),
+            AppMsg::Undelivered { from, .. } => Some(from),
+            AppMsg::Idle(..) | AppMsg::Alert(_) | AppMsg::Finish => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum EndReason {
+    User,               // the user sent `endapp`
+    Terminated(String), // the app sent a `terminate` with the given reason
+    Crashed,            // the app went away without saying why
+    Incompatible(u32),  // the app speaks the given unsupported version
+}
+
+/// Who an app is allowed to message with `send`, set per app in the config as
+/// `"apps": { "<name>": { "send": "sessions" | "any" } }`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SendPolicy {
+    Sessions, // only users currently running the app
+    Any,      // any user
+}
+
 struct AppInfo {
     name: String,
     desc: String,
 }
 
-pub struct AppState<App: app::App, S: Sender> {
+/// Everything shared by the tasks of every session
+struct Context<App: app::App, S: Sender> {
     // config: serde_json::Value, this could allow querying config from apps
     app_dir: String, // TODO turn this into ref
-    app_id: u64,
+    admin: Option<String>,
+    next_id: AtomicU64,
+    shared: App::Shared,
     sender: S,
-    running_apps: HashMap<String, App>,
-    app_cache: HashMap<String, AppInfo>,
-    task_receiver: mpsc::Receiver<AppMsg>,
+    // Never held across an await, so a slow app only holds up its own users
+    app_cache: Mutex<HashMap<String, AppInfo>>,
+    send_policies: HashMap<String, SendPolicy>,
+    // The only directory apps may attach files from by path
+    outbox_dir: Option<PathBuf>,
+    native: NativeApps,
     incoming: mpsc::Sender<AppMsg>,
 }
 
-impl<App: app::App, S: Sender> AppState<App, S> {
+/// Hands every message to the task of the session it belongs to. Messages for
+/// one session are handled in order, different sessions don't wait on each
+/// other.
+pub struct AppState<App: app::App, S: Sender> {
+    ctx: Arc<Context<App, S>>,
+    sessions: HashMap<String, SessionTask>,
+    task_receiver: mpsc::Receiver<AppMsg>,
+}
+
+struct SessionTask {
+    queue: mpsc::UnboundedSender<AppMsg>,
+    queued: u64,
+    handle: JoinHandle<()>,
+}
+
+impl<App: app::App, S: Sender + 'static> AppState<App, S> {
     pub fn new(
         config: serde_json::Value,
         sender: S,
+    ) -> (Self, mpsc::Sender<AppMsg>) {
+        Self::with_native_apps(config, sender, NativeApps::default())
+    }
+
+    /// A server that also runs the given apps compiled into it
+    pub fn with_native_apps(
+        config: serde_json::Value,
+        sender: S,
+        native: NativeApps,
     ) -> (Self, mpsc::Sender<AppMsg>) {
         let app_dir = config["appdir"]
             .as_str()
             .expect("Config is missing appdir")
             .into();
+        let admin = config["admin"].as_str().map(String::from);
+        let outbox_dir = config["outboxdir"].as_str().map(PathBuf::from);
+        let mut send_policies = HashMap::new();
+        if let Some(apps) = config["apps"].as_object() {
+            for (name, app) in apps {
+                let policy = match app["send"].as_str() {
+                    None | Some("sessions") => SendPolicy::Sessions,
+                    Some("any") => SendPolicy::Any,
+                    Some(policy) => {
+                        panic!("Invalid send policy {:?} for {}", policy, name)
+                    }
+                };
+                send_policies.insert(name.clone(), policy);
+            }
+        }
+
         let (task_sender, task_receiver) = mpsc::channel(100);
-        let incoming = task_sender.clone();
+        let ctx = Context {
+            app_dir,
+            admin,
+            next_id: AtomicU64::new(0),
+            shared: App::shared(&config),
+            sender,
+            app_cache: Mutex::new(HashMap::new()),
+            send_policies,
+            outbox_dir,
+            native,
+            incoming: task_sender.clone(),
+        };
         (
             AppState {
-                app_dir,
-                app_id: 0,
-                sender,
-                running_apps: HashMap::new(),
-                app_cache: HashMap::new(),
+                ctx: Arc::new(ctx),
+                sessions: HashMap::new(),
                 task_receiver,
-                incoming,
             },
             task_sender,
         )
     }
 
     pub async fn process_queue(&mut self) {
-        // TODO consider running each run_action in it's own async ctx
-        // use FutureUnordered?
         while let Some(msg) = self.task_receiver.recv().await {
             match msg {
-                AppMsg::InMsg(source, msg) => {
-                    self.run_action(source, msg).await
+                AppMsg::Alert(msg) => {
+                    eprintln!("Alert: {}", msg);
+                    if let Some(admin) = self.ctx.admin.clone() {
+                        let ctx = self.ctx.clone();
+                        tokio::spawn(
+                            async move { ctx.notify(&admin, &msg).await },
+                        );
+                    }
                 }
-                AppMsg::EndMsg(source, appid) => {
-                    self.endapp(&source, Some(appid)).await;
+                AppMsg::Idle(source, handled) => {
+                    self.process_idle(source, handled)
                 }
-                AppMsg::OutMsg(source, msg) => self.sender.send(&source, &msg),
                 AppMsg::Finish => {
                     break;
                 }
+                msg => self.dispatch(msg),
+            }
+        }
+
+        // Let every session finish what it was sent. They may still report
+        // back, so keep emptying the queue until they're done.
+        let handles = self.sessions.drain().map(|(_, task)| task.handle);
+        let done = future::join_all(handles);
+        tokio::pin!(done);
+        loop {
+            tokio::select! {
+                _ = &mut done => break,
+                _ = self.task_receiver.recv() => {}
             }
         }
         eprintln!("done processing queue");
     }
 
-    async fn populate_app_cache(&mut self, name: &str) -> io::Result<()> {
-        if self.app_cache.contains_key(name) {
+    /// Drop the task of a session without apps, unless something was queued
+    /// for it since it went idle.
+    fn process_idle(&mut self, source: String, handled: u64) {
+        if self
+            .sessions
+            .get(&source)
+            .is_some_and(|task| task.queued == handled)
+        {
+            self.sessions.remove(&source);
+        }
+    }
+
+    fn dispatch(&mut self, msg: AppMsg) {
+        let source = match msg.session() {
+            Some(source) => source.to_string(),
+            None => return,
+        };
+        let ctx = &self.ctx;
+        let task = self.sessions.entry(source.clone()).or_insert_with(|| {
+            let (queue, recv) = mpsc::unbounded_channel();
+            let session = Session::new(source, ctx.clone());
+            SessionTask {
+                queue,
+                queued: 0,
+                handle: tokio::spawn(session.run(recv)),
+            }
+        });
+        task.queued += 1;
+        if task.queue.send(msg).is_err() {
+            eprintln!("Session task went away, dropping msg");
+        }
+    }
+}
+
+impl<App: app::App, S: Sender> Context<App, S> {
+    async fn populate_app_cache(&self, name: &str) -> io::Result<()> {
+        if self.app_cache.lock().unwrap().contains_key(name) {
             eprintln!("Found app inside cache");
             return Ok(());
         }
 
-        eprintln!("Found app outside cache, opening socket");
-        let desc = App::get_description(&self.app_dir, name).await?;
-        self.app_cache.insert(
+        let desc = match self.native.get(name) {
+            Some(app) => app.description(),
+            None => {
+                eprintln!("Found app outside cache, opening socket");
+                App::get_description(&self.shared, &self.app_dir, name).await?
+            }
+        };
+        self.app_cache.lock().unwrap().insert(
             name.to_string(),
             AppInfo {
                 name: name.to_string(),
@@ -96,121 +264,345 @@ impl<App: app::App, S: Sender> AppState<App, S> {
         Ok(())
     }
 
-    fn get_id(&mut self) -> u64 {
-        let id = self.app_id;
-        self.app_id += 1;
-        id
+    fn get_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::SeqCst)
+    }
+
+    /// Send a message from the server itself, failures are only logged
+    async fn notify(&self, dest: &str, msg: &str) {
+        if let Err(e) = self.send_with_retry(dest, msg, &[]).await {
+            eprintln!("Could not send to {}: {}", dest, e);
+        }
+    }
+
+    async fn send_with_retry(
+        &self,
+        dest: &str,
+        msg: &str,
+        files: &[OutFile],
+    ) -> Result<(), SendError> {
+        let mut delay = RETRY_DELAY;
+        let mut attempt = 1;
+        loop {
+            let result = if files.is_empty() {
+                self.sender.send(dest, msg).await
+            } else {
+                self.sender.send_attachments(dest, msg, files).await
+            };
+            match result {
+                Err(e) if e.is_transient() && attempt < SEND_ATTEMPTS => {
+                    eprintln!(
+                        "Sending to {} failed ({}), retrying in {:?}",
+                        dest, e, delay
+                    );
+                    sleep(delay).await;
+                    delay *= 2;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// An app running in a session, behind a socket or compiled into the server
+enum SessionApp<App: app::App> {
+    External(App),
+    Native(NativeRunner),
+}
+
+impl<App: app::App> SessionApp<App> {
+    fn get_id(&self) -> u64 {
+        match self {
+            SessionApp::External(app) => app.get_id(),
+            SessionApp::Native(app) => app.get_id(),
+        }
+    }
+
+    fn get_name(&self) -> &str {
+        match self {
+            SessionApp::External(app) => app.get_name(),
+            SessionApp::Native(app) => app.get_name(),
+        }
+    }
+
+    async fn send(&mut self, msg: &ToApp) {
+        match self {
+            SessionApp::External(app) => app.send(msg).await,
+            SessionApp::Native(app) => app.send(msg).await,
+        }
     }
 
-    async fn run_action(&mut self, source: String, msg: String) {
+    async fn stop(&mut self) {
+        match self {
+            SessionApp::External(app) => app.stop().await,
+            // Dropping the session is all there is to stopping it
+            SessionApp::Native(_) => {}
+        }
+    }
+}
+
+/// Everything to do with one user, or one group, handled by its own task. Its
+/// apps are kept least recently used first. The last one is in the
+/// foreground and gets everything sent to the session, the others keep
+/// running in the background.
+struct Session<App: app::App, S: Sender> {
+    source: String,
+    apps: Vec<SessionApp<App>>,
+    ctx: Arc<Context<App, S>>,
+}
+
+impl<App: app::App, S: Sender> Session<App, S> {
+    fn new(source: String, ctx: Arc<Context<App, S>>) -> Self {
+        Session {
+            source,
+            apps: vec![],
+            ctx,
+        }
+    }
+
+    async fn run(mut self, mut queue: mpsc::UnboundedReceiver<AppMsg>) {
+        let mut handled = 0;
+        while let Some(msg) = queue.recv().await {
+            self.handle(msg).await;
+            handled += 1;
+            if self.apps.is_empty() {
+                let idle = AppMsg::Idle(self.source.clone(), handled);
+                let _ = self.ctx.incoming.send(idle).await;
+            }
+        }
+    }
+
+    async fn handle(&mut self, msg: AppMsg) {
+        match msg {
+            AppMsg::InMsg(_, author, msg, attachments) => {
+                self.run_action(author, msg, attachments).await
+            }
+            AppMsg::EndMsg(_, appid, reason) => {
+                self.endapp(Some(appid), reason).await;
+            }
+            AppMsg::OutMsg(_, id, msg, attachments) => {
+                self.send_reply(id, &msg, attachments).await
+            }
+            AppMsg::SendMsg { app, from, msg, .. } => {
+                self.deliver(&app, &from, &msg).await
+            }
+            AppMsg::Undelivered { app, to, error, .. } => {
+                let id = self.by_name(&app).map(|app| app.get_id());
+                self.report_undelivered(id, &to, &error).await;
+            }
+            AppMsg::Idle(..) | AppMsg::Alert(_) | AppMsg::Finish => {}
+        }
+    }
+
+    fn foreground(&self) -> Option<&SessionApp<App>> {
+        self.apps.last()
+    }
+
+    fn is_foreground(&self, id: u64) -> bool {
+        self.foreground().is_some_and(|app| app.get_id() == id)
+    }
+
+    fn get_mut(&mut self, id: u64) -> Option<&mut SessionApp<App>> {
+        self.apps.iter_mut().find(|app| app.get_id() == id)
+    }
+
+    fn by_name(&self, name: &str) -> Option<&SessionApp<App>> {
+        self.apps.iter().find(|app| app.get_name() == name)
+    }
+
+    /// Bring the app called `name` to the foreground
+    fn switch_to(&mut self, name: &str) -> bool {
+        match self.apps.iter().position(|app| app.get_name() == name) {
+            Some(i) => {
+                let app = self.apps.remove(i);
+                self.apps.push(app);
+                true
+            }
+            None => false,
+        }
+    }
+
+    async fn notify(&self, msg: &str) {
+        self.ctx.notify(&self.source, msg).await
+    }
+
+    async fn run_action(
+        &mut self,
+        author: String,
+        msg: String,
+        attachments: Vec<Attachment>,
+    ) {
         // TODO always send read receipt - that requires more info
         // Maybe eventually this method should take the json obj
-        let msg_lower = msg.to_lowercase();
-        let cmd: Vec<&str> = msg_lower.split(" ").collect();
-        match cmd[0] {
-            "startapp" => {
-                // Start an app
-                match self.running_apps.get(&source) {
-                    Some(_) => self.sender.send(
-                        &source,
-                        "You are already running an app. See `currentapp` for more info."
-                    ),
-                    None => {
-                        if cmd.len() != 2 {
-                            self.sender.send(
-                                &source,
-                                "Malformed startapp request, Expected `startapp <app>`."
-                            );
-                            return;
+        let (command, args) = match command::parse(&msg) {
+            Parsed::Command(command, args) => (command, args),
+            Parsed::Malformed(command) => {
+                self.notify(&format!(
+                    "Malformed {} request, Expected `{}`.",
+                    command.name,
+                    command.usage()
+                ))
+                .await;
+                return;
+            }
+            Parsed::Other => {
+                match self.apps.last_mut() {
+                    // Don't answer every bit of chatter in a group
+                    None if comm::group_id(&self.source).is_some() => {}
+                    None => self.send_unknown(&msg).await,
+                    Some(app) => {
+                        let mut msg = ToApp::msg(&author, &msg);
+                        if let ToApp::Msg { attachments: a, .. } = &mut msg {
+                            *a = attachments;
                         }
+                        app.send(&msg).await
+                    }
+                };
+                return;
+            }
+        };
 
-                        let app_name = cmd[1];
-                        self.startapp(source, app_name).await;
-                    },
+        match command.name {
+            "startapp" => {
+                let app_name = &args[0];
+                if self.switch_to(app_name) {
+                    // Already running, there's nothing to start
+                    self.notify(&format!("Switched to {}.", app_name)).await;
+                } else {
+                    self.startapp(app_name).await;
+                }
+            }
+            "switchapp" => {
+                let app_name = &args[0];
+                if self.apps.is_empty() {
+                    self.send_no_apps().await;
+                } else if self.switch_to(app_name) {
+                    self.notify(&format!("Switched to {}.", app_name)).await;
+                } else {
+                    self.notify(
+                        &format!(
+                            "You are not running {}. See `runningapps` for the apps you are running.",
+                            app_name
+                        ),
+                    ).await;
                 }
             }
             "listapps" => {
-                self.listapps(&source).await;
+                self.listapps().await;
+            }
+            "runningapps" => {
+                self.runningapps().await;
             }
             "currentapp" => {
                 // If there's a running app return it's name
-                match self.running_apps.get(&source) {
-                    None => self.send_no_apps(&source),
-                    Some(app) => self.sender.send(&source, app.get_name()),
+                match self.foreground() {
+                    None => self.send_no_apps().await,
+                    Some(app) => self.notify(app.get_name()).await,
                 }
             }
             "endapp" => {
-                self.endapp(&source, None).await;
-            }
-            "help" => {
-                self.send_help(&source);
-            }
-            _ => {
-                match self.running_apps.get_mut(&source) {
-                    None => self.send_help(&source),
-                    Some(app) => {
-                        let msg = serde_json::json!({
-                            "type": "msg",
-                            "data": &msg
-                        })
-                        .to_string();
-                        app.send(&msg).await
+                let app_name = match args.first() {
+                    None => {
+                        self.endapp(None, EndReason::User).await;
+                        return;
                     }
+                    Some(app_name) => app_name,
                 };
+
+                match self.by_name(app_name).map(|app| app.get_id()) {
+                    Some(id) => self.endapp(Some(id), EndReason::User).await,
+                    None => {
+                        self.notify(
+                            &format!(
+                                "You are not running {}. See `runningapps` for the apps you are running.",
+                                app_name
+                            ),
+                        ).await
+                    }
+                }
             }
+            "help" => match args.first() {
+                None => self.send_help().await,
+                Some(name) => match Command::get(name) {
+                    Some(command) => self.notify(&command.help()).await,
+                    None => self.send_unknown(name).await,
+                },
+            },
+            name => unreachable!("No handler for command {}", name),
         }
     }
 
-    async fn startapp(&mut self, source: String, app_name: &str) {
-        // We're about to yield so make sure we insert first to prevent other tasks
-        // from racing here.
-        {
-            let ident = source.clone();
-            let id = self.get_id();
-            // TODO use an enum placeholder instead of all these options and combine new w/ start
-            self.running_apps.insert(
-                ident,
-                App::new(id, app_name, &source, self.incoming.clone()),
-            );
-        }
-
-        if let Err(_) = self.populate_app_cache(app_name).await {
-            self.sender.send(
-                &source,
-                "Could not find app, please contact your admin if you believe this is in error."
-            );
-            self.running_apps.remove(&source);
+    async fn startapp(&mut self, app_name: &str) {
+        if let Err(e) = self.ctx.populate_app_cache(app_name).await {
+            eprintln!("Could not query app {}: {}", app_name, e);
+            if e.kind() == io::ErrorKind::InvalidData {
+                self.notify(
+                    "That app is not compatible with this server, please notify your admin.",
+                ).await;
+            } else {
+                self.notify(
+                    "Could not find app, please contact your admin if you believe this is in error."
+                ).await;
+            }
             return;
         }
 
-        // The app might have been removed by endapp during the appinfo fetch
-        // above.
-        if let Some(mut app) = self.running_apps.remove(&source) {
-            if let Err(_) = app.start(&self.app_dir, app_name).await {
-                self.sender.send(
-                    &source,
-                    "Could not start app, please notify your admin.",
-                );
+        let id = self.ctx.get_id();
+        let control = self.ctx.incoming.clone();
+        let mut app = match self.ctx.native.get(app_name) {
+            Some(native) => SessionApp::Native(NativeRunner::new(
+                native.as_ref(),
+                id,
+                app_name,
+                &self.source,
+                control,
+            )),
+            None => {
+                let mut app = App::new(id, app_name, &self.source, control);
+                if app
+                    .start(&self.ctx.shared, &self.ctx.app_dir, app_name)
+                    .await
+                    .is_err()
+                {
+                    self.notify(
+                        "Could not start app, please notify your admin.",
+                    )
+                    .await;
+                    // TODO remove it from app_cache
+                    return;
+                }
+                SessionApp::External(app)
+            }
+        };
+        let group = comm::group_id(&self.source).is_some();
+        app.send(&ToApp::start(&self.source, group)).await;
+        self.apps.push(app);
+    }
+
+    async fn runningapps(&self) {
+        if self.apps.is_empty() {
+            return self.send_no_apps().await;
+        }
 
-            // TODO remove it from app_cache
+        let mut lines = vec!["You are running the following apps.".into()];
+        for (i, app) in self.apps.iter().rev().enumerate() {
+            if i == 0 {
+                lines.push(format!("{} (current)", app.get_name()));
             } else {
-                let start_msg = serde_json::json!({
-                    "type": "start",
-                    "user": &source,
-                })
-                .to_string();
-                app.send(&start_msg).await;
-                self.running_apps.insert(source, app);
+                lines.push(app.get_name().into());
             }
         }
+        lines.push("Send `switchapp <app>` to switch between them.".into());
+        self.notify(&lines.join("\n")).await;
     }
 
-    async fn listapps(&mut self, source: &str) {
+    async fn listapps(&mut self) {
         // List all installed apps.
         // Do a listdir on the directory containing apps
         // for a known app, check the cache, otherwise populate it
         eprintln!("Reading appdir");
-        let mut entries = fs::read_dir(&self.app_dir)
+        let mut entries = fs::read_dir(&self.ctx.app_dir)
             .await
             .expect("Failed to read app_dir!");
         while let Some(entry) = entries.next().await {
@@ -218,9 +610,16 @@ impl<App: app::App, S: Sender> AppState<App, S> {
             let name = entry.file_name();
             // Ignore any errors here
             let _ = self
+                .ctx
                 .populate_app_cache(name.to_str().expect("Invalid utf8 name"))
                 .await;
         }
+        for name in App::configured(&self.ctx.shared) {
+            let _ = self.ctx.populate_app_cache(&name).await;
+        }
+        for name in self.ctx.native.names() {
+            let _ = self.ctx.populate_app_cache(&name).await;
+        }
 
         eprintln!("building resp");
         let mut lines = vec![
@@ -228,95 +627,332 @@ impl<App: app::App, S: Sender> AppState<App, S> {
             "To install more, please contact your admin.".into(),
         ];
 
-        let mut apps: Vec<_> = self.app_cache.keys().into_iter().collect();
-        apps.sort();
-        for app in apps {
-            let info = self.app_cache.get(app).unwrap();
-            lines.push(format!("{} - {}", info.name, info.desc));
+        {
+            let app_cache = self.ctx.app_cache.lock().unwrap();
+            let mut apps: Vec<_> = app_cache.keys().collect();
+            apps.sort();
+            for app in apps {
+                let info = app_cache.get(app).unwrap();
+                lines.push(format!("{} - {}", info.name, info.desc));
+            }
         }
         // TODO cache this?
         let infostr = lines.join("\n");
         eprintln!("sent resp");
-        self.sender.send(&source, &infostr);
+        self.notify(&infostr).await;
     }
 
-    async fn endapp(&mut self, source: &str, appid: Option<u64>) {
-        // If there's a running app, terminate it.
-        if let Some(_) = appid {
-            let foundid = self.running_apps.get(source).map(|app| app.get_id());
-            if appid != foundid {
-                return;
+    async fn endapp(&mut self, appid: Option<u64>, reason: EndReason) {
+        // Without an appid end the app in the foreground, if any.
+        let removed = match appid {
+            Some(id) => self
+                .apps
+                .iter()
+                .position(|app| app.get_id() == id)
+                .map(|i| self.apps.remove(i)),
+            None if self.apps.is_empty() => return self.send_no_apps().await,
+            None => self.apps.pop(),
+        };
+        let next = self.foreground().map(|app| app.get_name().to_string());
+
+        match removed {
+            None => {}
+            Some(mut app) => {
+                app.stop().await;
+                let name = app.get_name();
+                let source = &self.source;
+                match reason {
+                    EndReason::User => match next {
+                        Some(next) => {
+                            let msg =
+                                format!("Stopped {}, back to {}.", name, next);
+                            self.notify(&msg).await
+                        }
+                        None => self.notify("Stopped app").await,
+                    },
+                    EndReason::Terminated(reason) => {
+                        eprintln!(
+                            "App {} terminated session {} for {}: {:?}",
+                            name,
+                            app.get_id(),
+                            source,
+                            reason
+                        );
+                        let msg = if reason.is_empty() {
+                            format!("{} has ended.", name)
+                        } else {
+                            format!("{} has ended: {}", name, reason)
+                        };
+                        self.notify(&msg).await;
+                    }
+                    EndReason::Incompatible(version) => {
+                        eprintln!(
+                            "App {} speaks unsupported protocol version {}",
+                            name, version
+                        );
+                        self.notify(
+                            &format!(
+                                "{} is not compatible with this server, please notify your admin.",
+                                name
+                            ),
+                        ).await;
+                    }
+                    EndReason::Crashed => {
+                        eprintln!(
+                            "App {} crashed during session {} for {}",
+                            name,
+                            app.get_id(),
+                            source
+                        );
+                        self.notify(
+                            &format!(
+                                "{} stopped unexpectedly, please notify your admin.",
+                                name
+                            ),
+                        ).await;
+                    }
+                }
             }
         }
+    }
 
-        match self.running_apps.remove(source) {
-            None => self.send_no_apps(source),
-            Some(mut app) => {
-                app.stop().await;
-                self.sender.send(source, "Stopped app");
+    /// Let app `id` know that a message it sent to `dest` was not delivered
+    async fn report_undelivered(
+        &mut self,
+        id: Option<u64>,
+        dest: &str,
+        e: &SendError,
+    ) {
+        eprintln!("Could not send to {} for {}: {}", dest, self.source, e);
+        if let Some(app) = id.and_then(|id| self.get_mut(id)) {
+            app.send(&ToApp::Undelivered {
+                to: dest.into(),
+                error: e.kind().into(),
+                reason: e.message().into(),
+                session: None,
+            })
+            .await;
+        }
+    }
+
+    /// Messages from apps in the background are tagged with the app's name,
+    /// so that they aren't mistaken for the foreground app's.
+    fn tag(&self, id: u64, msg: &str) -> String {
+        if self.is_foreground(id) {
+            return msg.into();
+        }
+        match self.apps.iter().find(|app| app.get_id() == id) {
+            Some(app) => format!("[{}] {}", app.get_name(), msg),
+            None => msg.into(),
+        }
+    }
+
+    /// Where `path` really is, if it's inside `outboxdir`. Any app could
+    /// otherwise have the server send any file it can read.
+    async fn outbox(&self, path: &str) -> Option<PathBuf> {
+        let dir = fs::canonicalize(self.ctx.outbox_dir.as_ref()?).await.ok()?;
+        let path = fs::canonicalize(path).await.ok()?;
+        match path.starts_with(&dir) {
+            true => Some(path.into()),
+            false => None,
+        }
+    }
+
+    async fn send_reply(
+        &mut self,
+        id: u64,
+        msg: &str,
+        attachments: Vec<OutAttachment>,
+    ) {
+        let mut files = vec![];
+        for attachment in attachments {
+            match attachment {
+                OutAttachment::Path { path } => {
+                    match self.outbox(&path).await {
+                        Some(path) => files.push(OutFile::Path(path)),
+                        None => eprintln!(
+                            "Dropping attachment {:?} for {}: not in outboxdir",
+                            path, self.source
+                        ),
+                    }
+                }
+                OutAttachment::Inline {
+                    data,
+                    filename,
+                    content_type,
+                } => match BASE64.decode(data) {
+                    Ok(data) => files.push(OutFile::Inline {
+                        data,
+                        filename,
+                        content_type,
+                    }),
+                    Err(e) => eprintln!(
+                        "Dropping attachment for {}: {}",
+                        self.source, e
+                    ),
+                },
             }
         }
+
+        let msg = self.tag(id, msg);
+        let result = self.ctx.send_with_retry(&self.source, &msg, &files).await;
+        if let Err(e) = result {
+            let source = self.source.clone();
+            self.report_undelivered(Some(id), &source, &e).await;
+        }
+    }
+
+    /// Deliver a message sent by `app` for the session `from`
+    async fn deliver(&mut self, app: &str, from: &str, msg: &str) {
+        let policy = self
+            .ctx
+            .send_policies
+            .get(app)
+            .copied()
+            .unwrap_or(SendPolicy::Sessions);
+        let running = self.by_name(app).map(|running| running.get_id());
+        let allowed = match policy {
+            SendPolicy::Any => true,
+            SendPolicy::Sessions => running.is_some(),
+        };
+        if !allowed {
+            eprintln!(
+                "App {} (session of {}) may not message {}, dropping msg",
+                app, from, self.source
+            );
+            return;
+        }
+
+        // Someone not running the app couldn't tell where it came from
+        let msg = match running {
+            Some(id) => self.tag(id, msg),
+            None => format!("[{}] {}", app, msg),
+        };
+        if let Err(error) =
+            self.ctx.send_with_retry(&self.source, &msg, &[]).await
+        {
+            let undelivered = AppMsg::Undelivered {
+                app: app.into(),
+                from: from.into(),
+                to: self.source.clone(),
+                error,
+            };
+            let _ = self.ctx.incoming.send(undelivered).await;
+        }
+    }
+
+    async fn send_help(&self) {
+        self.notify(&command::help()).await;
     }
 
-    fn send_help(&self, dest: &str) {
-        // TODO better msg
-        self.sender.send(dest, "Welcome to signal-apps!");
+    /// Answer something that isn't a command, suggesting one if it looks
+    /// like a typo of one
+    async fn send_unknown(&self, msg: &str) {
+        let word = msg.split_whitespace().next().unwrap_or("");
+        match command::suggest(word) {
+            Some(command) => {
+                self.notify(&format!(
+                    "There's no command {}. Did you mean `{}`? Send `help {}` to learn more.",
+                    word,
+                    command.usage(),
+                    command.name
+                ))
+                .await
+            }
+            None => self.send_help().await,
+        }
     }
 
-    fn send_no_apps(&self, dest: &str) {
-        self.sender
-            .send(dest, "You have no running apps. Send `help` to learn more.");
+    async fn send_no_apps(&self) {
+        self.notify("You have no running apps. Send `help` to learn more.")
+            .await;
     }
 }
 
 #[cfg(test)]
 mod test {
     use std::cell::RefCell;
+    use std::collections::VecDeque;
     use std::fs::File;
+    use std::path::Path;
 
     use async_trait::async_trait;
     use tempdir::TempDir;
 
     use super::*;
+    use crate::native::{NativeApp, NativeSession};
+    use crate::protocol::FromApp;
 
-    const SOURCE: &'static str = "+15555555";
+    const SOURCE: &str = "+15555555";
 
     pub struct MockSender {
         channel: mpsc::UnboundedSender<(String, String)>,
+        attachments: std::sync::Mutex<Vec<OutFile>>,
+        failures: std::sync::Mutex<VecDeque<SendError>>,
     }
 
     impl MockSender {
         fn new() -> (Self, mpsc::UnboundedReceiver<(String, String)>) {
             let (channel, recv) = mpsc::unbounded_channel();
-            (MockSender { channel }, recv)
+            let attachments = std::sync::Mutex::new(vec![]);
+            let failures = std::sync::Mutex::new(VecDeque::new());
+            (
+                MockSender {
+                    channel,
+                    attachments,
+                    failures,
+                },
+                recv,
+            )
         }
     }
 
+    #[async_trait]
     impl Sender for MockSender {
-        fn send(&self, dest: &str, msg: &str) {
+        async fn send(&self, dest: &str, msg: &str) -> Result<(), SendError> {
+            if let Some(e) = self.failures.lock().unwrap().pop_front() {
+                return Err(e);
+            }
             let sender = self.channel.clone();
-            sender.send((dest.to_string(), msg.to_string())).unwrap()
+            sender.send((dest.to_string(), msg.to_string())).unwrap();
+            Ok(())
+        }
+
+        async fn send_attachments(
+            &self,
+            dest: &str,
+            msg: &str,
+            attachments: &[OutFile],
+        ) -> Result<(), SendError> {
+            self.attachments
+                .lock()
+                .unwrap()
+                .extend_from_slice(attachments);
+            self.send(dest, msg).await
         }
     }
 
     struct MockApp {
         id: u64,
         name: String,
-        messages: Vec<String>,
+        messages: Vec<ToApp>,
     }
 
-    thread_local!(static DESCRIPTIONQUERIES: RefCell<usize> = RefCell::new(0));
+    thread_local!(static DESCRIPTIONQUERIES: RefCell<usize> = const { RefCell::new(0) });
 
     #[async_trait]
     impl app::App for MockApp {
+        type Shared = ();
+
         async fn get_description(
+            _shared: &(),
             _app_dir: &str,
             name: &str,
         ) -> io::Result<String> {
             DESCRIPTIONQUERIES.with(|d| {
                 *d.borrow_mut() += 1;
             });
-            Ok(format!("mockapp {}", name).into())
+            Ok(format!("mockapp {}", name))
         }
 
         fn new(
@@ -342,19 +978,63 @@ mod test {
 
         async fn start(
             &mut self,
+            _shared: &(),
             _app_dir: &str,
-            _name: &str,
+            name: &str,
         ) -> io::Result<()> {
+            // Stands in for an app that takes a long time to answer
+            if name == "slow" {
+                sleep(Duration::from_secs(60)).await;
+            }
             Ok(())
         }
 
-        async fn send(&mut self, msg: &str) {
-            self.messages.push(msg.into());
+        async fn send(&mut self, msg: &ToApp) {
+            self.messages.push(msg.clone());
         }
 
         async fn stop(&mut self) {}
     }
 
+    impl AppState<MockApp, MockSender> {
+        fn session(&self, source: &str) -> Session<MockApp, MockSender> {
+            Session::new(source.into(), self.ctx.clone())
+        }
+    }
+
+    impl Session<MockApp, MockSender> {
+        /// The mock app called `name`, or the one in the foreground
+        fn mock(&self, name: Option<&str>) -> &MockApp {
+            let app = match name {
+                Some(name) => self.by_name(name),
+                None => self.foreground(),
+            };
+            match app {
+                Some(SessionApp::External(app)) => app,
+                _ => panic!("No mock app {:?}", name),
+            }
+        }
+    }
+
+    fn new_state(
+        config: serde_json::Value,
+        sender: MockSender,
+    ) -> AppState<MockApp, MockSender> {
+        AppState::new(config, sender).0
+    }
+
+    /// A session of SOURCE, that is the only user of the sender
+    fn new_session(
+        config: serde_json::Value,
+        sender: MockSender,
+    ) -> Session<MockApp, MockSender> {
+        new_state(config, sender).session(SOURCE)
+    }
+
+    fn in_msg(source: &str, msg: &str) -> AppMsg {
+        AppMsg::InMsg(source.into(), source.into(), msg.into(), vec![])
+    }
+
     #[tokio::test]
     async fn test_finish() {
         let (sender, _) = MockSender::new();
@@ -372,16 +1052,78 @@ mod test {
         state.process_queue().await;
     }
 
+    #[tokio::test(start_paused = true)]
+    async fn test_sessions_run_concurrently() {
+        let tmp_dir = TempDir::new("apps").expect("create tempdir failed!");
+        let file_path = tmp_dir.path().join("slow");
+        File::create(file_path).expect("create app failed!");
+
+        let (sender, mut sent) = MockSender::new();
+        let config = serde_json::json!({
+            "appdir": tmp_dir.path().to_str()
+        });
+        let (mut state, queue): (AppState<MockApp, MockSender>, _) =
+            AppState::new(config, sender);
+        let task = tokio::spawn(async move { state.process_queue().await });
+
+        for msg in [
+            in_msg(SOURCE, "startapp slow"),
+            in_msg(SOURCE, "currentapp"),
+            in_msg("+other", "currentapp"),
+            AppMsg::Finish,
+        ] {
+            queue.send(msg).await.expect("Failed to send msg");
+        }
+
+        // The other user doesn't wait for the slow app to start, while
+        // SOURCE's messages are still handled in order.
+        let msg = sent.recv().await.expect("Found no sent messages");
+        assert_eq!("+other", msg.0);
+        assert_eq!(
+            "You have no running apps. Send `help` to learn more.",
+            msg.1
+        );
+        let msg = sent.recv().await.expect("Found no sent messages");
+        assert_eq!(SOURCE, msg.0);
+        assert_eq!("slow", msg.1);
+
+        task.await.expect("process_queue failed!");
+        drop(queue);
+        assert_eq!(None, sent.recv().await);
+    }
+
+    #[tokio::test]
+    async fn test_idle_sessions_dropped() {
+        let (sender, _) = MockSender::new();
+        let config = serde_json::json!({
+            "appdir": "/tmp/test"
+        });
+        let (mut state, queue): (AppState<MockApp, MockSender>, _) =
+            AppState::new(config, sender);
+
+        state.dispatch(in_msg(SOURCE, "currentapp"));
+        state.dispatch(in_msg(SOURCE, "currentapp"));
+        assert_eq!(2, state.sessions[SOURCE].queued);
+
+        // Only once the session went through everything it was sent
+        state.process_idle(SOURCE.into(), 1);
+        assert!(state.sessions.contains_key(SOURCE));
+        state.process_idle(SOURCE.into(), 2);
+        assert!(!state.sessions.contains_key(SOURCE));
+        drop(queue);
+    }
+
     #[tokio::test]
     async fn test_currentapp_noapp() {
         let (sender, mut sent) = MockSender::new();
         let config = serde_json::json!({
             "appdir": "/tmp/test"
         });
-        let new_app = AppState::new(config, sender);
-        let mut state: AppState<MockApp, MockSender> = new_app.0;
+        let mut session = new_session(config, sender);
 
-        state.run_action(SOURCE.into(), "currentapp".into()).await;
+        session
+            .run_action(SOURCE.into(), "currentapp".into(), vec![])
+            .await;
 
         let msg = sent.recv().await.expect("Found no sent messages");
         assert_eq!(SOURCE, msg.0);
@@ -391,7 +1133,7 @@ mod test {
         );
 
         // Check that there's no additional messages
-        drop(state);
+        drop(session);
         assert_eq!(None, sent.recv().await);
     }
 
@@ -401,10 +1143,9 @@ mod test {
         let config = serde_json::json!({
             "appdir": "/tmp/test"
         });
-        let new_app = AppState::new(config, sender);
-        let mut state: AppState<MockApp, MockSender> = new_app.0;
+        let mut session = new_session(config, sender);
 
-        state.endapp(SOURCE, None).await;
+        session.endapp(None, EndReason::User).await;
         let msg = sent.recv().await.expect("Found no sent messages");
         assert_eq!(SOURCE, msg.0);
         assert_eq!(
@@ -413,8 +1154,8 @@ mod test {
         );
 
         // This variant should be internal only, so we expect no output
-        state.endapp(SOURCE, Some(1)).await;
-        drop(state);
+        session.endapp(Some(1), EndReason::Crashed).await;
+        drop(session);
         assert_eq!(None, sent.recv().await);
     }
 
@@ -425,9 +1166,8 @@ mod test {
         let config = serde_json::json!({
             "appdir": tmp_dir.path().to_str()
         });
-        let new_app = AppState::new(config, sender);
-        let mut state: AppState<MockApp, MockSender> = new_app.0;
-        state.listapps(SOURCE).await;
+        let mut session = new_session(config, sender);
+        session.listapps().await;
 
         let msg = sent.recv().await.expect("Found no sent messages");
         let expected: Vec<&'static str> = vec![
@@ -440,7 +1180,7 @@ mod test {
         assert_eq!(expected, msg.1);
 
         // Check that there's no additional messages
-        drop(state);
+        drop(session);
         assert_eq!(None, sent.recv().await);
     }
 
@@ -456,9 +1196,8 @@ mod test {
         let config = serde_json::json!({
             "appdir": tmp_dir.path().to_str()
         });
-        let new_app = AppState::new(config, sender);
-        let mut state: AppState<MockApp, MockSender> = new_app.0;
-        state.listapps(SOURCE).await;
+        let mut session = new_session(config, sender);
+        session.listapps().await;
 
         let msg = sent.recv().await.expect("Found no sent messages");
         let expected: Vec<&'static str> = vec![
@@ -473,7 +1212,7 @@ mod test {
         assert_eq!(expected, msg.1);
 
         // Check that there's no additional messages
-        drop(state);
+        drop(session);
         assert_eq!(None, sent.recv().await);
     }
 
@@ -489,12 +1228,13 @@ mod test {
         let config = serde_json::json!({
             "appdir": tmp_dir.path().to_str()
         });
-        let new_app = AppState::new(config, sender);
-        let mut state: AppState<MockApp, MockSender> = new_app.0;
-        state.listapps(SOURCE).await;
-        state.listapps(SOURCE).await;
+        let state = new_state(config, sender);
+        let mut session = state.session(SOURCE);
+        let mut other = state.session("+other");
+        session.listapps().await;
+        other.listapps().await;
 
-        for _ in 0u8..2 {
+        for dest in &[SOURCE, "+other"] {
             let msg = sent.recv().await.expect("Found no sent messages");
             let expected: Vec<&'static str> = vec![
                 "You currently have the following apps installed.",
@@ -504,16 +1244,17 @@ mod test {
             ];
             let expected = expected.join("\n");
 
-            assert_eq!(SOURCE, msg.0);
+            assert_eq!(dest, &msg.0);
             assert_eq!(expected, msg.1);
         }
 
+        // The cache is shared between sessions
         DESCRIPTIONQUERIES.with(|d| {
             assert_eq!(*d.borrow(), 2);
         });
 
         // Check that there's no additional messages
-        drop(state);
+        drop((state, session, other));
         assert_eq!(None, sent.recv().await);
     }
 
@@ -527,17 +1268,16 @@ mod test {
         let config = serde_json::json!({
             "appdir": tmp_dir.path().to_str()
         });
-        let new_app = AppState::new(config, sender);
-        let mut state: AppState<MockApp, MockSender> = new_app.0;
+        let state = new_state(config, sender);
 
         // TODO test that a query happened
-        state.startapp(SOURCE.into(), "app").await;
+        state.session(SOURCE).startapp("app").await;
         DESCRIPTIONQUERIES.with(|d| {
             assert_eq!(*d.borrow(), 1);
         });
 
         // TODO test that no query happened
-        state.startapp("+other".into(), "app").await;
+        state.session("+other").startapp("app").await;
         DESCRIPTIONQUERIES.with(|d| {
             assert_eq!(*d.borrow(), 1);
         });
@@ -553,17 +1293,12 @@ mod test {
         let config = serde_json::json!({
             "appdir": tmp_dir.path().to_str()
         });
-        let new_app = AppState::new(config, sender);
-        let mut state: AppState<MockApp, MockSender> = new_app.0;
+        let mut session = new_session(config, sender);
 
-        state.startapp(SOURCE.into(), "app").await;
+        session.startapp("app").await;
 
-        let expected =
-            serde_json::json!({"type": "start", "user": SOURCE}).to_string();
-        assert_eq!(
-            vec![expected],
-            state.running_apps.get(SOURCE).unwrap().messages
-        );
+        let expected = ToApp::start(SOURCE, false);
+        assert_eq!(vec![expected], session.mock(None).messages);
     }
 
     #[tokio::test]
@@ -576,19 +1311,20 @@ mod test {
         let config = serde_json::json!({
             "appdir": tmp_dir.path().to_str()
         });
-        let new_app = AppState::new(config, sender);
-        let mut state: AppState<MockApp, MockSender> = new_app.0;
+        let mut session = new_session(config, sender);
 
         let app_name = "app";
-        state.startapp(SOURCE.into(), app_name).await;
-        state.run_action(SOURCE.into(), "currentapp".into()).await;
+        session.startapp(app_name).await;
+        session
+            .run_action(SOURCE.into(), "currentapp".into(), vec![])
+            .await;
 
         let msg = sent.recv().await.expect("Found no sent messages");
         assert_eq!(SOURCE, msg.0);
         assert_eq!(app_name, msg.1);
 
         // Check that there's no additional messages
-        drop(state);
+        drop(session);
         assert_eq!(None, sent.recv().await);
     }
 
@@ -602,19 +1338,562 @@ mod test {
         let config = serde_json::json!({
             "appdir": tmp_dir.path().to_str()
         });
-        let new_app = AppState::new(config, sender);
-        let mut state: AppState<MockApp, MockSender> = new_app.0;
+        let mut session = new_session(config, sender);
 
-        state.startapp(SOURCE.into(), "app").await;
+        session.startapp("app").await;
 
         let msg = "hello world!";
-        state.run_action(SOURCE.into(), msg.into()).await;
+        session.run_action(SOURCE.into(), msg.into(), vec![]).await;
+
+        let expected = ToApp::msg(SOURCE, msg);
+        assert_eq!(expected, session.mock(None).messages[1]);
+    }
+
+    #[tokio::test]
+    async fn test_switch_between_sessions() {
+        let tmp_dir = TempDir::new("apps").expect("create tempdir failed!");
+        for name in &["game", "notes"] {
+            let file_path = tmp_dir.path().join(name);
+            File::create(file_path).expect("create app failed!");
+        }
+
+        let (sender, mut sent) = MockSender::new();
+        let config = serde_json::json!({
+            "appdir": tmp_dir.path().to_str()
+        });
+        let mut session = new_session(config, sender);
+
+        for msg in ["startapp game", "startapp notes", "note"] {
+            session.run_action(SOURCE.into(), msg.into(), vec![]).await;
+        }
+        assert_eq!("notes", session.mock(None).name);
+
+        session
+            .run_action(SOURCE.into(), "runningapps".into(), vec![])
+            .await;
+        let msg = sent.recv().await.expect("Found no sent messages");
+        assert_eq!(
+            "You are running the following apps.\nnotes (current)\ngame\n\
+             Send `switchapp <app>` to switch between them.",
+            msg.1
+        );
+
+        for msg in ["switchapp game", "move"] {
+            session.run_action(SOURCE.into(), msg.into(), vec![]).await;
+        }
+        let msg = sent.recv().await.expect("Found no sent messages");
+        assert_eq!("Switched to game.", msg.1);
+
+        let game = session.mock(Some("game"));
+        let notes = session.mock(Some("notes"));
+        assert_eq!(
+            vec![ToApp::start(SOURCE, false), ToApp::msg(SOURCE, "move")],
+            game.messages
+        );
+        assert_eq!(
+            vec![ToApp::start(SOURCE, false), ToApp::msg(SOURCE, "note")],
+            notes.messages
+        );
+
+        // Replies from the app in the background are tagged
+        let (game, notes) = (game.id, notes.id);
+        session.send_reply(game, "your move", vec![]).await;
+        session.send_reply(notes, "saved", vec![]).await;
+        assert_eq!("your move", sent.recv().await.unwrap().1);
+        assert_eq!("[notes] saved", sent.recv().await.unwrap().1);
+
+        session
+            .run_action(SOURCE.into(), "endapp notes".into(), vec![])
+            .await;
+        let msg = sent.recv().await.expect("Found no sent messages");
+        assert_eq!("Stopped notes, back to game.", msg.1);
+
+        session
+            .run_action(SOURCE.into(), "endapp".into(), vec![])
+            .await;
+        let msg = sent.recv().await.expect("Found no sent messages");
+        assert_eq!("Stopped app", msg.1);
+        assert!(session.apps.is_empty());
+
+        // Check that there's no additional messages
+        drop(session);
+        assert_eq!(None, sent.recv().await);
+    }
+
+    #[tokio::test]
+    async fn test_commands() {
+        let tmp_dir = TempDir::new("apps").expect("create tempdir failed!");
+        let file_path = tmp_dir.path().join("My-Game");
+        File::create(file_path).expect("create app failed!");
+
+        let (sender, mut sent) = MockSender::new();
+        let config = serde_json::json!({
+            "appdir": tmp_dir.path().to_str()
+        });
+        let mut session = new_session(config, sender);
+
+        // Names are matched in any case, arguments are kept as they are
+        for msg in ["STARTAPP  'My-Game'", "strtapp", "CurrentApp"] {
+            session.run_action(SOURCE.into(), msg.into(), vec![]).await;
+        }
+        assert_eq!("My-Game", session.mock(None).name);
+        assert_eq!(
+            vec![ToApp::start(SOURCE, false), ToApp::msg(SOURCE, "strtapp")],
+            session.mock(None).messages
+        );
+        let msg = sent.recv().await.expect("Found no sent messages");
+        assert_eq!("My-Game", msg.1);
+
+        session
+            .run_action(SOURCE.into(), "startapp".into(), vec![])
+            .await;
+        let msg = sent.recv().await.expect("Found no sent messages");
+        assert_eq!(
+            "Malformed startapp request, Expected `startapp <app>`.",
+            msg.1
+        );
+
+        // Names that could lead out of the app directory aren't looked up
+        for msg in ["startapp ../My-Game", "startapp 'My Game'"] {
+            session.run_action(SOURCE.into(), msg.into(), vec![]).await;
+            let msg = sent.recv().await.expect("Found no sent messages");
+            assert_eq!(
+                "Malformed startapp request, Expected `startapp <app>`.",
+                msg.1
+            );
+        }
+        assert_eq!(1, session.apps.len());
+
+        session
+            .run_action(SOURCE.into(), "help switchApp".into(), vec![])
+            .await;
+        let msg = sent.recv().await.expect("Found no sent messages");
+        assert_eq!(Command::get("switchapp").unwrap().help(), msg.1);
+
+        session
+            .run_action(SOURCE.into(), "endapp \"My-Game\"".into(), vec![])
+            .await;
+        sent.recv().await.expect("Found no sent messages");
+        assert!(session.apps.is_empty());
+
+        // Without an app to send them to, typos are pointed out
+        session
+            .run_action(SOURCE.into(), "strtapp game".into(), vec![])
+            .await;
+        let msg = sent.recv().await.expect("Found no sent messages");
+        assert_eq!(
+            "There's no command strtapp. Did you mean `startapp <app>`? \
+             Send `help startapp` to learn more.",
+            msg.1
+        );
+        session.run_action(SOURCE.into(), "hi".into(), vec![]).await;
+        let msg = sent.recv().await.expect("Found no sent messages");
+        assert_eq!(command::help(), msg.1);
+
+        // Check that there's no additional messages
+        drop(session);
+        assert_eq!(None, sent.recv().await);
+    }
+
+    #[tokio::test]
+    async fn test_terminate_relays_reason() {
+        let tmp_dir = TempDir::new("apps").expect("create tempdir failed!");
+        let file_path = tmp_dir.path().join("app");
+        File::create(file_path).expect("create app failed!");
+
+        let (sender, mut sent) = MockSender::new();
+        let config = serde_json::json!({
+            "appdir": tmp_dir.path().to_str()
+        });
+        let mut session = new_session(config, sender);
+
+        session.startapp("app").await;
+        let id = session.mock(None).id;
+        let reason = EndReason::Terminated("Game over".into());
+        session.endapp(Some(id), reason).await;
+        assert!(session.apps.is_empty());
+
+        let msg = sent.recv().await.expect("Found no sent messages");
+        assert_eq!(SOURCE, msg.0);
+        assert_eq!("app has ended: Game over", msg.1);
+
+        // Check that there's no additional messages
+        drop(session);
+        assert_eq!(None, sent.recv().await);
+    }
+
+    #[tokio::test]
+    async fn test_crash_notifies_user() {
+        let tmp_dir = TempDir::new("apps").expect("create tempdir failed!");
+        let file_path = tmp_dir.path().join("app");
+        File::create(file_path).expect("create app failed!");
+
+        let (sender, mut sent) = MockSender::new();
+        let config = serde_json::json!({
+            "appdir": tmp_dir.path().to_str()
+        });
+        let mut session = new_session(config, sender);
+
+        session.startapp("app").await;
+        let id = session.mock(None).id;
+        session.endapp(Some(id), EndReason::Crashed).await;
+        assert!(session.apps.is_empty());
+
+        let msg = sent.recv().await.expect("Found no sent messages");
+        assert_eq!(SOURCE, msg.0);
+        assert_eq!(
+            "app stopped unexpectedly, please notify your admin.",
+            msg.1
+        );
+
+        // Check that there's no additional messages
+        drop(session);
+        assert_eq!(None, sent.recv().await);
+    }
+
+    #[tokio::test]
+    async fn test_send_to_other_session() {
+        let tmp_dir = TempDir::new("apps").expect("create tempdir failed!");
+        for name in &["app", "other"] {
+            let file_path = tmp_dir.path().join(name);
+            File::create(file_path).expect("create app failed!");
+        }
+
+        let (sender, mut sent) = MockSender::new();
+        let config = serde_json::json!({
+            "appdir": tmp_dir.path().to_str()
+        });
+        let state = new_state(config, sender);
+
+        let mut opponent = state.session("+opponent");
+        opponent.startapp("app").await;
+        let mut bystander = state.session("+bystander");
+        bystander.startapp("other").await;
+        let mut stranger = state.session("+stranger");
+
+        for to in [&mut opponent, &mut bystander, &mut stranger] {
+            to.deliver("app", SOURCE, "your turn").await;
+        }
+
+        let msg = sent.recv().await.expect("Found no sent messages");
+        assert_eq!("+opponent", msg.0);
+        assert_eq!("your turn", msg.1);
+
+        // Check that there's no additional messages
+        drop((state, opponent, bystander, stranger));
+        assert_eq!(None, sent.recv().await);
+    }
+
+    #[tokio::test]
+    async fn test_send_policy_any() {
+        let (sender, mut sent) = MockSender::new();
+        let config = serde_json::json!({
+            "appdir": "/tmp/test",
+            "apps": {
+                "app": { "send": "any" }
+            }
+        });
+        let state = new_state(config, sender);
+        let mut stranger = state.session("+stranger");
+
+        stranger.deliver("app", SOURCE, "hello").await;
+        stranger.deliver("other", SOURCE, "hello").await;
+
+        let msg = sent.recv().await.expect("Found no sent messages");
+        assert_eq!("+stranger", msg.0);
+        assert_eq!("[app] hello", msg.1);
+
+        // Check that there's no additional messages
+        drop((state, stranger));
+        assert_eq!(None, sent.recv().await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_send_retries_transient_errors() {
+        let (sender, mut sent) = MockSender::new();
+        let config = serde_json::json!({
+            "appdir": "/tmp/test"
+        });
+        let mut session = new_session(config, sender);
+        {
+            let mut failures = session.ctx.sender.failures.lock().unwrap();
+            failures.push_back(SendError::Network("timeout".into()));
+            failures.push_back(SendError::RateLimited("429".into()));
+        }
+
+        session.send_reply(0, "hello", vec![]).await;
+
+        let msg = sent.recv().await.expect("Found no sent messages");
+        assert_eq!(SOURCE, msg.0);
+        assert_eq!("hello", msg.1);
+
+        // Check that it was only delivered once
+        drop(session);
+        assert_eq!(None, sent.recv().await);
+    }
+
+    #[tokio::test]
+    async fn test_undelivered_reported_to_app() {
+        let tmp_dir = TempDir::new("apps").expect("create tempdir failed!");
+        let file_path = tmp_dir.path().join("app");
+        File::create(file_path).expect("create app failed!");
+
+        let (sender, _) = MockSender::new();
+        let config = serde_json::json!({
+            "appdir": tmp_dir.path().to_str(),
+            "apps": {
+                "app": { "send": "any" }
+            }
+        });
+        let mut state = new_state(config, sender);
+        let mut session = state.session(SOURCE);
+        let mut stranger = state.session("+stranger");
+
+        session.startapp("app").await;
+        state
+            .ctx
+            .sender
+            .failures
+            .lock()
+            .unwrap()
+            .push_back(SendError::Unregistered("+stranger".into()));
+        stranger.deliver("app", SOURCE, "hello").await;
+
+        // The failure goes back through the queue to the sending session
+        let msg = state.task_receiver.recv().await.unwrap();
+        assert_eq!(Some(SOURCE), msg.session());
+        session.handle(msg).await;
+        assert_eq!(
+            &ToApp::Undelivered {
+                to: "+stranger".into(),
+                error: "unregistered".into(),
+                reason: "+stranger".into(),
+                session: None,
+            },
+            session.mock(None).messages.last().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_group_session() {
+        let tmp_dir = TempDir::new("apps").expect("create tempdir failed!");
+        let file_path = tmp_dir.path().join("app");
+        File::create(file_path).expect("create app failed!");
+
+        let (sender, mut sent) = MockSender::new();
+        let config = serde_json::json!({
+            "appdir": tmp_dir.path().to_str()
+        });
+        let group = comm::group_key("abc=");
+        let mut session = new_state(config, sender).session(&group);
+
+        session
+            .run_action("+1".into(), "chatter".into(), vec![])
+            .await;
+        session
+            .run_action("+1".into(), "startapp app".into(), vec![])
+            .await;
+        session
+            .run_action("+2".into(), "hello".into(), vec![])
+            .await;
 
-        let expected =
-            serde_json::json!({"type": "msg", "data": msg}).to_string();
         assert_eq!(
-            expected,
-            state.running_apps.get(SOURCE).unwrap().messages[1]
+            vec![ToApp::start(&group, true), ToApp::msg("+2", "hello")],
+            session.mock(None).messages
         );
+
+        // Chatter without a running app is ignored
+        drop(session);
+        assert_eq!(None, sent.recv().await);
+    }
+
+    #[tokio::test]
+    async fn test_attachments_forwarded_to_running_apps() {
+        let tmp_dir = TempDir::new("apps").expect("create tempdir failed!");
+        let file_path = tmp_dir.path().join("app");
+        File::create(file_path).expect("create app failed!");
+
+        let (sender, _) = MockSender::new();
+        let config = serde_json::json!({
+            "appdir": tmp_dir.path().to_str()
+        });
+        let mut session = new_session(config, sender);
+
+        session.startapp("app").await;
+
+        let attachment = Attachment {
+            content_type: "image/png".into(),
+            filename: Some("cat.png".into()),
+            size: Some(3),
+            path: "/attachments/1234".into(),
+        };
+        session
+            .run_action(SOURCE.into(), "".into(), vec![attachment.clone()])
+            .await;
+
+        match &session.mock(None).messages[1] {
+            ToApp::Msg { attachments, .. } => {
+                assert_eq!(&vec![attachment], attachments)
+            }
+            msg => panic!("Unexpected msg {:?}", msg),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reply_with_attachments() {
+        let tmp_dir = TempDir::new("outbox").expect("create tempdir failed!");
+        let outbox = tmp_dir.path().join("outbox");
+        std::fs::create_dir(&outbox).unwrap();
+        let cat = outbox.join("cat.png");
+        File::create(&cat).unwrap();
+        let secret = tmp_dir.path().join("secret");
+        File::create(&secret).unwrap();
+        std::os::unix::fs::symlink(&secret, outbox.join("link")).unwrap();
+
+        let (sender, mut sent) = MockSender::new();
+        let config = serde_json::json!({
+            "appdir": "/tmp/test",
+            "outboxdir": outbox,
+        });
+        let mut session = new_session(config, sender);
+
+        let path = |path: &Path| OutAttachment::Path {
+            path: path.to_str().unwrap().into(),
+        };
+        let attachments = vec![
+            path(&cat),
+            path(&secret),
+            path(&outbox.join("link")),
+            path(&outbox.join("../secret")),
+            path(&outbox.join("missing")),
+            OutAttachment::Inline {
+                data: "aGVsbG8=".into(),
+                filename: Some("hello.txt".into()),
+                content_type: None,
+            },
+            OutAttachment::Inline {
+                data: "not base64!".into(),
+                filename: None,
+                content_type: None,
+            },
+        ];
+        session.send_reply(0, "files", attachments).await;
+
+        let msg = sent.recv().await.expect("Found no sent messages");
+        assert_eq!(SOURCE, msg.0);
+        assert_eq!("files", msg.1);
+
+        // Only files really in the outbox are sent, and the malformed
+        // attachment is dropped
+        let files = session.ctx.sender.attachments.lock().unwrap().clone();
+        assert_eq!(
+            vec![
+                OutFile::Path(cat.canonicalize().unwrap()),
+                OutFile::Inline {
+                    data: b"hello".to_vec(),
+                    filename: Some("hello.txt".into()),
+                    content_type: None,
+                },
+            ],
+            files
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reply_paths_need_outbox() {
+        let (sender, mut sent) = MockSender::new();
+        let config = serde_json::json!({
+            "appdir": "/tmp/test"
+        });
+        let mut session = new_session(config, sender);
+
+        let attachments = vec![OutAttachment::Path {
+            path: "/etc/passwd".into(),
+        }];
+        session.send_reply(0, "files", attachments).await;
+
+        assert_eq!("files", sent.recv().await.unwrap().1);
+        assert!(session.ctx.sender.attachments.lock().unwrap().is_empty());
+    }
+
+    /// A native app that shouts every message back, until it's told "bye"
+    struct Shout;
+
+    impl NativeApp for Shout {
+        fn description(&self) -> String {
+            "shouts back".into()
+        }
+
+        fn session(&self) -> Box<dyn NativeSession> {
+            Box::new(Shout)
+        }
+    }
+
+    #[async_trait]
+    impl NativeSession for Shout {
+        async fn handle(&mut self, msg: &ToApp) -> Vec<FromApp> {
+            match msg {
+                ToApp::Msg { data, .. } if data == "bye" => {
+                    vec![FromApp::Terminate {
+                        reason: "Quiet now".into(),
+                        session: None,
+                    }]
+                }
+                ToApp::Msg { data, .. } => vec![FromApp::Response {
+                    value: data.to_uppercase(),
+                    attachments: vec![],
+                    session: None,
+                }],
+                _ => vec![],
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_native_app() {
+        let tmp_dir = TempDir::new("apps").expect("create tempdir failed!");
+        let file_path = tmp_dir.path().join("app");
+        File::create(file_path).expect("create app failed!");
+
+        let (sender, mut sent) = MockSender::new();
+        let config = serde_json::json!({
+            "appdir": tmp_dir.path().to_str()
+        });
+        let mut native = NativeApps::default();
+        native.register("shout", Shout);
+        let mut state = AppState::with_native_apps(config, sender, native).0;
+        let mut session = state.session(SOURCE);
+
+        session.listapps().await;
+        let msg = sent.recv().await.expect("Found no sent messages");
+        assert_eq!(
+            "You currently have the following apps installed.\n\
+             To install more, please contact your admin.\n\
+             app - mockapp app\n\
+             shout - shouts back",
+            msg.1
+        );
+
+        for msg in ["startapp shout", "hello"] {
+            session.run_action(SOURCE.into(), msg.into(), vec![]).await;
+        }
+        // Replies go through the queue, like those of any other app
+        let msg = state.task_receiver.recv().await.unwrap();
+        session.handle(msg).await;
+        let msg = sent.recv().await.expect("Found no sent messages");
+        assert_eq!((SOURCE.into(), "HELLO".into()), msg);
+
+        session
+            .run_action(SOURCE.into(), "bye".into(), vec![])
+            .await;
+        let msg = state.task_receiver.recv().await.unwrap();
+        session.handle(msg).await;
+        let msg = sent.recv().await.expect("Found no sent messages");
+        assert_eq!("shout has ended: Quiet now", msg.1);
+        assert!(session.apps.is_empty());
+
+        // Check that there's no additional messages
+        drop((state, session));
+        assert_eq!(None, sent.recv().await);
     }
 }
@@ -0,0 +1,209 @@
+//! A stand-in for signal-cli used by the end-to-end tests.
+//!
+//! It keeps all of its state in the directory passed with `--config`:
+//!
+//! + `inbox` is followed like `tail -f`, every line is an envelope that is
+//!   handed to the server as an incoming message (`receive` or `jsonRpc`).
+//! + every message the server sends (`send` or the `send` request of
+//!   `jsonRpc`) is appended to `sent` as a line of json with `recipient` or
+//!   `groupId`, `message` and `attachments`.
+//!
+//! Accounts starting with `+0` are not registered, and sending to them fails
+//! the same way it does with the real signal-cli.
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use tokio::io::{stdin, AsyncBufReadExt, BufReader};
+use tokio::sync::mpsc;
+use tokio::time::{sleep, Duration};
+
+fn unregistered(number: &str) -> bool {
+    number.starts_with("+0")
+}
+
+/// Send every line appended to `<dir>/inbox` down `lines`
+async fn follow_inbox(dir: PathBuf, lines: mpsc::UnboundedSender<String>) {
+    let path = dir.join("inbox");
+    let mut offset = 0;
+    loop {
+        let inbox = fs::read(&path).unwrap_or_default();
+        // Only take complete lines, the rest may still be being written
+        if let Some(end) = inbox[offset..].iter().rposition(|&b| b == b'\n') {
+            let new = String::from_utf8_lossy(&inbox[offset..offset + end]);
+            for line in new.lines().filter(|line| !line.trim().is_empty()) {
+                if lines.send(line.into()).is_err() {
+                    return;
+                }
+            }
+            offset += end + 1;
+        }
+        sleep(Duration::from_millis(20)).await;
+    }
+}
+
+fn record_send(dir: &Path, msg: serde_json::Value) {
+    let mut sent = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(dir.join("sent"))
+        .expect("open sent failed!");
+    writeln!(sent, "{}", msg).expect("write sent failed!");
+}
+
+/// `send -m msg [-g group | recipient] [-a files...]`
+fn send(dir: &Path, args: &[String]) {
+    let mut msg = serde_json::json!({ "attachments": [] });
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-m" => msg["message"] = serde_json::json!(args.next()),
+            "-g" => msg["groupId"] = serde_json::json!(args.next()),
+            "-a" => {
+                msg["attachments"] = args.by_ref().cloned().collect();
+            }
+            recipient => msg["recipient"] = recipient.into(),
+        }
+    }
+
+    if let Some(recipient) = msg["recipient"].as_str() {
+        if unregistered(recipient) {
+            eprintln!("{}: Unregistered user", recipient);
+            process::exit(1);
+        }
+    }
+    record_send(dir, msg);
+}
+
+async fn receive(dir: PathBuf) {
+    let (tx, mut lines) = mpsc::unbounded_channel();
+    tokio::spawn(follow_inbox(dir, tx));
+    while let Some(line) = lines.recv().await {
+        println!("{}", line);
+    }
+}
+
+fn handle_request(
+    dir: &Path,
+    request: &serde_json::Value,
+) -> serde_json::Value {
+    let id = request["id"].clone();
+    if request["method"] != "send" {
+        return serde_json::json!({
+            "jsonrpc": "2.0",
+            "error": { "code": -32601, "message": "Method not implemented" },
+            "id": id,
+        });
+    }
+
+    let params = &request["params"];
+    let recipient = params["recipient"][0].as_str();
+    if let Some(recipient) = recipient.filter(|r| unregistered(r)) {
+        let results = serde_json::json!([{
+            "recipientAddress": { "number": recipient },
+            "type": "UNREGISTERED_FAILURE",
+        }]);
+        return serde_json::json!({
+            "jsonrpc": "2.0",
+            "error": {
+                "code": -1,
+                "message": "Failed to send message",
+                "data": { "response": { "results": results } },
+            },
+            "id": id,
+        });
+    }
+
+    let mut msg = serde_json::json!({
+        "message": params["message"],
+        "attachments": params.get("attachments").unwrap_or(&serde_json::json!([])),
+    });
+    match recipient {
+        Some(recipient) => msg["recipient"] = recipient.into(),
+        None => msg["groupId"] = params["groupId"].clone(),
+    }
+    record_send(dir, msg);
+
+    serde_json::json!({
+        "jsonrpc": "2.0",
+        "result": {
+            "results": [{ "type": "SUCCESS" }],
+            "timestamp": 1,
+        },
+        "id": id,
+    })
+}
+
+async fn json_rpc(dir: PathBuf) {
+    let (tx, mut inbox) = mpsc::unbounded_channel();
+    tokio::spawn(follow_inbox(dir.clone(), tx));
+    let mut requests = BufReader::new(stdin()).lines();
+    loop {
+        tokio::select! {
+            line = inbox.recv() => {
+                let params: serde_json::Value = match line {
+                    Some(line) => serde_json::from_str(&line)
+                        .expect("inbox lines must be json"),
+                    None => return,
+                };
+                let notification = serde_json::json!({
+                    "jsonrpc": "2.0",
+                    "method": "receive",
+                    "params": params,
+                });
+                println!("{}", notification);
+            }
+            request = requests.next_line() => {
+                let request = match request {
+                    Ok(Some(request)) => request,
+                    _ => return,
+                };
+                let request = serde_json::from_str(&request)
+                    .expect("requests must be json");
+                println!("{}", handle_request(&dir, &request));
+            }
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let mut dir = None;
+    let mut user = None;
+    let mut args = env::args().skip(1);
+    let command = loop {
+        match args.next().as_deref() {
+            Some("--config") => dir = args.next().map(PathBuf::from),
+            Some("-u") => user = args.next(),
+            Some(arg) if arg.starts_with('-') => {}
+            Some(command) => break command.to_string(),
+            None => {
+                eprintln!("No command given");
+                process::exit(1);
+            }
+        }
+    };
+    let args: Vec<String> = args.collect();
+    let dir = dir.expect("fake-signal-cli needs --config");
+
+    if let Some(user) = user.as_deref().filter(|u| unregistered(u)) {
+        eprintln!("User {} is not registered.", user);
+        process::exit(1);
+    }
+
+    match command.as_str() {
+        "listIdentities" => {}
+        "daemon" => loop {
+            sleep(Duration::from_secs(3600)).await;
+        },
+        "receive" => receive(dir).await,
+        "send" => send(&dir, &args),
+        "jsonRpc" => json_rpc(dir).await,
+        command => {
+            eprintln!("Unknown command {}", command);
+            process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,650 @@
+use std::fmt;
+use std::path::Path;
+
+use tokio::io::{split, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::net::UnixStream;
+use tokio::time::{timeout, Duration};
+
+use crate::frame::{FrameCodec, FrameError, FrameReader};
+use crate::protocol::{self, FromApp, ToApp};
+
+/// How long `check-app` waits for an app to answer
+pub const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Once an app answered, how long to wait for anything else it sends
+const QUIET: Duration = Duration::from_millis(100);
+
+/// Who the checks claim to be
+const USER: &str = "+15555555555";
+
+/// One step of a check and everything that went wrong in it
+#[derive(Debug, Default)]
+pub struct Step {
+    pub name: String,
+    /// Things worth knowing that the protocol allows
+    pub notes: Vec<String>,
+    pub violations: Vec<String>,
+}
+
+impl Step {
+    fn new(name: &str) -> Self {
+        Step {
+            name: name.into(),
+            ..Step::default()
+        }
+    }
+}
+
+/// What `check_app` found
+#[derive(Debug, Default)]
+pub struct Report {
+    pub steps: Vec<Step>,
+}
+
+impl Report {
+    pub fn violations(&self) -> usize {
+        self.steps.iter().map(|step| step.violations.len()).sum()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for step in &self.steps {
+            if step.violations.is_empty() {
+                writeln!(f, "{}: ok", step.name)?;
+            } else {
+                writeln!(f, "{}: FAILED", step.name)?;
+            }
+            for violation in &step.violations {
+                writeln!(f, "  ! {}", violation)?;
+            }
+            for note in &step.notes {
+                writeln!(f, "  - {}", note)?;
+            }
+        }
+        match self.violations() {
+            0 => writeln!(f, "No protocol violations"),
+            1 => writeln!(f, "1 protocol violation"),
+            n => writeln!(f, "{} protocol violations", n),
+        }
+    }
+}
+
+/// Everything an app sent back before going quiet
+#[derive(Default)]
+struct Replies {
+    msgs: Vec<FromApp>,
+    /// The app closed the connection, or broke it with a bad frame
+    closed: bool,
+}
+
+impl Replies {
+    fn terminated(&self) -> bool {
+        self.msgs
+            .iter()
+            .any(|msg| matches!(msg, FromApp::Terminate { .. }))
+    }
+}
+
+struct Connection {
+    reader: FrameReader<ReadHalf<UnixStream>>,
+    writer: WriteHalf<UnixStream>,
+}
+
+/// Runs an app through everything the server might send it
+struct Checker<'a> {
+    path: &'a Path,
+    codec: FrameCodec,
+    wait: Duration,
+    multiplex: bool,
+    session: u64,
+    report: Report,
+}
+
+/// Shorten long messages to something that fits on a line of the report
+fn summarize(msg: &str) -> String {
+    match msg.char_indices().nth(20) {
+        Some((end, _)) => format!("{:?}... ({} bytes)", &msg[..end], msg.len()),
+        None => format!("{:?}", msg),
+    }
+}
+
+/// Check a single frame from the app, returning it if it's a valid message
+fn check_reply(
+    frame: &str,
+    session: Option<u64>,
+    step: &mut Step,
+) -> Option<FromApp> {
+    let value: serde_json::Value = match serde_json::from_str(frame) {
+        Ok(value) => value,
+        Err(e) => {
+            let frame = summarize(frame);
+            step.violations
+                .push(format!("sent {}, not json: {}", frame, e));
+            return None;
+        }
+    };
+
+    let kind = match value["type"].as_str() {
+        Some(kind) => kind,
+        None => {
+            step.violations
+                .push(format!("sent {} without a type", value));
+            return None;
+        }
+    };
+    match kind {
+        "hello" | "terminate" => {}
+        "response" | "send" => match &value["value"] {
+            serde_json::Value::String(_) => {}
+            serde_json::Value::Null => {
+                step.violations
+                    .push(format!("sent {} without a value", kind));
+                return None;
+            }
+            other => {
+                step.violations.push(format!(
+                    "sent {} with a value that isn't a string: {}",
+                    kind, other
+                ));
+                return None;
+            }
+        },
+        _ => {
+            step.violations
+                .push(format!("sent unknown type {:?}", kind));
+            return None;
+        }
+    }
+
+    let msg = match FromApp::decode(frame) {
+        Ok(msg) => msg,
+        Err(e) => {
+            step.violations.push(format!("sent a bad {}: {}", kind, e));
+            return None;
+        }
+    };
+    if let FromApp::Hello { version, .. } = msg {
+        if let Err(e) = protocol::check_version(version) {
+            step.violations.push(e.to_string());
+        }
+    } else if session.is_some() && msg.session() != session {
+        step.violations.push(format!(
+            "tagged a {} with session {:?} instead of {:?}",
+            kind,
+            msg.session(),
+            session
+        ));
+    }
+    Some(msg)
+}
+
+impl<'a> Checker<'a> {
+    async fn connect(&self, step: &mut Step) -> Option<Connection> {
+        match UnixStream::connect(self.path).await {
+            Ok(stream) => {
+                let (reader, writer) = split(stream);
+                Some(Connection {
+                    reader: FrameReader::new(reader, self.codec),
+                    writer,
+                })
+            }
+            Err(e) => {
+                let path = self.path.display();
+                step.violations
+                    .push(format!("can't connect to {}: {}", path, e));
+                None
+            }
+        }
+    }
+
+    /// Tag `msg` with the current session when multiplexing
+    fn tagged(&self, msg: ToApp) -> ToApp {
+        match self.session_id() {
+            Some(id) => msg.with_session(id),
+            None => msg,
+        }
+    }
+
+    async fn send(&self, conn: &mut Connection, msg: &ToApp, step: &mut Step) {
+        let msg = msg.encode();
+        if let Err(e) = self.codec.write(&mut conn.writer, &msg).await {
+            step.notes.push(format!("sending failed: {}", e));
+        }
+    }
+
+    /// The id session messages are tagged with, if any
+    fn session_id(&self) -> Option<u64> {
+        if self.multiplex {
+            Some(self.session)
+        } else {
+            None
+        }
+    }
+
+    /// Collect replies tagged with `session` until the app goes quiet. Until
+    /// a `response` arrives the app is given the full wait if `response` is
+    /// set.
+    async fn replies(
+        &self,
+        conn: &mut Connection,
+        session: Option<u64>,
+        response: bool,
+        step: &mut Step,
+    ) -> Replies {
+        let mut replies = Replies::default();
+        loop {
+            let answered = replies
+                .msgs
+                .iter()
+                .any(|msg| matches!(msg, FromApp::Response { .. }));
+            let wait = if replies.msgs.is_empty() || (response && !answered) {
+                self.wait
+            } else {
+                QUIET
+            };
+
+            let frame = match timeout(wait, conn.reader.read_frame()).await {
+                Err(_) => return replies,
+                Ok(Ok(Some(frame))) => frame,
+                Ok(Ok(None)) => {
+                    replies.closed = true;
+                    return replies;
+                }
+                // Apps may well drop a connection that got bad input
+                Ok(Err(FrameError::Io(e))) => {
+                    step.notes.push(format!("connection failed: {}", e));
+                    replies.closed = true;
+                    return replies;
+                }
+                Ok(Err(e)) => {
+                    let problem = match e {
+                        FrameError::Oversize { size, max } => format!(
+                            "sent a frame of {} bytes, over the limit of {}",
+                            size, max
+                        ),
+                        FrameError::Truncated { expected, received } => {
+                            format!(
+                                "closed the connection {} bytes into a frame \
+                                 of {}",
+                                received, expected
+                            )
+                        }
+                        FrameError::InvalidUtf8(e) => {
+                            format!("sent a frame that isn't utf-8: {}", e)
+                        }
+                        e => e.to_string(),
+                    };
+                    step.violations.push(problem);
+                    replies.closed = true;
+                    return replies;
+                }
+            };
+            if let Some(msg) = check_reply(&frame, session, step) {
+                replies.msgs.push(msg);
+            }
+        }
+    }
+
+    async fn query(&mut self) {
+        let mut step = Step::new("query");
+        if let Some(mut conn) = self.connect(&mut step).await {
+            self.send(&mut conn, &ToApp::query(), &mut step).await;
+            let replies = self.replies(&mut conn, None, true, &mut step).await;
+
+            let mut described = false;
+            for msg in replies.msgs {
+                match msg {
+                    FromApp::Hello { version, multiplex } => {
+                        step.notes.push(format!(
+                            "protocol version {}{}",
+                            version,
+                            if multiplex { ", multiplexed" } else { "" }
+                        ));
+                        self.multiplex = multiplex;
+                    }
+                    FromApp::Response { value, .. } if !described => {
+                        step.notes.push(format!("description {:?}", value));
+                        described = true;
+                    }
+                    FromApp::Response { .. } => {}
+                    FromApp::Send { .. } => step
+                        .violations
+                        .push("sent send in reply to query".into()),
+                    FromApp::Terminate { .. } => step
+                        .violations
+                        .push("sent terminate in reply to query".into()),
+                }
+            }
+            if !described {
+                step.violations.push("no reply to query".into());
+            }
+        }
+        self.report.steps.push(step);
+    }
+
+    /// Start a new session on a new connection
+    async fn start(&mut self, step: &mut Step) -> Option<Connection> {
+        self.session += 1;
+        let mut conn = self.connect(step).await?;
+        let start = self.tagged(ToApp::start(USER, false));
+        self.send(&mut conn, &start, step).await;
+        let replies = self
+            .replies(&mut conn, self.session_id(), false, step)
+            .await;
+        if replies.terminated() || replies.closed {
+            step.notes.push("ended the session on start".into());
+            return None;
+        }
+        Some(conn)
+    }
+
+    /// Send one message after another, as long as the session lasts
+    async fn session(&mut self) {
+        let long = "x".repeat(64 << 10);
+        let msgs = ["hello", "", "héllo 👋 \"quoted\"\n", &long];
+
+        let mut step = Step::new("start");
+        let conn = self.start(&mut step).await;
+        self.report.steps.push(step);
+        let mut conn = match conn {
+            Some(conn) => conn,
+            None => return,
+        };
+
+        for data in &msgs {
+            let mut step = Step::new(&format!("msg {}", summarize(data)));
+            let msg = self.tagged(ToApp::msg(USER, data));
+            self.send(&mut conn, &msg, &mut step).await;
+            let replies = self
+                .replies(&mut conn, self.session_id(), false, &mut step)
+                .await;
+            let ended = replies.terminated() || replies.closed;
+            if replies.msgs.is_empty() {
+                step.notes.push("no reply".into());
+            }
+            if ended {
+                step.notes.push("ended the session".into());
+            }
+            self.report.steps.push(step);
+            if ended {
+                return;
+            }
+        }
+
+        if self.multiplex {
+            let mut step = Step::new("close");
+            let close = ToApp::Close {
+                session: self.session,
+            };
+            self.send(&mut conn, &close, &mut step).await;
+            let replies = self
+                .replies(&mut conn, self.session_id(), false, &mut step)
+                .await;
+            if !replies.msgs.is_empty() {
+                step.notes.push("replied to close".into());
+            }
+            self.report.steps.push(step);
+        }
+    }
+
+    /// Whether the app still answers queries after `step`
+    async fn still_answers(&self, step: &mut Step) {
+        let mut check = Step::default();
+        if let Some(mut conn) = self.connect(&mut check).await {
+            self.send(&mut conn, &ToApp::query(), &mut check).await;
+            let replies = self.replies(&mut conn, None, true, &mut check).await;
+            if replies
+                .msgs
+                .iter()
+                .any(|msg| matches!(msg, FromApp::Response { .. }))
+            {
+                return;
+            }
+        }
+        step.violations.push("stopped answering queries".into());
+    }
+
+    /// Send `bytes` as is in a session, and see whether the app survives it
+    async fn bad_input(&mut self, name: &str, bytes: &[u8]) {
+        let mut step = Step::new(name);
+        if let Some(mut conn) = self.start(&mut step).await {
+            // The app may well stop reading part way through
+            let write = conn.writer.write_all(bytes);
+            if let Ok(Err(e)) = timeout(self.wait, write).await {
+                step.notes.push(format!("sending failed: {}", e));
+            }
+            let replies = self
+                .replies(&mut conn, self.session_id(), false, &mut step)
+                .await;
+            if replies.closed {
+                step.notes.push("closed the connection".into());
+            }
+        }
+        self.still_answers(&mut step).await;
+        self.report.steps.push(step);
+    }
+
+    async fn oversize(&mut self) {
+        let data = "x".repeat(self.codec.max_frame_size());
+        let msg = self.tagged(ToApp::msg(USER, &data)).encode();
+        let mut bytes = (msg.len() as u32).to_be_bytes().to_vec();
+        bytes.extend_from_slice(msg.as_bytes());
+        self.bad_input("oversize msg", &bytes).await;
+    }
+
+    async fn malformed(&mut self) {
+        let frame = |msg: &[u8]| {
+            let mut frame = (msg.len() as u32).to_be_bytes().to_vec();
+            frame.extend_from_slice(msg);
+            frame
+        };
+        let inputs: Vec<(&str, Vec<u8>)> = vec![
+            ("malformed json", frame(b"{\"type\": \"msg\", ")),
+            ("unknown type", frame(br#"{"type": "bogus"}"#)),
+            ("msg without fields", frame(br#"{"type": "msg"}"#)),
+            ("invalid utf-8", frame(b"\xff\xfe")),
+            ("truncated frame", frame(b"{}")[..5].to_vec()),
+        ];
+        for (name, bytes) in inputs {
+            self.bad_input(name, &bytes).await;
+        }
+    }
+
+    /// Drop a connection in the middle of a session
+    async fn disconnect(&mut self) {
+        let mut step = Step::new("disconnect");
+        if let Some(mut conn) = self.start(&mut step).await {
+            let msg = self.tagged(ToApp::msg(USER, "hello"));
+            self.send(&mut conn, &msg, &mut step).await;
+            drop(conn);
+        }
+        self.still_answers(&mut step).await;
+        self.report.steps.push(step);
+    }
+}
+
+/// Run the app listening at `path` through a query, a session, bad input and
+/// a dropped connection, waiting up to `wait` for each answer. The app is
+/// held to the frame size limit of `codec`.
+pub async fn check_app(
+    path: &Path,
+    codec: FrameCodec,
+    wait: Duration,
+) -> Report {
+    let mut checker = Checker {
+        path,
+        codec,
+        wait,
+        multiplex: false,
+        session: 0,
+        report: Report::default(),
+    };
+
+    checker.query().await;
+    if checker.report.violations() == 0 {
+        checker.session().await;
+        checker.oversize().await;
+        checker.malformed().await;
+        checker.disconnect().await;
+    }
+    checker.report
+}
+
+#[cfg(test)]
+mod test {
+    use tempdir::TempDir;
+    use tokio::net::UnixListener;
+
+    use super::*;
+    use crate::frame;
+
+    const WAIT: Duration = Duration::from_millis(200);
+
+    async fn write_json(
+        writer: &mut WriteHalf<UnixStream>,
+        msg: serde_json::Value,
+    ) {
+        let _ = frame::write_frame(writer, &msg.to_string()).await;
+    }
+
+    /// Serve connections at `path` with `app`, which answers each message
+    fn serve<F>(tmp_dir: &TempDir, app: F) -> std::path::PathBuf
+    where
+        F: Fn(ToApp) -> Vec<serde_json::Value> + Copy + Send + 'static,
+    {
+        let path = tmp_dir.path().join("app");
+        let listener = UnixListener::bind(&path).expect("bind failed!");
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let (reader, mut writer) = split(stream);
+                    let mut reader =
+                        FrameReader::new(reader, FrameCodec::default());
+                    while let Ok(Some(msg)) = reader.read_msg().await {
+                        for reply in app(msg) {
+                            write_json(&mut writer, reply).await;
+                        }
+                    }
+                });
+            }
+        });
+        path
+    }
+
+    fn violations(report: &Report) -> Vec<String> {
+        report
+            .steps
+            .iter()
+            .flat_map(|step| step.violations.iter().cloned())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_good_app() {
+        let tmp_dir = TempDir::new("apps").expect("create tempdir failed!");
+        let path = serve(&tmp_dir, |msg| match msg {
+            ToApp::Query { .. } => vec![
+                serde_json::json!({"type": "hello", "version": 7}),
+                serde_json::json!({"type": "response", "value": "Echo"}),
+            ],
+            ToApp::Msg { data, .. } => {
+                vec![serde_json::json!({"type": "response", "value": data})]
+            }
+            _ => vec![],
+        });
+
+        let report = check_app(&path, FrameCodec::default(), WAIT).await;
+        assert_eq!(Vec::<String>::new(), violations(&report));
+        assert_eq!("query", report.steps[0].name);
+        assert!(report.steps[0]
+            .notes
+            .contains(&"description \"Echo\"".to_string()));
+        assert!(report.to_string().ends_with("No protocol violations\n"));
+        assert!(report.steps.iter().any(|step| step.name == "oversize msg"));
+    }
+
+    #[tokio::test]
+    async fn test_bad_app() {
+        let tmp_dir = TempDir::new("apps").expect("create tempdir failed!");
+        let path = serve(&tmp_dir, |msg| match msg {
+            ToApp::Query { .. } => {
+                vec![serde_json::json!({"type": "hello", "version": 99})]
+            }
+            _ => vec![],
+        });
+
+        // Nothing else is worth checking if the app can't be queried
+        let report = check_app(&path, FrameCodec::default(), WAIT).await;
+        assert_eq!(1, report.steps.len());
+        assert_eq!(
+            vec![
+                "app speaks protocol version 99, server supports 1-7",
+                "no reply to query",
+            ],
+            violations(&report)
+        );
+        assert!(report.to_string().ends_with("2 protocol violations\n"));
+
+        let tmp_dir = TempDir::new("apps").expect("create tempdir failed!");
+        let path = serve(&tmp_dir, |msg| match msg {
+            ToApp::Query { .. } => {
+                vec![serde_json::json!({"type": "response", "value": "Bad"})]
+            }
+            ToApp::Msg { data, .. } if data == "hello" => vec![
+                serde_json::json!({"type": "response"}),
+                serde_json::json!({"type": "reply", "value": data}),
+                serde_json::json!({"type": "send", "value": 3}),
+            ],
+            _ => vec![],
+        });
+        let report = check_app(&path, FrameCodec::default(), WAIT).await;
+        let msg = &report.steps[2];
+        assert_eq!("msg \"hello\"", msg.name);
+        assert_eq!(
+            vec![
+                "sent response without a value",
+                "sent unknown type \"reply\"",
+                "sent send with a value that isn't a string: 3",
+            ],
+            msg.violations
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bad_frames() {
+        let tmp_dir = TempDir::new("apps").expect("create tempdir failed!");
+        let path = tmp_dir.path().join("app");
+        let listener = UnixListener::bind(&path).expect("bind failed!");
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let (_, mut writer) = split(stream);
+                write_json(
+                    &mut writer,
+                    serde_json::json!({"type": "response", "value": "x"}),
+                )
+                .await;
+                let _ = writer.write_all(&u32::MAX.to_be_bytes()).await;
+            }
+        });
+
+        let report = check_app(&path, FrameCodec::new(1024), WAIT).await;
+        assert_eq!(
+            vec![format!(
+                "sent a frame of {} bytes, over the limit of 1024",
+                u32::MAX
+            )],
+            violations(&report)
+        );
+
+        // A connection that can't be made is reported too
+        let report = check_app(
+            &tmp_dir.path().join("none"),
+            FrameCodec::default(),
+            WAIT,
+        )
+        .await;
+        assert_eq!(1, report.violations());
+        assert!(violations(&report)[0].starts_with("can't connect to"));
+    }
+}
@@ -1,5 +1,32 @@
+use std::fmt;
+use std::path::PathBuf;
+
 use async_trait::async_trait;
 
+/// Sessions with Signal groups are keyed by this prefix followed by the group
+/// id, sessions with individual users by their number.
+pub const GROUP_PREFIX: &str = "group:";
+
+pub fn group_key(group_id: &str) -> String {
+    format!("{}{}", GROUP_PREFIX, group_id)
+}
+
+/// The group id of a session key, if the key refers to a group
+pub fn group_id(key: &str) -> Option<&str> {
+    key.strip_prefix(GROUP_PREFIX)
+}
+
+/// A file to send along with a message
+#[derive(Clone, Debug, PartialEq)]
+pub enum OutFile {
+    Path(PathBuf),
+    Inline {
+        data: Vec<u8>,
+        filename: Option<String>,
+        content_type: Option<String>,
+    },
+}
+
 #[async_trait]
 pub trait Receiver {
     async fn get_msg(&mut self) -> Option<String>;
@@ -10,6 +37,66 @@ pub trait Control {
     async fn insert_msg(&self, msg: &str);
 }
 
-pub trait Sender {
-    fn send(&self, dest: &str, msg: &str);
+/// Why a message could not be delivered
+#[derive(Clone, Debug, PartialEq)]
+pub enum SendError {
+    Network(String),
+    Unregistered(String),
+    RateLimited(String),
+    UntrustedIdentity(String),
+    Other(String),
+}
+
+impl SendError {
+    /// Whether trying again later might work
+    pub fn is_transient(&self) -> bool {
+        matches!(self, SendError::Network(_) | SendError::RateLimited(_))
+    }
+
+    /// A short name for the kind of error, as reported to apps
+    pub fn kind(&self) -> &'static str {
+        match self {
+            SendError::Network(_) => "network",
+            SendError::Unregistered(_) => "unregistered",
+            SendError::RateLimited(_) => "rate_limited",
+            SendError::UntrustedIdentity(_) => "untrusted_identity",
+            SendError::Other(_) => "other",
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            SendError::Network(msg)
+            | SendError::Unregistered(msg)
+            | SendError::RateLimited(msg)
+            | SendError::UntrustedIdentity(msg)
+            | SendError::Other(msg) => msg,
+        }
+    }
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} error: {}", self.kind(), self.message())
+    }
+}
+
+impl std::error::Error for SendError {}
+
+#[async_trait]
+pub trait Sender: Send + Sync {
+    /// Send `msg` to `dest`, which is either a number or a group key
+    async fn send(&self, dest: &str, msg: &str) -> Result<(), SendError>;
+
+    /// Send `msg` along with `attachments`. Senders that can't send files only
+    /// send the text.
+    async fn send_attachments(
+        &self,
+        dest: &str,
+        msg: &str,
+        attachments: &[OutFile],
+    ) -> Result<(), SendError> {
+        let _ = attachments;
+        self.send(dest, msg).await
+    }
 }
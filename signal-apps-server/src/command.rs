@@ -0,0 +1,341 @@
+use std::fmt;
+
+/// An argument taken by a built-in command, which only takes values that
+/// pass `valid`
+pub struct Arg {
+    pub name: &'static str,
+    pub about: &'static str,
+    pub optional: bool,
+    pub valid: fn(&str) -> bool,
+}
+
+/// A command handled by the server instead of being sent to an app
+pub struct Command {
+    pub name: &'static str,
+    pub args: &'static [Arg],
+    pub about: &'static str,
+}
+
+// Commands are told apart by name alone
+impl PartialEq for Command {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+    }
+}
+
+impl fmt::Debug for Command {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Command({})", self.name)
+    }
+}
+
+/// Whether `name` could name an app: it isn't empty, and has no whitespace,
+/// `/` or `..` that could lead out of the app directory
+pub fn app_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.contains(|c: char| c == '/' || c.is_whitespace())
+        && !name.contains("..")
+}
+
+fn any(_: &str) -> bool {
+    true
+}
+
+const APP: Arg = Arg {
+    name: "app",
+    about: "the name of an app, see `listapps`",
+    optional: false,
+    valid: app_name,
+};
+
+const RUNNING_APP: Arg = Arg {
+    name: "app",
+    about: "the name of one of your apps, see `runningapps`",
+    optional: false,
+    valid: app_name,
+};
+
+/// Every built-in command. Anything else is sent to the current app.
+pub const COMMANDS: &[Command] = &[
+    Command {
+        name: "startapp",
+        args: &[APP],
+        about: "Start an app, or switch to it if it's already running.",
+    },
+    Command {
+        name: "switchapp",
+        args: &[RUNNING_APP],
+        about: "Switch to another running app. Apps in the background keep \
+                running.",
+    },
+    Command {
+        name: "listapps",
+        args: &[],
+        about: "List the apps you can start.",
+    },
+    Command {
+        name: "runningapps",
+        args: &[],
+        about: "List the apps you are running.",
+    },
+    Command {
+        name: "currentapp",
+        args: &[],
+        about: "Show the app your messages are sent to.",
+    },
+    Command {
+        name: "endapp",
+        args: &[Arg {
+            optional: true,
+            ..RUNNING_APP
+        }],
+        about: "Stop the current app, or the given one.",
+    },
+    Command {
+        name: "help",
+        args: &[Arg {
+            name: "command",
+            about: "the name of a command",
+            optional: true,
+            valid: any,
+        }],
+        about: "List the commands, or explain one of them.",
+    },
+];
+
+/// A message, read as a command
+#[derive(Debug, PartialEq)]
+pub enum Parsed {
+    /// A command and its arguments, which fit what it takes
+    Command(&'static Command, Vec<String>),
+    /// A command with arguments it doesn't take, too many or too few
+    Malformed(&'static Command),
+    /// Anything that isn't a command
+    Other,
+}
+
+impl Command {
+    /// The command by `name`, in any case
+    pub fn get(name: &str) -> Option<&'static Command> {
+        let name = name.to_lowercase();
+        COMMANDS.iter().find(|command| command.name == name)
+    }
+
+    /// How to call the command, e.g. `endapp [<app>]`
+    pub fn usage(&self) -> String {
+        let mut usage = self.name.to_string();
+        for arg in self.args {
+            if arg.optional {
+                usage += &format!(" [<{}>]", arg.name);
+            } else {
+                usage += &format!(" <{}>", arg.name);
+            }
+        }
+        usage
+    }
+
+    /// Everything there is to know about the command, for `help <command>`
+    pub fn help(&self) -> String {
+        let mut help = format!("`{}`\n{}", self.usage(), self.about);
+        for arg in self.args {
+            help += &format!("\n<{}>: {}", arg.name, arg.about);
+            if arg.optional {
+                help += " (optional)";
+            }
+        }
+        help
+    }
+
+    fn takes(&self, args: &[String]) -> bool {
+        let required = self.args.iter().filter(|arg| !arg.optional).count();
+        (required..=self.args.len()).contains(&args.len())
+            && self.args.iter().zip(args).all(|(arg, a)| (arg.valid)(a))
+    }
+}
+
+/// The list of commands, for `help`
+pub fn help() -> String {
+    let mut help = "Welcome to signal-apps! Messages are sent to the app \
+                    you're running, except for these commands:"
+        .to_string();
+    for command in COMMANDS {
+        help += &format!("\n`{}`: {}", command.usage(), command.about);
+    }
+    help + "\nSend `help <command>` to learn more about one."
+}
+
+/// Split `msg` into words. Quotes group words with spaces into one, with `\`
+/// escaping a quote or `\` inside double quotes. A quote only starts a group
+/// at the start of a word. Returns None if a quote is never closed.
+pub fn tokenize(msg: &str) -> Option<Vec<String>> {
+    let mut tokens = vec![];
+    let mut chars = msg.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let quote = match chars.peek() {
+            None => return Some(tokens),
+            Some(&c) if c == '"' || c == '\'' => chars.next(),
+            Some(_) => None,
+        };
+
+        let mut token = String::new();
+        match quote {
+            Some(quote) => loop {
+                match chars.next()? {
+                    c if c == quote => break,
+                    '\\' if quote == '"' => match chars.next()? {
+                        c @ ('"' | '\\') => token.push(c),
+                        c => {
+                            token.push('\\');
+                            token.push(c);
+                        }
+                    },
+                    c => token.push(c),
+                }
+            },
+            None => {
+                while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                    token.push(c);
+                }
+            }
+        }
+        tokens.push(token);
+    }
+}
+
+/// Read `msg` as a command. Command names are matched in any case, the case
+/// of arguments is kept.
+pub fn parse(msg: &str) -> Parsed {
+    let name = msg.split_whitespace().next().unwrap_or("");
+    let command = match Command::get(name) {
+        Some(command) => command,
+        None => return Parsed::Other,
+    };
+
+    let args = msg.trim_start()[name.len()..].to_string();
+    match tokenize(&args) {
+        Some(args) if command.takes(&args) => Parsed::Command(command, args),
+        _ => Parsed::Malformed(command),
+    }
+}
+
+/// How many single character edits, or swaps of neighbouring characters, it
+/// takes to turn `a` into `b`
+fn distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut rows = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in rows.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in rows[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            let mut d = (rows[i - 1][j] + 1)
+                .min(rows[i][j - 1] + 1)
+                .min(rows[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d = d.min(rows[i - 2][j - 2] + 1);
+            }
+            rows[i][j] = d;
+        }
+    }
+    rows[a.len()][b.len()]
+}
+
+/// The command `word` is most likely a typo of, if it's close to any
+pub fn suggest(word: &str) -> Option<&'static Command> {
+    let word = word.to_lowercase();
+    let len = word.chars().count();
+    COMMANDS
+        .iter()
+        .map(|command| (distance(&word, command.name), command))
+        .filter(|(d, _)| *d <= 2 && d * 3 <= len)
+        .min_by_key(|(d, _)| *d)
+        .map(|(_, command)| command)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn tokens(msg: &str) -> Vec<String> {
+        tokenize(msg).unwrap()
+    }
+
+    #[test]
+    fn test_tokenize() {
+        assert_eq!(vec!["startapp", "echo"], tokens("  startapp   echo "));
+        assert_eq!(vec!["endapp", "my app"], tokens("endapp \"my app\""));
+        assert_eq!(vec!["a b", "c"], tokens("'a b' c"));
+        assert_eq!(vec!["say \"hi\"\\"], tokens(r#""say \"hi\"\\""#));
+        assert_eq!(vec!["it's", "a\\b"], tokens(r#"it's "a\b""#));
+        assert_eq!(vec![""], tokens("''"));
+        assert!(tokens("").is_empty());
+        assert_eq!(None, tokenize("endapp \"my app"));
+        assert_eq!(None, tokenize(r#""my app\""#));
+    }
+
+    #[test]
+    fn test_parse() {
+        let startapp = Command::get("startapp").unwrap();
+        let endapp = Command::get("endapp").unwrap();
+        assert_eq!(
+            Parsed::Command(startapp, vec!["Echo".into()]),
+            parse("StartApp  Echo")
+        );
+        assert_eq!(Parsed::Command(endapp, vec![]), parse(" ENDAPP"));
+        assert_eq!(
+            Parsed::Command(endapp, vec!["my.app".into()]),
+            parse("endapp 'my.app'")
+        );
+        assert_eq!(Parsed::Malformed(startapp), parse("startapp"));
+        assert_eq!(Parsed::Malformed(startapp), parse("startapp a b"));
+        assert_eq!(Parsed::Malformed(startapp), parse("startapp \"a"));
+        for name in ["''", "'a b'", "a/b", "..", "../a", "'a\tb'"] {
+            let msg = format!("startapp {}", name);
+            assert_eq!(Parsed::Malformed(startapp), parse(&msg), "{}", msg);
+        }
+        assert_eq!(
+            Parsed::Command(Command::get("help").unwrap(), vec!["".into()]),
+            parse("help ''")
+        );
+        assert_eq!(Parsed::Other, parse("startapps echo"));
+        assert_eq!(Parsed::Other, parse("hello there"));
+        assert_eq!(Parsed::Other, parse(""));
+    }
+
+    #[test]
+    fn test_help() {
+        let endapp = Command::get("endapp").unwrap();
+        assert_eq!("endapp [<app>]", endapp.usage());
+        assert_eq!(
+            "`endapp [<app>]`\nStop the current app, or the given one.\n\
+             <app>: the name of one of your apps, see `runningapps` \
+             (optional)",
+            endapp.help()
+        );
+        assert_eq!("startapp <app>", Command::get("startapp").unwrap().usage());
+
+        let help = help();
+        for command in COMMANDS {
+            assert!(help.contains(&command.usage()));
+        }
+    }
+
+    #[test]
+    fn test_suggest() {
+        let name = |word| suggest(word).map(|command| command.name);
+        assert_eq!(Some("startapp"), name("startap"));
+        assert_eq!(Some("startapp"), name("STRATAPP"));
+        assert_eq!(Some("listapps"), name("listapp"));
+        assert_eq!(Some("help"), name("hlep"));
+        assert_eq!(Some("endapp"), name("endap"));
+        assert_eq!(None, name("hey"));
+        assert_eq!(None, name("hi"));
+        assert_eq!(None, name("hello"));
+    }
+}
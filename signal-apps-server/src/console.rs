@@ -0,0 +1,221 @@
+use std::io::Result;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use tokio::io::{stdin, AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::sync::mpsc;
+
+use crate::comm::{self, Control, OutFile, Receiver, SendError, Sender};
+
+/// A backend reading messages from stdin and printing replies to stdout, so
+/// that apps can be tried out without a Signal account. Every line is sent by
+/// the user it starts with, e.g. `+1555> startapp echo`, or by the last user
+/// when there's no prefix. `+1555@abc=> hi` sends `hi` to the group `abc=`.
+pub struct Console {
+    send_chan: Arc<mpsc::Sender<String>>,
+}
+
+pub struct ConsoleReceiver {
+    recv_chan: mpsc::Receiver<String>,
+}
+
+pub struct ConsoleSender;
+
+impl Console {
+    pub fn new() -> Result<(Self, ConsoleReceiver, ConsoleSender)> {
+        Self::from_reader(stdin())
+    }
+
+    /// A console reading lines from `input` instead of stdin. Once `input`
+    /// ends the server is told to shut down.
+    pub fn from_reader<R: AsyncRead + Unpin + Send + 'static>(
+        input: R,
+    ) -> Result<(Self, ConsoleReceiver, ConsoleSender)> {
+        let (tx, rx) = mpsc::channel(100);
+        let recv_chan = rx;
+        let send_chan = Arc::new(tx);
+
+        {
+            let send_chan = send_chan.clone();
+            tokio::spawn(async move {
+                let mut lines = BufReader::new(input).lines();
+                let mut current = None;
+                while let Ok(Some(line)) = lines.next_line().await {
+                    match parse_line(&line, &mut current) {
+                        Some(msg) => send_chan
+                            .send(msg.to_string())
+                            .await
+                            .expect("Sending line failed!"),
+                        None => eprintln!(
+                            "Start lines with the user sending them, e.g. \
+                             `+1555> startapp echo`"
+                        ),
+                    }
+                }
+                eprintln!("Closed stdin");
+                // The console keeps a sender for insert_msg, so the receiver
+                // would never see the channel close
+                let _ = send_chan.send("".into()).await;
+            });
+        }
+
+        Ok((
+            Console { send_chan },
+            ConsoleReceiver { recv_chan },
+            ConsoleSender,
+        ))
+    }
+}
+
+/// The user and, if sent to a group, the group a line was sent by
+#[derive(Clone, Debug, PartialEq)]
+struct Author {
+    user: String,
+    group: Option<String>,
+}
+
+/// Turn a line into a message shaped like the ones signal-cli prints, so it
+/// can be handled like any other.
+fn parse_line(
+    line: &str,
+    current: &mut Option<Author>,
+) -> Option<serde_json::Value> {
+    let prefix = line
+        .split_once("> ")
+        .filter(|(author, _)| !author.is_empty() && !author.contains(' '));
+    let text = match prefix {
+        Some((author, text)) => {
+            *current = Some(match author.split_once('@') {
+                Some((user, group)) => Author {
+                    user: user.into(),
+                    group: Some(group.into()),
+                },
+                None => Author {
+                    user: author.into(),
+                    group: None,
+                },
+            });
+            text
+        }
+        None => line,
+    };
+
+    let author = current.as_ref()?;
+    let mut data = serde_json::json!({ "message": text });
+    if let Some(group) = &author.group {
+        data["groupInfo"] = serde_json::json!({ "groupId": group });
+    }
+    Some(serde_json::json!({
+        "envelope": {
+            "source": author.user,
+            "dataMessage": data,
+        }
+    }))
+}
+
+#[async_trait]
+impl Control for Console {
+    async fn insert_msg(&self, msg: &str) {
+        self.send_chan
+            .send(msg.to_string())
+            .await
+            .expect("Sending control msg failed!");
+    }
+}
+
+#[async_trait]
+impl Receiver for ConsoleReceiver {
+    async fn get_msg(&mut self) -> Option<String> {
+        self.recv_chan.recv().await
+    }
+}
+
+#[async_trait]
+impl Sender for ConsoleSender {
+    async fn send(
+        &self,
+        dest: &str,
+        msg: &str,
+    ) -> std::result::Result<(), SendError> {
+        let dest = match comm::group_id(dest) {
+            Some(group) => format!("@{}", group),
+            None => dest.into(),
+        };
+        println!("{} < {}", dest, msg);
+        Ok(())
+    }
+
+    async fn send_attachments(
+        &self,
+        dest: &str,
+        msg: &str,
+        attachments: &[OutFile],
+    ) -> std::result::Result<(), SendError> {
+        self.send(dest, msg).await?;
+        for attachment in attachments {
+            match attachment {
+                OutFile::Path(path) => println!("  [attachment {:?}]", path),
+                OutFile::Inline { data, filename, .. } => println!(
+                    "  [attachment {} ({} bytes)]",
+                    filename.as_deref().unwrap_or("unnamed"),
+                    data.len()
+                ),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_line() {
+        let mut current = None;
+        assert_eq!(None, parse_line("listapps", &mut current));
+
+        let msg = parse_line("+1555> startapp echo", &mut current).unwrap();
+        assert_eq!(
+            Some(("+1555".into(), "+1555", "startapp echo", vec![])),
+            crate::signalcli::get_msg(&msg, Path::new("/tmp"))
+        );
+
+        let msg = parse_line("a> b", &mut current).unwrap();
+        assert_eq!(
+            Some(("a".into(), "a", "b", vec![])),
+            crate::signalcli::get_msg(&msg, Path::new("/tmp"))
+        );
+        // Lines without a user are sent by the last one
+        let msg = parse_line("hi there> you", &mut current).unwrap();
+        assert_eq!(
+            Some(("a".into(), "a", "hi there> you", vec![])),
+            crate::signalcli::get_msg(&msg, Path::new("/tmp"))
+        );
+
+        let msg = parse_line("+1555@abc=> hi", &mut current).unwrap();
+        assert_eq!(
+            Some((comm::group_key("abc="), "+1555", "hi", vec![])),
+            crate::signalcli::get_msg(&msg, Path::new("/tmp"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_input_ends() {
+        let input: &[u8] = b"+1555> startapp echo\nhi\n";
+        let (_console, mut recv, _) = Console::from_reader(input).unwrap();
+
+        for text in ["startapp echo", "hi"] {
+            let msg = recv.get_msg().await.unwrap();
+            let msg = serde_json::from_str(&msg).unwrap();
+            assert_eq!(
+                Some(("+1555".into(), "+1555", text, vec![])),
+                crate::signalcli::get_msg(&msg, Path::new("/tmp"))
+            );
+        }
+        // The shutdown sentinel, as if the server got SIGINT
+        assert_eq!(Some("".into()), recv.get_msg().await);
+    }
+}
@@ -0,0 +1,369 @@
+use std::fmt;
+use std::io;
+use std::str::{self, Utf8Error};
+
+use bytes::{Buf, BufMut, BytesMut};
+use serde::de::DeserializeOwned;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// The largest frame accepted from an app unless `maxframesize` is set in the
+/// config. Attachments sent inline are base64 encoded into the json, which
+/// grows them by a third, so this fits files of up to about 12 MiB.
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 16 << 20;
+
+/// Bytes in the length prefix of every frame
+const PREFIX_LEN: usize = 4;
+
+#[derive(Debug)]
+pub enum FrameError {
+    Io(io::Error),
+    /// The stream ended part way through a frame
+    Truncated {
+        expected: usize,
+        received: usize,
+    },
+    /// A frame claimed to be longer than the codec accepts
+    Oversize {
+        size: usize,
+        max: usize,
+    },
+    InvalidUtf8(Utf8Error),
+    InvalidJson(serde_json::Error),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FrameError::Io(e) => write!(f, "{}", e),
+            FrameError::Truncated { expected, received } => write!(
+                f,
+                "stream ended after {} of {} bytes of a frame",
+                received, expected
+            ),
+            FrameError::Oversize { size, max } => write!(
+                f,
+                "frame of {} bytes is larger than the maximum of {}",
+                size, max
+            ),
+            FrameError::InvalidUtf8(e) => write!(f, "invalid utf-8: {}", e),
+            FrameError::InvalidJson(e) => write!(f, "invalid json: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FrameError::Io(e) => Some(e),
+            FrameError::InvalidUtf8(e) => Some(e),
+            FrameError::InvalidJson(e) => Some(e),
+            FrameError::Truncated { .. } | FrameError::Oversize { .. } => None,
+        }
+    }
+}
+
+impl From<io::Error> for FrameError {
+    fn from(e: io::Error) -> Self {
+        FrameError::Io(e)
+    }
+}
+
+impl From<FrameError> for io::Error {
+    fn from(e: FrameError) -> Self {
+        match e {
+            FrameError::Io(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
+}
+
+/// The size a frame claims in its prefix, which `buf` must hold
+fn frame_size(buf: &[u8]) -> usize {
+    let mut prefix = [0; PREFIX_LEN];
+    prefix.copy_from_slice(&buf[..PREFIX_LEN]);
+    u32::from_be_bytes(prefix) as usize
+}
+
+/// Splits a stream of bytes into frames: a 4 byte big endian length followed
+/// by that many bytes of utf-8.
+#[derive(Clone, Copy, Debug)]
+pub struct FrameCodec {
+    max_frame_size: usize,
+}
+
+impl FrameCodec {
+    pub fn new(max_frame_size: usize) -> Self {
+        FrameCodec { max_frame_size }
+    }
+
+    /// A codec for frames of up to `maxframesize` bytes, if set in `config`
+    pub fn from_config(config: &serde_json::Value) -> Self {
+        let size = &config["maxframesize"];
+        if size.is_null() {
+            return FrameCodec::default();
+        }
+        match size.as_u64() {
+            Some(size) if size <= u32::MAX as u64 => Self::new(size as usize),
+            _ => panic!("Invalid maxframesize {}", size),
+        }
+    }
+
+    pub fn max_frame_size(&self) -> usize {
+        self.max_frame_size
+    }
+
+    /// Take the next complete frame off the front of `buf`, if there is one.
+    /// The length is checked before anything is allocated for the frame.
+    pub fn decode(
+        &self,
+        buf: &mut BytesMut,
+    ) -> Result<Option<String>, FrameError> {
+        if buf.len() < PREFIX_LEN {
+            return Ok(None);
+        }
+
+        let size = frame_size(buf);
+        if size > self.max_frame_size {
+            return Err(FrameError::Oversize {
+                size,
+                max: self.max_frame_size,
+            });
+        }
+        if buf.len() < PREFIX_LEN + size {
+            buf.reserve(PREFIX_LEN + size - buf.len());
+            return Ok(None);
+        }
+
+        buf.advance(PREFIX_LEN);
+        let frame = buf.split_to(size);
+        match str::from_utf8(&frame) {
+            Ok(frame) => Ok(Some(frame.into())),
+            Err(e) => Err(FrameError::InvalidUtf8(e)),
+        }
+    }
+
+    /// Like `decode`, once nothing more will be added to `buf`. Anything left
+    /// over that isn't a complete frame is an error.
+    pub fn decode_eof(
+        &self,
+        buf: &mut BytesMut,
+    ) -> Result<Option<String>, FrameError> {
+        match self.decode(buf)? {
+            Some(frame) => Ok(Some(frame)),
+            None if buf.is_empty() => Ok(None),
+            None => {
+                let expected = if buf.len() < PREFIX_LEN {
+                    PREFIX_LEN
+                } else {
+                    PREFIX_LEN + frame_size(buf)
+                };
+                Err(FrameError::Truncated {
+                    expected,
+                    received: buf.len(),
+                })
+            }
+        }
+    }
+
+    /// Append `msg` to `buf` as a frame
+    pub fn encode(
+        &self,
+        msg: &str,
+        buf: &mut BytesMut,
+    ) -> Result<(), FrameError> {
+        let size = msg.len();
+        if size > self.max_frame_size || size > u32::MAX as usize {
+            return Err(FrameError::Oversize {
+                size,
+                max: self.max_frame_size.min(u32::MAX as usize),
+            });
+        }
+        buf.reserve(PREFIX_LEN + size);
+        buf.put_u32(size as u32);
+        buf.put_slice(msg.as_bytes());
+        Ok(())
+    }
+
+    /// Write `msg` to `writer` as a single frame
+    pub async fn write<W: AsyncWrite + Unpin>(
+        &self,
+        writer: &mut W,
+        msg: &str,
+    ) -> Result<(), FrameError> {
+        let mut buf = BytesMut::new();
+        self.encode(msg, &mut buf)?;
+        writer.write_all(&buf).await?;
+        Ok(())
+    }
+}
+
+impl Default for FrameCodec {
+    fn default() -> Self {
+        FrameCodec::new(DEFAULT_MAX_FRAME_SIZE)
+    }
+}
+
+/// Reads frames off a stream. Partly read frames are kept between calls, so
+/// `read_frame` can be given up on, e.g. in a `select!`, without losing data.
+pub struct FrameReader<R> {
+    reader: R,
+    codec: FrameCodec,
+    buf: BytesMut,
+}
+
+impl<R: AsyncRead + Unpin> FrameReader<R> {
+    pub fn new(reader: R, codec: FrameCodec) -> Self {
+        FrameReader {
+            reader,
+            codec,
+            buf: BytesMut::new(),
+        }
+    }
+
+    /// The next frame, or None once the stream closed between two frames
+    pub async fn read_frame(&mut self) -> Result<Option<String>, FrameError> {
+        loop {
+            if let Some(frame) = self.codec.decode(&mut self.buf)? {
+                return Ok(Some(frame));
+            }
+            if self.reader.read_buf(&mut self.buf).await? == 0 {
+                return self.codec.decode_eof(&mut self.buf);
+            }
+        }
+    }
+
+    /// The next frame parsed as json, or None once the stream closed
+    pub async fn read_msg<T: DeserializeOwned>(
+        &mut self,
+    ) -> Result<Option<T>, FrameError> {
+        match self.read_frame().await? {
+            Some(frame) => serde_json::from_str(&frame)
+                .map(Some)
+                .map_err(FrameError::InvalidJson),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Write `msg` to `writer` as a single frame of at most the default size
+pub async fn write_frame<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    msg: &str,
+) -> Result<(), FrameError> {
+    FrameCodec::default().write(writer, msg).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn frame(msg: &[u8]) -> Vec<u8> {
+        let mut frame = (msg.len() as u32).to_be_bytes().to_vec();
+        frame.extend_from_slice(msg);
+        frame
+    }
+
+    #[test]
+    fn test_decode_partial_frames() {
+        let codec = FrameCodec::default();
+        let mut stream = frame(b"hello");
+        stream.extend(frame(b""));
+        stream.extend(frame(b"world"));
+
+        // Feed the frames in a byte at a time
+        let mut buf = BytesMut::new();
+        let mut frames = vec![];
+        for byte in stream {
+            buf.put_u8(byte);
+            while let Some(frame) = codec.decode(&mut buf).unwrap() {
+                frames.push(frame);
+            }
+        }
+        assert_eq!(vec!["hello", "", "world"], frames);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let codec = FrameCodec::new(8);
+
+        // Oversize frames are refused before they are read in
+        let mut buf = BytesMut::from(&u32::MAX.to_be_bytes()[..]);
+        match codec.decode(&mut buf) {
+            Err(FrameError::Oversize { size, max: 8 }) => {
+                assert_eq!(u32::MAX as usize, size)
+            }
+            result => panic!("Unexpected result {:?}", result),
+        }
+
+        let mut buf = BytesMut::from(&frame(b"\xff\xfe")[..]);
+        assert!(matches!(
+            codec.decode(&mut buf),
+            Err(FrameError::InvalidUtf8(_))
+        ));
+
+        let mut buf = BytesMut::from(&frame(b"hello")[..7]);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        match codec.decode_eof(&mut buf) {
+            Err(FrameError::Truncated { expected, received }) => {
+                assert_eq!((9, 7), (expected, received))
+            }
+            result => panic!("Unexpected result {:?}", result),
+        }
+
+        let mut buf = BytesMut::from(&[0, 0][..]);
+        assert!(matches!(
+            codec.decode_eof(&mut buf),
+            Err(FrameError::Truncated {
+                expected: 4,
+                received: 2
+            })
+        ));
+        assert!(codec.decode_eof(&mut BytesMut::new()).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_encode() {
+        let codec = FrameCodec::new(8);
+        let mut buf = BytesMut::new();
+        codec.encode("hello", &mut buf).unwrap();
+        assert_eq!(frame(b"hello"), buf.to_vec());
+        assert!(matches!(
+            codec.encode("too long!", &mut buf),
+            Err(FrameError::Oversize { size: 9, max: 8 })
+        ));
+    }
+
+    #[test]
+    fn test_from_config() {
+        let codec = |config| FrameCodec::from_config(&config).max_frame_size();
+        assert_eq!(DEFAULT_MAX_FRAME_SIZE, codec(serde_json::json!({})));
+        assert_eq!(
+            64 << 20,
+            codec(serde_json::json!({ "maxframesize": 64 << 20 }))
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid maxframesize")]
+    fn test_invalid_max_frame_size() {
+        FrameCodec::from_config(&serde_json::json!({ "maxframesize": -1 }));
+    }
+
+    #[tokio::test]
+    async fn test_reader() {
+        let mut stream = frame(br#"{"type": "response"}"#);
+        stream.extend(frame(b"not json"));
+        stream.extend(frame(b"hi"));
+        let mut reader = FrameReader::new(&stream[..], FrameCodec::default());
+
+        let msg: serde_json::Value = reader.read_msg().await.unwrap().unwrap();
+        assert_eq!("response", msg["type"]);
+        assert!(matches!(
+            reader.read_msg::<serde_json::Value>().await,
+            Err(FrameError::InvalidJson(_))
+        ));
+        assert_eq!(Some("hi".into()), reader.read_frame().await.unwrap());
+        assert!(reader.read_frame().await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,617 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Result;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use async_process::{ChildStdin, ChildStdout, Command, Stdio};
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use futures::FutureExt;
+use futures_lite::{io::BufReader, prelude::*};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio::time::{timeout, Duration};
+
+use crate::comm::{self, Control, OutFile, Receiver, SendError, Sender};
+use crate::signalcli::{self, SignalCliConfig};
+use crate::supervisor::{supervise, Backoff, LinkMonitor, Service};
+
+/// An error returned by signal-cli for a request
+#[derive(Clone, Debug, PartialEq)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+    pub data: serde_json::Value,
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (code {})", self.message, self.code)
+    }
+}
+
+type RpcResult = std::result::Result<serde_json::Value, RpcError>;
+
+/// How long signal-cli gets to answer a send, which may upload attachments
+const SEND_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Writes requests to signal-cli and hands each response back to whoever made
+/// the request with the same id.
+struct RpcClient {
+    requests: mpsc::UnboundedSender<String>,
+    pending: Mutex<HashMap<u64, oneshot::Sender<RpcResult>>>,
+    next_id: AtomicU64,
+}
+
+impl RpcClient {
+    /// Make a request and wait up to `limit` for its result. A request that
+    /// isn't answered in time is forgotten, so a late answer is dropped.
+    /// Returns None if it timed out, and Some(Err) if signal-cli went away.
+    async fn call_within(
+        &self,
+        method: &str,
+        params: serde_json::Value,
+        limit: Duration,
+    ) -> Option<std::result::Result<RpcResult, oneshot::error::RecvError>> {
+        let (id, result) = self.call(method, params);
+        match timeout(limit, result).await {
+            Ok(result) => Some(result),
+            Err(_) => {
+                self.pending.lock().unwrap().remove(&id);
+                None
+            }
+        }
+    }
+
+    /// Queue a request, returning its id and a receiver for its result.
+    /// Requests are written in the order they are made.
+    fn call(
+        &self,
+        method: &str,
+        params: serde_json::Value,
+    ) -> (u64, oneshot::Receiver<RpcResult>) {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let (tx, rx) = oneshot::channel();
+        let request = serde_json::json!({
+            "jsonrpc": "2.0",
+            "method": method,
+            "params": params,
+            "id": id,
+        });
+
+        // Queued under the lock, so `JsonRpcService::stopped` never sees a
+        // request without its pending entry or the other way around
+        let mut pending = self.pending.lock().unwrap();
+        if self.requests.send(request.to_string()).is_ok() {
+            pending.insert(id, tx);
+        }
+        // Otherwise the writer is gone, dropping tx tells the caller
+        (id, rx)
+    }
+
+    fn handle_response(&self, response: &serde_json::Value) {
+        let id = match response["id"].as_u64() {
+            Some(id) => id,
+            None => {
+                eprintln!("Got response without id: {}", response);
+                return;
+            }
+        };
+        let tx = match self.pending.lock().unwrap().remove(&id) {
+            Some(tx) => tx,
+            None => {
+                eprintln!("Got response for unknown request {}", id);
+                return;
+            }
+        };
+
+        let result = match response.get("error") {
+            Some(error) => Err(RpcError {
+                code: error["code"].as_i64().unwrap_or(0),
+                message: error["message"].as_str().unwrap_or("").into(),
+                data: error["data"].clone(),
+            }),
+            None => Ok(response["result"].clone()),
+        };
+        let _ = tx.send(result);
+    }
+}
+
+pub struct JsonRpcReceiver {
+    recv_chan: mpsc::Receiver<String>,
+}
+
+pub struct JsonRpcSender {
+    client: Arc<RpcClient>,
+}
+
+/// A single long running `signal-cli jsonRpc` process used both to receive
+/// and to send messages.
+pub struct SignalCliJsonRpc {
+    task: JoinHandle<()>,
+    send_chan: Arc<mpsc::Sender<String>>,
+}
+
+struct JsonRpcService {
+    signalcli: SignalCliConfig,
+    user: String,
+    client: Arc<RpcClient>,
+    requests: mpsc::UnboundedReceiver<String>,
+    send_chan: Arc<mpsc::Sender<String>>,
+}
+
+#[async_trait]
+impl Service for JsonRpcService {
+    fn command(&self) -> Command {
+        let mut cmd = self.signalcli.command();
+        cmd.arg("-u")
+            .arg(&self.user)
+            .arg("jsonRpc")
+            .args(&self.signalcli.daemon_args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped());
+        cmd
+    }
+
+    async fn run(
+        &mut self,
+        stdin: Option<ChildStdin>,
+        stdout: Option<ChildStdout>,
+    ) {
+        let (stdin, stdout) = match (stdin, stdout) {
+            (Some(stdin), Some(stdout)) => (stdin, stdout),
+            _ => return,
+        };
+        let JsonRpcService {
+            client,
+            requests,
+            send_chan,
+            ..
+        } = self;
+        let read = async {
+            let mut lines = BufReader::new(stdout).lines();
+            while let Some(Ok(line)) = lines.next().await {
+                JsonRpcService::handle_line(&line, client, send_chan).await;
+            }
+            eprintln!("signal-cli jsonRpc closed stdout");
+        };
+        tokio::select! {
+            _ = read => {}
+            _ = JsonRpcService::write_requests(stdin, requests) => {}
+        }
+    }
+
+    fn stopped(&mut self) {
+        // Whatever was still in flight is lost with the process. Requests
+        // that were never written are dropped too: their callers are told
+        // they failed and may retry, so they mustn't be sent on restart.
+        let mut pending = self.client.pending.lock().unwrap();
+        while let Some(Some(_)) = self.requests.recv().now_or_never() {}
+        pending.clear();
+    }
+}
+
+impl JsonRpcService {
+    async fn write_requests(
+        mut stdin: ChildStdin,
+        requests: &mut mpsc::UnboundedReceiver<String>,
+    ) {
+        while let Some(request) = requests.recv().await {
+            let line = request + "\n";
+            if let Err(e) = stdin.write_all(line.as_bytes()).await {
+                eprintln!("Writing to signal-cli failed: {}", e);
+                break;
+            }
+            let _ = stdin.flush().await;
+        }
+    }
+
+    async fn handle_line(
+        line: &str,
+        client: &RpcClient,
+        send_chan: &mpsc::Sender<String>,
+    ) {
+        let msg = match serde_json::from_str::<serde_json::Value>(line) {
+            Ok(msg) => msg,
+            Err(_) => {
+                if !line.is_empty() {
+                    eprintln!("Ignoring output from signal-cli: {:?}", line);
+                }
+                return;
+            }
+        };
+
+        match msg["method"].as_str() {
+            // Incoming messages have the same shape as `receive --output=json`
+            // lines, so they can be handed on as is.
+            Some("receive") => send_chan
+                .send(msg["params"].to_string())
+                .await
+                .expect("Sending line failed!"),
+            Some(method) => eprintln!("Ignoring {} notification", method),
+            None => client.handle_response(&msg),
+        }
+    }
+}
+
+impl SignalCliJsonRpc {
+    pub fn new(
+        signalcli: &SignalCliConfig,
+        user: &str,
+        monitor: Arc<LinkMonitor>,
+    ) -> Result<(Self, JsonRpcReceiver, JsonRpcSender)> {
+        let (tx, rx) = mpsc::channel(100);
+        let recv_chan = rx;
+        let send_chan = Arc::new(tx);
+
+        let (requests, request_chan) = mpsc::unbounded_channel();
+        let client = Arc::new(RpcClient {
+            requests,
+            pending: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+        });
+
+        let service = JsonRpcService {
+            signalcli: signalcli.clone(),
+            user: user.into(),
+            client: client.clone(),
+            requests: request_chan,
+            send_chan: send_chan.clone(),
+        };
+        let task = tokio::spawn(supervise(
+            "signal-cli jsonRpc".into(),
+            service,
+            monitor,
+            Backoff::default(),
+        ));
+
+        Ok((
+            SignalCliJsonRpc { task, send_chan },
+            JsonRpcReceiver { recv_chan },
+            JsonRpcSender { client },
+        ))
+    }
+}
+
+impl Drop for SignalCliJsonRpc {
+    fn drop(&mut self) {
+        // Dropping the supervisor kills signal-cli
+        self.task.abort();
+    }
+}
+
+#[async_trait]
+impl Control for SignalCliJsonRpc {
+    async fn insert_msg(&self, msg: &str) {
+        self.send_chan
+            .send(msg.to_string())
+            .await
+            .expect("Sending control msg failed!");
+    }
+}
+
+#[async_trait]
+impl Receiver for JsonRpcReceiver {
+    async fn get_msg(&mut self) -> Option<String> {
+        self.recv_chan.recv().await
+    }
+}
+
+/// Percent-encode the characters that would end a data URI parameter or start
+/// another, so that a file name from an app stays a single parameter
+fn encode_filename(filename: &str) -> String {
+    let mut encoded = String::with_capacity(filename.len());
+    for c in filename.chars() {
+        match c {
+            c if c.is_ascii_control() || ";,%=".contains(c) => {
+                encoded.push_str(&format!("%{:02X}", c as u32))
+            }
+            c => encoded.push(c),
+        }
+    }
+    encoded
+}
+
+/// Files are sent inline as data URIs so that they don't have to outlive the
+/// call to send.
+fn attachment_param(attachment: &OutFile) -> String {
+    match attachment {
+        OutFile::Path(path) => path.to_string_lossy().into(),
+        OutFile::Inline {
+            data,
+            filename,
+            content_type,
+        } => {
+            let content_type = content_type
+                .as_deref()
+                .unwrap_or("application/octet-stream");
+            let filename = match filename {
+                Some(filename) => {
+                    format!(";filename={}", encode_filename(filename))
+                }
+                None => "".into(),
+            };
+            format!(
+                "data:{}{};base64,{}",
+                content_type,
+                filename,
+                BASE64.encode(data)
+            )
+        }
+    }
+}
+
+fn send_params(
+    dest: &str,
+    msg: &str,
+    attachments: &[OutFile],
+) -> serde_json::Value {
+    let mut params = serde_json::json!({ "message": msg });
+    match comm::group_id(dest) {
+        Some(group) => params["groupId"] = group.into(),
+        None => params["recipient"] = serde_json::json!([dest]),
+    }
+    if !attachments.is_empty() {
+        let attachments: Vec<_> =
+            attachments.iter().map(attachment_param).collect();
+        params["attachments"] = attachments.into();
+    }
+    params
+}
+
+/// Map the result of a send request to a `SendError`. signal-cli reports a
+/// result per recipient, failed sends either come back as a successful request
+/// with failed results or as an error carrying those results.
+fn send_result(result: RpcResult) -> std::result::Result<(), SendError> {
+    let (results, message) = match &result {
+        Ok(value) => (&value["results"], None),
+        Err(e) => (&e.data["response"]["results"], Some(&e.message)),
+    };
+
+    for result in results.as_array().into_iter().flatten() {
+        let kind = result["type"].as_str().unwrap_or("SUCCESS");
+        let reason = format!(
+            "{} for {}",
+            kind,
+            result["recipientAddress"]["number"]
+                .as_str()
+                .unwrap_or("recipient")
+        );
+        match kind {
+            "SUCCESS" => {}
+            "NETWORK_FAILURE" => return Err(SendError::Network(reason)),
+            "UNREGISTERED_FAILURE" => {
+                return Err(SendError::Unregistered(reason))
+            }
+            "RATE_LIMIT_FAILURE" => return Err(SendError::RateLimited(reason)),
+            "IDENTITY_FAILURE" => {
+                return Err(SendError::UntrustedIdentity(reason))
+            }
+            _ => return Err(SendError::Other(reason)),
+        }
+    }
+
+    match message {
+        Some(message) => Err(signalcli::classify_error(message)),
+        None => Ok(()),
+    }
+}
+
+#[async_trait]
+impl Sender for JsonRpcSender {
+    async fn send(
+        &self,
+        dest: &str,
+        msg: &str,
+    ) -> std::result::Result<(), SendError> {
+        self.send_attachments(dest, msg, &[]).await
+    }
+
+    async fn send_attachments(
+        &self,
+        dest: &str,
+        msg: &str,
+        attachments: &[OutFile],
+    ) -> std::result::Result<(), SendError> {
+        let params = send_params(dest, msg, attachments);
+        match self.client.call_within("send", params, SEND_TIMEOUT).await {
+            Some(Ok(result)) => send_result(result),
+            // signal-cli exited before answering, it is being restarted
+            Some(Err(_)) => {
+                Err(SendError::Network("signal-cli went away".into()))
+            }
+            // signal-cli is alive but stuck, let the send be retried
+            None => Err(SendError::Network("signal-cli did not answer".into())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_send_params() {
+        assert_eq!(
+            serde_json::json!({ "message": "hi", "recipient": ["+1555"] }),
+            send_params("+1555", "hi", &[])
+        );
+
+        let attachments = vec![
+            OutFile::Path("/tmp/cat.png".into()),
+            OutFile::Inline {
+                data: b"hello".to_vec(),
+                filename: Some("hello.txt".into()),
+                content_type: Some("text/plain".into()),
+            },
+        ];
+        assert_eq!(
+            serde_json::json!({
+                "message": "hi",
+                "groupId": "abc=",
+                "attachments": [
+                    "/tmp/cat.png",
+                    "data:text/plain;filename=hello.txt;base64,aGVsbG8="
+                ]
+            }),
+            send_params(&comm::group_key("abc="), "hi", &attachments)
+        );
+
+        // Names can't add parameters to the data URI or end it early
+        let attachments = vec![OutFile::Inline {
+            data: b"hello".to_vec(),
+            filename: Some("a;base64,b=%\n.txt".into()),
+            content_type: None,
+        }];
+        assert_eq!(
+            serde_json::json!(["data:application/octet-stream;\
+                 filename=a%3Bbase64%2Cb%3D%25%0A.txt;base64,aGVsbG8="]),
+            send_params("+1555", "hi", &attachments)["attachments"]
+        );
+    }
+
+    #[test]
+    fn test_send_result() {
+        assert_eq!(
+            Ok(()),
+            send_result(Ok(serde_json::json!({
+                "results": [{"type": "SUCCESS"}],
+                "timestamp": 1
+            })))
+        );
+        assert_eq!(
+            Err(SendError::Network("NETWORK_FAILURE for +1555".into())),
+            send_result(Ok(serde_json::json!({
+                "results": [{
+                    "type": "NETWORK_FAILURE",
+                    "recipientAddress": {"number": "+1555"}
+                }]
+            })))
+        );
+        assert_eq!(
+            Err(SendError::Unregistered(
+                "UNREGISTERED_FAILURE for +1555".into()
+            )),
+            send_result(Err(RpcError {
+                code: -1,
+                message: "Failed to send message".into(),
+                data: serde_json::json!({"response": {"results": [{
+                    "type": "UNREGISTERED_FAILURE",
+                    "recipientAddress": {"number": "+1555"}
+                }]}}),
+            }))
+        );
+        assert_eq!(
+            Err(SendError::Other("Invalid group id".into())),
+            send_result(Err(RpcError {
+                code: -1,
+                message: "Invalid group id".into(),
+                data: serde_json::Value::Null,
+            }))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_responses_matched_by_id() {
+        let (requests, mut written) = mpsc::unbounded_channel();
+        let client = RpcClient {
+            requests,
+            pending: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+        };
+
+        let (_, first) = client.call("send", serde_json::json!({}));
+        let (_, second) = client.call("send", serde_json::json!({}));
+        for id in 0..2 {
+            let request: serde_json::Value =
+                serde_json::from_str(&written.recv().await.unwrap()).unwrap();
+            assert_eq!(id, request["id"]);
+            assert_eq!("send", request["method"]);
+        }
+
+        client.handle_response(&serde_json::json!({
+            "jsonrpc": "2.0",
+            "error": { "code": -1, "message": "Unregistered user" },
+            "id": 1
+        }));
+        client.handle_response(&serde_json::json!({
+            "jsonrpc": "2.0",
+            "result": { "timestamp": 1 },
+            "id": 0
+        }));
+
+        assert_eq!(
+            Ok(serde_json::json!({ "timestamp": 1 })),
+            first.await.unwrap()
+        );
+        assert_eq!(
+            Err(RpcError {
+                code: -1,
+                message: "Unregistered user".into(),
+                data: serde_json::Value::Null,
+            }),
+            second.await.unwrap()
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_send_times_out() {
+        let (requests, mut written) = mpsc::unbounded_channel();
+        let client = Arc::new(RpcClient {
+            requests,
+            pending: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+        });
+        let sender = JsonRpcSender {
+            client: client.clone(),
+        };
+
+        // signal-cli takes the request but never answers it
+        assert_eq!(
+            Err(SendError::Network("signal-cli did not answer".into())),
+            sender.send("+1555", "hi").await
+        );
+        assert!(written.recv().await.is_some());
+        assert!(client.pending.lock().unwrap().is_empty());
+
+        // So the answer, when it does come, goes nowhere
+        client.handle_response(&serde_json::json!({
+            "jsonrpc": "2.0",
+            "result": {},
+            "id": 0
+        }));
+    }
+
+    #[tokio::test]
+    async fn test_stopped_drops_queued_requests() {
+        let (requests, request_chan) = mpsc::unbounded_channel();
+        let client = Arc::new(RpcClient {
+            requests,
+            pending: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+        });
+        let mut service = JsonRpcService {
+            signalcli: SignalCliConfig::from_config(&serde_json::json!({})),
+            user: "+15555555".into(),
+            client: client.clone(),
+            requests: request_chan,
+            send_chan: Arc::new(mpsc::channel(1).0),
+        };
+
+        // Neither was written before signal-cli went away
+        let (_, first) = client.call("send", serde_json::json!({}));
+        let (_, second) = client.call("send", serde_json::json!({}));
+        service.stopped();
+        assert!(first.await.is_err());
+        assert!(second.await.is_err());
+
+        // So only requests made since are written to the new process
+        let (_, _third) = client.call("send", serde_json::json!({}));
+        let request: serde_json::Value =
+            serde_json::from_str(&service.requests.recv().await.unwrap())
+                .unwrap();
+        assert_eq!(2, request["id"]);
+        assert_eq!(None, service.requests.recv().now_or_never());
+    }
+}
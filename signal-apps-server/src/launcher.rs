@@ -0,0 +1,539 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+
+use async_process::{Child, Command, Stdio};
+use futures::future;
+use serde::Deserialize;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout, Duration, Instant};
+
+use crate::supervisor::{log_output, Backoff, STABLE_AFTER};
+
+/// How long to wait for a freshly started app to create its socket
+const SOCKET_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often to look for the socket while waiting for it
+const SOCKET_POLL: Duration = Duration::from_millis(50);
+
+/// How long an app gets to exit after being asked to stop, before it's killed
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// When to restart an app that exited
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartPolicy {
+    Always,
+    #[default]
+    OnFailure, // only when it crashed or exited with an error
+    Never,
+}
+
+impl RestartPolicy {
+    fn should_restart(self, status: Option<ExitStatus>) -> bool {
+        match self {
+            RestartPolicy::Always => true,
+            RestartPolicy::OnFailure => !status.is_some_and(|s| s.success()),
+            RestartPolicy::Never => false,
+        }
+    }
+}
+
+/// How to run an app, read from `<name>.json` in the `manifestdir` of the
+/// config:
+///
+/// ```json
+/// {
+///     "command": ["python3", "echo.py", "/etc/signal-apps/config.json"],
+///     "workdir": "/opt/signal-apps/echo",
+///     "env": { "PYTHONUNBUFFERED": "1" },
+///     "socket": "echo",
+///     "restart": "on-failure"
+/// }
+/// ```
+///
+/// Only `command` is required. `socket` is the name of the socket the app
+/// creates in `appdir`, and the name users start it with. It defaults to the
+/// name of the manifest. `restart` is one of `always`, `on-failure` or
+/// `never`.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct AppManifest {
+    #[serde(skip)]
+    pub name: String,
+    pub command: Vec<String>,
+    pub workdir: Option<PathBuf>,
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    pub socket: Option<String>,
+    #[serde(default)]
+    pub restart: RestartPolicy,
+}
+
+impl AppManifest {
+    pub fn parse(name: &str, manifest: &str) -> io::Result<Self> {
+        let mut manifest: AppManifest = serde_json::from_str(manifest)
+            .map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid manifest for {}: {}", name, e),
+                )
+            })?;
+        if manifest.command.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("manifest for {} has an empty command", name),
+            ));
+        }
+        manifest.name = name.into();
+        Ok(manifest)
+    }
+
+    /// Read every `*.json` manifest in `dir`, sorted by name
+    pub fn load_dir(dir: &Path) -> io::Result<Vec<Self>> {
+        let mut manifests = vec![];
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                continue;
+            }
+            let name = match path.file_stem().and_then(|s| s.to_str()) {
+                Some(name) => name.to_string(),
+                None => continue,
+            };
+            manifests.push(Self::parse(&name, &fs::read_to_string(&path)?)?);
+        }
+        manifests.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(manifests)
+    }
+
+    /// The manifests in the `manifestdir` of the config, if one is set
+    pub fn from_config(config: &serde_json::Value) -> io::Result<Vec<Self>> {
+        match config["manifestdir"].as_str() {
+            Some(dir) => Self::load_dir(Path::new(dir)),
+            None => Ok(vec![]),
+        }
+    }
+
+    pub fn socket_path(&self, app_dir: &Path) -> PathBuf {
+        app_dir.join(self.socket.as_deref().unwrap_or(&self.name))
+    }
+
+    fn command(&self, app_dir: &Path) -> Command {
+        let mut cmd = Command::new(&self.command[0]);
+        cmd.args(&self.command[1..])
+            .envs(&self.env)
+            .env("SIGNAL_APPS_SOCKET", self.socket_path(app_dir))
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        if let Some(workdir) = &self.workdir {
+            cmd.current_dir(workdir);
+        }
+        cmd
+    }
+}
+
+struct LaunchedApp {
+    name: String,
+    stop: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+}
+
+/// The app processes started from manifests, each restarted according to its
+/// restart policy until `stop` is called.
+#[derive(Default)]
+pub struct AppLauncher {
+    apps: Vec<LaunchedApp>,
+}
+
+impl AppLauncher {
+    /// Start an app for every manifest, and wait for them to create their
+    /// sockets so that users can start them straight away.
+    pub async fn start(manifests: Vec<AppManifest>, app_dir: &Path) -> Self {
+        let mut apps = vec![];
+        let mut ready = vec![];
+        for manifest in manifests {
+            let (stop, stopped) = oneshot::channel();
+            let (started, listening) = oneshot::channel();
+            let name = manifest.name.clone();
+            let app_dir = app_dir.to_path_buf();
+            let task = tokio::spawn(async move {
+                run_app(manifest, app_dir, started, stopped).await
+            });
+            ready.push((name.clone(), listening));
+            apps.push(LaunchedApp { name, stop, task });
+        }
+
+        let waits = ready.into_iter().map(|(name, listening)| async move {
+            match timeout(SOCKET_TIMEOUT, listening).await {
+                Ok(Ok(())) => {}
+                Ok(Err(_)) => eprintln!("App {} could not be started", name),
+                Err(_) => eprintln!(
+                    "App {} did not create its socket within {:?}",
+                    name, SOCKET_TIMEOUT
+                ),
+            }
+        });
+        future::join_all(waits).await;
+        AppLauncher { apps }
+    }
+
+    /// Stop every app, and wait for them to exit
+    pub async fn stop(self) {
+        let mut tasks = vec![];
+        for app in self.apps {
+            eprintln!("Stopping app {}", app.name);
+            let _ = app.stop.send(());
+            tasks.push(app.task);
+        }
+        future::join_all(tasks).await;
+    }
+}
+
+/// The device and inode of a socket, to tell the one an app created from
+/// whatever is at its path later
+type SocketId = (u64, u64);
+
+fn socket_id(path: &Path) -> Option<SocketId> {
+    let metadata = fs::symlink_metadata(path).ok()?;
+    if !metadata.file_type().is_socket() {
+        return None;
+    }
+    Some((metadata.dev(), metadata.ino()))
+}
+
+/// Remove the socket at `path`, if it's still the one with `id`
+fn remove_socket(path: &Path, id: Option<SocketId>) {
+    if id.is_some() && socket_id(path) == id {
+        let _ = fs::remove_file(path);
+    }
+}
+
+/// Make way for an app to create its socket at `path`, by removing a socket
+/// nothing listens on anymore, e.g. one left behind by a crash. Anything else
+/// there is left alone, and an error.
+fn clear_stale_socket(path: &Path) -> io::Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{:?} is not a socket", path),
+        ));
+    }
+    if UnixStream::connect(path).is_ok() {
+        return Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("{:?} is already being listened on", path),
+        ));
+    }
+    fs::remove_file(path)
+}
+
+async fn wait_for_socket(path: &Path) -> SocketId {
+    loop {
+        if let Some(id) = socket_id(path) {
+            return id;
+        }
+        sleep(SOCKET_POLL).await;
+    }
+}
+
+/// Ask `child` to exit with SIGTERM, and kill it if it doesn't in time
+async fn terminate(name: &str, child: &mut Child) {
+    unsafe {
+        libc::kill(child.id() as libc::pid_t, libc::SIGTERM);
+    }
+    if timeout(STOP_TIMEOUT, child.status()).await.is_err() {
+        eprintln!("App {} did not stop, killing it", name);
+        let _ = child.kill();
+        let _ = child.status().await;
+    }
+}
+
+/// Keep the app of `manifest` running until `stopped`. `started` is sent on
+/// once its socket first shows up, and dropped if it exits before that and
+/// isn't restarted.
+async fn run_app(
+    manifest: AppManifest,
+    app_dir: PathBuf,
+    started: oneshot::Sender<()>,
+    mut stopped: oneshot::Receiver<()>,
+) {
+    let name = manifest.name.clone();
+    let socket = manifest.socket_path(&app_dir);
+    let mut started = Some(started);
+    let mut backoff = Backoff::default();
+    loop {
+        // Don't mistake the socket of a previous run for the new one
+        if let Err(e) = clear_stale_socket(&socket) {
+            eprintln!("Not starting app {}: {}", name, e);
+            return;
+        }
+        let since = Instant::now();
+        let mut created = None;
+        let status = match manifest.command(&app_dir).spawn() {
+            Ok(mut child) => {
+                eprintln!("Started app {}", name);
+                if let Some(stdout) = child.stdout.take() {
+                    tokio::spawn(log_output(name.clone(), stdout));
+                }
+                if let Some(stderr) = child.stderr.take() {
+                    tokio::spawn(log_output(name.clone(), stderr));
+                }
+
+                loop {
+                    tokio::select! {
+                        status = child.status() => break status.ok(),
+                        id = wait_for_socket(&socket), if created.is_none() => {
+                            eprintln!(
+                                "App {} is listening on {:?}",
+                                name, socket
+                            );
+                            created = Some(id);
+                            if let Some(started) = started.take() {
+                                let _ = started.send(());
+                            }
+                        }
+                        _ = &mut stopped => {
+                            terminate(&name, &mut child).await;
+                            remove_socket(&socket, created);
+                            return;
+                        }
+                    }
+                }
+            }
+            Err(e) => {
+                eprintln!("Could not start app {}: {}", name, e);
+                None
+            }
+        };
+
+        eprintln!("App {} exited with {:?}", name, status);
+        remove_socket(&socket, created);
+        if !manifest.restart.should_restart(status) {
+            return;
+        }
+        if since.elapsed() >= STABLE_AFTER {
+            backoff.reset();
+        }
+        let delay = backoff.next_delay();
+        eprintln!("Restarting app {} in {:?}", name, delay);
+        tokio::select! {
+            _ = sleep(delay) => {}
+            _ = &mut stopped => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::io::Write;
+    use std::os::unix::net::UnixListener;
+    use std::process;
+    use std::thread;
+
+    use tempdir::TempDir;
+
+    use super::*;
+
+    /// Tells this test binary to act as an app, see `fixture`
+    const FIXTURE: &str = "SIGNAL_APPS_LAUNCHER_FIXTURE";
+
+    /// A manifest running `fixture` in `dir`, doing `what`
+    fn fixture_manifest(dir: &Path, what: &str) -> AppManifest {
+        let exe = env::current_exe().unwrap();
+        AppManifest {
+            name: "app".into(),
+            command: vec![
+                exe.to_str().unwrap().into(),
+                "launcher::test::fixture".into(),
+                "--exact".into(),
+                "--ignored".into(),
+            ],
+            workdir: Some(dir.into()),
+            env: [(FIXTURE.to_string(), what.to_string())].into(),
+            socket: None,
+            restart: RestartPolicy::OnFailure,
+        }
+    }
+
+    /// The app the tests below launch. It adds its pid to `runs`, then either
+    /// listens until it's killed, or with `crash` listens for a bit and fails,
+    /// except on its third run, which exits straight away.
+    #[test]
+    #[ignore]
+    fn fixture() {
+        let what = match env::var(FIXTURE) {
+            Ok(what) => what,
+            Err(_) => return,
+        };
+        let mut runs = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open("runs")
+            .unwrap();
+        writeln!(runs, "{}", process::id()).unwrap();
+        let count = fs::read_to_string("runs").unwrap().lines().count();
+        if what == "crash" && count >= 3 {
+            process::exit(0);
+        }
+
+        let _listener =
+            UnixListener::bind(env::var("SIGNAL_APPS_SOCKET").unwrap())
+                .unwrap();
+        if what == "crash" {
+            thread::sleep(Duration::from_millis(100));
+            process::exit(1);
+        }
+        loop {
+            thread::sleep(Duration::from_secs(60));
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let manifest = AppManifest::parse(
+            "echo",
+            r#"{
+                "command": ["python3", "echo.py"],
+                "workdir": "/opt/echo",
+                "env": { "A": "b" },
+                "restart": "always"
+            }"#,
+        )
+        .unwrap();
+        assert_eq!("echo", manifest.name);
+        assert_eq!(vec!["python3", "echo.py"], manifest.command);
+        assert_eq!(Some(PathBuf::from("/opt/echo")), manifest.workdir);
+        assert_eq!("b", manifest.env["A"]);
+        assert_eq!(RestartPolicy::Always, manifest.restart);
+        assert_eq!(
+            PathBuf::from("/apps/echo"),
+            manifest.socket_path(Path::new("/apps"))
+        );
+
+        let manifest =
+            AppManifest::parse("x", r#"{"command": ["x"], "socket": "y"}"#)
+                .unwrap();
+        assert_eq!(RestartPolicy::OnFailure, manifest.restart);
+        assert_eq!(
+            PathBuf::from("/apps/y"),
+            manifest.socket_path(Path::new("/apps"))
+        );
+
+        for bad in [
+            r#"{"command": []}"#,
+            r#"{"workdir": "/"}"#,
+            r#"{"command": ["x"], "restart": "sometimes"}"#,
+            r#"{"command": ["x"], "bogus": 1}"#,
+        ] {
+            let e = AppManifest::parse("bad", bad).unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidData, e.kind(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_load_dir() {
+        let tmp_dir = TempDir::new("manifests").expect("tempdir failed!");
+        fs::write(tmp_dir.path().join("b.json"), r#"{"command": ["b"]}"#)
+            .unwrap();
+        fs::write(tmp_dir.path().join("a.json"), r#"{"command": ["a"]}"#)
+            .unwrap();
+        fs::write(tmp_dir.path().join("README"), "not a manifest").unwrap();
+
+        let config = serde_json::json!({ "manifestdir": tmp_dir.path() });
+        let names: Vec<_> = AppManifest::from_config(&config)
+            .unwrap()
+            .into_iter()
+            .map(|m| m.name)
+            .collect();
+        assert_eq!(vec!["a", "b"], names);
+        assert!(AppManifest::from_config(&serde_json::json!({}))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_restart_policy() {
+        let ok = Some(ExitStatus::default());
+        assert!(RestartPolicy::Always.should_restart(ok));
+        assert!(!RestartPolicy::OnFailure.should_restart(ok));
+        assert!(RestartPolicy::OnFailure.should_restart(None));
+        assert!(!RestartPolicy::Never.should_restart(None));
+    }
+
+    #[tokio::test]
+    async fn test_waits_for_socket_and_stops() {
+        let tmp_dir = TempDir::new("apps").expect("tempdir failed!");
+        let socket = tmp_dir.path().join("app");
+        // A socket left behind by a previous run is no reason not to start
+        drop(UnixListener::bind(&socket).unwrap());
+        let manifests = vec![fixture_manifest(tmp_dir.path(), "listen")];
+
+        let launcher = AppLauncher::start(manifests, tmp_dir.path()).await;
+        UnixStream::connect(&socket).expect("app isn't listening");
+
+        launcher.stop().await;
+        assert!(!socket.exists());
+        let runs = fs::read_to_string(tmp_dir.path().join("runs")).unwrap();
+        let pid: libc::pid_t = runs.trim().parse().unwrap();
+        // The process is gone and has been reaped
+        assert_eq!(-1, unsafe { libc::kill(pid, 0) });
+    }
+
+    #[tokio::test]
+    async fn test_keeps_other_files() {
+        let tmp_dir = TempDir::new("apps").expect("tempdir failed!");
+        let socket = tmp_dir.path().join("app");
+        let manifest = fixture_manifest(tmp_dir.path(), "listen");
+        let run = |manifest| {
+            let (started, listening) = oneshot::channel();
+            let (_stop, stopped) = oneshot::channel();
+            let app_dir = tmp_dir.path().into();
+            tokio::spawn(run_app(manifest, app_dir, started, stopped));
+            listening
+        };
+
+        // Neither a file that isn't a socket nor one in use is replaced
+        fs::write(&socket, "not a socket").unwrap();
+        assert!(run(manifest.clone()).await.is_err());
+        assert_eq!("not a socket", fs::read_to_string(&socket).unwrap());
+
+        fs::remove_file(&socket).unwrap();
+        let _listener = UnixListener::bind(&socket).unwrap();
+        assert!(run(manifest).await.is_err());
+        UnixStream::connect(&socket).expect("socket was removed");
+        assert!(!tmp_dir.path().join("runs").exists());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_restarts_crashed_app() {
+        let tmp_dir = TempDir::new("apps").expect("tempdir failed!");
+        let manifest = fixture_manifest(tmp_dir.path(), "crash");
+        let (started, listening) = oneshot::channel();
+        let (_stop, stopped) = oneshot::channel();
+
+        // Stops once the app exits cleanly, on its third run
+        run_app(manifest, tmp_dir.path().into(), started, stopped).await;
+        assert!(listening.await.is_ok());
+        let runs = fs::read_to_string(tmp_dir.path().join("runs")).unwrap();
+        assert_eq!(3, runs.lines().count());
+
+        // Sockets left behind by a crash don't count as the app listening
+        let socket = tmp_dir.path().join("app");
+        assert!(!socket.exists());
+        UnixListener::bind(&socket).unwrap();
+    }
+}
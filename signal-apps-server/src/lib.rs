@@ -0,0 +1,22 @@
+//! The signal-apps server, connecting Signal users to apps. The binary in
+//! `main.rs` wires these together, they are a library so that benchmarks and
+//! other binaries can drive them too.
+pub mod app;
+pub mod appstate;
+pub mod check;
+pub mod comm;
+pub mod command;
+pub mod console;
+pub mod frame;
+pub mod jsonrpc;
+pub mod launcher;
+pub mod mux;
+pub mod native;
+pub mod protocol;
+pub mod signalcli;
+pub mod stdio;
+pub mod supervisor;
+pub mod tcp;
+pub mod transport;
+pub mod wasm;
+pub mod webhook;
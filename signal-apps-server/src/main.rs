@@ -1,40 +1,97 @@
+use std::env;
 use std::fs;
 use std::io::Result;
+use std::path::{Path, PathBuf};
+use std::process;
 
-use clap::clap_app;
+use clap::{clap_app, AppSettings, Arg, SubCommand};
 use futures::{join, stream::StreamExt};
-use serde_json;
 use signal_hook::consts::signal::SIGINT;
 use signal_hook_tokio::Signals;
-
-mod app;
-mod appstate;
-mod comm;
-mod signalcli;
-
-use crate::app::UnixStreamApp;
-use crate::appstate::{AppMsg, AppState};
-use crate::comm::{Control, Receiver, Sender};
-use crate::signalcli::SignalCliDaemon;
-
-fn get_msg(msg: &serde_json::Value) -> Option<(&str, &str)> {
-    let envelope = &msg["envelope"];
-    if let Some(source) = envelope["source"].as_str() {
-        if let Some(content) = envelope["dataMessage"]["message"].as_str() {
-            return Some((source, content));
+use tokio::sync::mpsc;
+use tokio::time::Duration;
+
+use signal_apps::app;
+use signal_apps::appstate::{AppMsg, AppState};
+use signal_apps::check;
+use signal_apps::comm::{Control, Receiver, Sender};
+use signal_apps::console::Console;
+use signal_apps::frame::FrameCodec;
+use signal_apps::jsonrpc::SignalCliJsonRpc;
+use signal_apps::launcher::{AppLauncher, AppManifest};
+use signal_apps::signalcli::{get_msg, SignalCliConfig, SignalCliDaemon};
+use signal_apps::supervisor::{Alert, AlertCommand, LinkMonitor};
+use signal_apps::transport::TransportApp;
+
+/// Where signal-cli saves attachments, unless set with `attachmentdir`
+fn get_attachment_dir(config: &serde_json::Value) -> PathBuf {
+    if let Some(dir) = config["attachmentdir"].as_str() {
+        return PathBuf::from(dir);
+    }
+    match SignalCliConfig::from_config(config).data_dir {
+        Some(data_dir) => data_dir.join("attachments"),
+        None => {
+            let home = env::var("HOME").unwrap_or_default();
+            Path::new(&home).join(".local/share/signal-cli/attachments")
         }
     }
+}
 
-    None
+/// How to reach the admin while signal-cli is down: `alertcommand` from the
+/// config, or else signal-cli run just to message `admin`
+fn get_alert_command(
+    config: &serde_json::Value,
+    signalcli: &SignalCliConfig,
+    user: &str,
+) -> Option<AlertCommand> {
+    if let Some(command) = config["alertcommand"].as_array() {
+        let mut args = command.iter().map(|arg| match arg.as_str() {
+            Some(arg) => arg.to_string(),
+            None => panic!("Invalid alertcommand argument {:?}", arg),
+        });
+        return Some(AlertCommand {
+            program: args.next().expect("alertcommand is empty").into(),
+            args: args.collect(),
+            when_up: true,
+        });
+    }
+    let admin = config["admin"].as_str()?;
+    Some(signalcli.alert_command(user, admin))
+}
+
+/// Hand every alert on to the admin. The link to Signal can't carry an alert
+/// about itself being down, so those go through `command`.
+async fn forward_alerts(
+    mut alerts: mpsc::UnboundedReceiver<Alert>,
+    command: Option<AlertCommand>,
+    state_queue: mpsc::Sender<AppMsg>,
+) {
+    while let Some(alert) = alerts.recv().await {
+        let (msg, up) = match alert {
+            Alert::Down(msg) => (msg, false),
+            Alert::Up(msg) => (msg, true),
+        };
+        match &command {
+            Some(command) if !up || command.when_up => {
+                eprintln!("Alert: {}", msg);
+                command.run(&msg).await;
+            }
+            _ => {
+                if state_queue.send(AppMsg::Alert(msg)).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
 }
 
 async fn signal_handler<C: Control>(control: C) {
-    let signals = Signals::new(&[SIGINT]).unwrap();
+    let signals = Signals::new([SIGINT]).unwrap();
     let handle = signals.handle();
 
     let mut signals = signals.fuse();
     eprintln!("Waiting for signals");
-    while let None = signals.next().await {}
+    while signals.next().await.is_none() {}
     eprintln!("Got exit signal");
     handle.close();
 
@@ -46,22 +103,37 @@ async fn main_loop<App, C, R, S>(
     control: C,
     mut recv: R,
     sender: S,
+    alerts: mpsc::UnboundedReceiver<Alert>,
+    alert_command: Option<AlertCommand>,
     config: serde_json::Value,
 ) where
     App: app::App,
-    C: Control,
+    C: Control + Send + Sync + 'static,
     R: Receiver,
-    S: Sender + Send + Sync,
+    S: Sender + 'static,
 {
+    let attachment_dir = get_attachment_dir(&config);
+    let launcher = match AppManifest::from_config(&config) {
+        Ok(manifests) => {
+            let app_dir = config["appdir"].as_str().unwrap_or_default();
+            AppLauncher::start(manifests, Path::new(app_dir)).await
+        }
+        Err(e) => {
+            eprintln!("Error: could not read app manifests: {}", e);
+            process::exit(1);
+        }
+    };
     let new_app = AppState::new(config, sender);
     let mut state: AppState<App, _> = new_app.0;
     let state_queue = new_app.1;
 
+    tokio::spawn(forward_alerts(alerts, alert_command, state_queue.clone()));
+
     let main_thread = async {
         println!("Setup main_thread");
         loop {
             let msg = recv.get_msg().await;
-            let msg = match msg.as_ref().map(String::as_str) {
+            let msg = match msg.as_deref() {
                 None => {
                     eprintln!("No further msgs");
                     break;
@@ -73,15 +145,15 @@ async fn main_loop<App, C, R, S>(
                 Some(msg) => msg,
             };
 
-            if let Ok(Some((source, msg))) =
+            if let Ok(Some((session, author, msg, attachments))) =
                 serde_json::from_str::<serde_json::Value>(msg)
                     .as_ref()
-                    .map(get_msg)
+                    .map(|msg| get_msg(msg, &attachment_dir))
             {
-                let source = source.to_string();
+                let author = author.to_string();
                 let msg = msg.to_string();
                 state_queue
-                    .send(AppMsg::InMsg(source, msg))
+                    .send(AppMsg::InMsg(session, author, msg, attachments))
                     .await
                     .expect("enqueing task failed!");
             }
@@ -96,9 +168,11 @@ async fn main_loop<App, C, R, S>(
         eprintln!("Exiting main thread");
     };
 
-    join!(main_thread, signal_handler(control), state.process_queue());
-    // TODO prevent drop of control until after the join completes.
-    //   We want to know that the daemon lives during state.drain.
+    // Input can also just run out, so don't wait for a signal after that
+    let signals = tokio::spawn(signal_handler(control));
+    join!(main_thread, state.process_queue());
+    signals.abort();
+    launcher.stop().await;
 }
 
 #[tokio::main]
@@ -108,20 +182,101 @@ async fn main() -> Result<()> {
         (author: "Aneesh Durg <aneeshdurg17@gmail.com>")
         (about: "Run a signal app server")
         (@arg CONFIG: -c --config +required +takes_value "Path to config json")
+        (@arg BACKEND: -b --backend +takes_value
+            possible_values(&["jsonrpc", "dbus", "console"])
+            default_value("jsonrpc")
+            "How to talk to signal-cli, or console to chat on stdin/stdout")
+    )
+    .setting(AppSettings::SubcommandsNegateReqs)
+    .subcommand(
+        SubCommand::with_name("check-app")
+            .about(
+                "Check that the app listening on a socket follows the protocol",
+            )
+            .arg(
+                Arg::with_name("SOCKET")
+                    .required(true)
+                    .help("Path to the app's socket"),
+            ),
     )
     .get_matches();
 
+    if let Some(check) = matches.subcommand_matches("check-app") {
+        let socket = check.value_of("SOCKET").unwrap();
+        // Hold the app to the server's frame size limit, if given its config
+        let codec = match matches.value_of("CONFIG") {
+            Some(config) => FrameCodec::from_config(&serde_json::from_str(
+                &fs::read_to_string(config)?,
+            )?),
+            None => FrameCodec::default(),
+        };
+        let report =
+            check::check_app(Path::new(socket), codec, check::REPLY_TIMEOUT)
+                .await;
+        print!("{}", report);
+        process::exit(if report.violations() == 0 { 0 } else { 1 });
+    }
+
     let config = matches.value_of("CONFIG").unwrap();
     let config: serde_json::Value =
         serde_json::from_str(&fs::read_to_string(config)?)?;
 
+    let backend = matches.value_of("BACKEND").unwrap();
+    if backend == "console" {
+        let (control, recv, send) = Console::new()?;
+        // There's no link to Signal to watch
+        let (_, alerts) = mpsc::unbounded_channel();
+        main_loop::<TransportApp, _, _, _>(
+            control, recv, send, alerts, None, config,
+        )
+        .await;
+        return Ok(());
+    }
+
     let user = config["username"]
         .as_str()
         .expect("config json needs a username key");
     eprintln!("Starting as user {:?}", user);
 
-    let (control, recv, send) = SignalCliDaemon::new(user)?;
-    main_loop::<UnixStreamApp, _, _, _>(control, recv, send, config).await;
+    let signalcli = SignalCliConfig::from_config(&config);
+    if let Err(e) = signalcli.check(user).await {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
+
+    // Tell the admin if signal-cli stays down for `alertafter` seconds
+    let threshold = config["alertafter"].as_u64().unwrap_or(300);
+    let (monitor, alerts) = LinkMonitor::new(Duration::from_secs(threshold));
+    let alert_command = get_alert_command(&config, &signalcli, user);
+
+    match backend {
+        "dbus" => {
+            let (control, recv, send) =
+                SignalCliDaemon::new(&signalcli, user, monitor)?;
+            main_loop::<TransportApp, _, _, _>(
+                control,
+                recv,
+                send,
+                alerts,
+                alert_command,
+                config,
+            )
+            .await;
+        }
+        _ => {
+            let (control, recv, send) =
+                SignalCliJsonRpc::new(&signalcli, user, monitor)?;
+            main_loop::<TransportApp, _, _, _>(
+                control,
+                recv,
+                send,
+                alerts,
+                alert_command,
+                config,
+            )
+            .await;
+        }
+    }
 
     Ok(())
 }
@@ -133,9 +288,12 @@ mod test {
     use std::process;
 
     use async_trait::async_trait;
+    use futures::future;
     use tempdir::TempDir;
-    use tokio::sync::mpsc;
-    use tokio::time::{sleep, Duration};
+    use tokio::time::sleep;
+
+    use signal_apps::comm::SendError;
+    use signal_apps::protocol;
 
     use super::*;
 
@@ -150,10 +308,30 @@ mod test {
         }
     }
 
+    #[async_trait]
     impl Sender for MockSender {
-        fn send(&self, dest: &str, msg: &str) {
+        async fn send(
+            &self,
+            dest: &str,
+            msg: &str,
+        ) -> std::result::Result<(), SendError> {
             let sender = self.channel.clone();
-            sender.send((dest.to_string(), msg.to_string())).unwrap()
+            sender.send((dest.to_string(), msg.to_string())).unwrap();
+            Ok(())
+        }
+    }
+
+    /// A sender whose link is down, so sending never finishes
+    pub struct DownSender;
+
+    #[async_trait]
+    impl Sender for DownSender {
+        async fn send(
+            &self,
+            _: &str,
+            _: &str,
+        ) -> std::result::Result<(), SendError> {
+            future::pending().await
         }
     }
 
@@ -195,12 +373,18 @@ mod test {
     struct MockApp {
         id: u64,
         name: String,
-        messages: Vec<String>,
+        messages: Vec<protocol::ToApp>,
     }
 
     #[async_trait]
     impl app::App for MockApp {
-        async fn get_description(_: &str, _: &str) -> io::Result<String> {
+        type Shared = ();
+
+        async fn get_description(
+            _: &(),
+            _: &str,
+            _: &str,
+        ) -> io::Result<String> {
             Ok("".into())
         }
 
@@ -220,12 +404,12 @@ mod test {
             &self.name
         }
 
-        async fn start(&mut self, _: &str, _: &str) -> io::Result<()> {
+        async fn start(&mut self, _: &(), _: &str, _: &str) -> io::Result<()> {
             Ok(())
         }
 
-        async fn send(&mut self, msg: &str) {
-            self.messages.push(msg.into());
+        async fn send(&mut self, msg: &protocol::ToApp) {
+            self.messages.push(msg.clone());
         }
 
         async fn stop(&mut self) {}
@@ -245,15 +429,75 @@ mod test {
             let config = serde_json::json!({
                 "appdir": tmp_dir.path().to_str()
             });
-            main_loop::<MockApp, _, _, _>(control, recv, send, config).await;
+            let (_, alerts) = mpsc::unbounded_channel();
+            main_loop::<MockApp, _, _, _>(
+                control, recv, send, alerts, None, config,
+            )
+            .await;
         };
 
         let t2 = async {
             channel.send("startapp app".into()).unwrap();
             sleep(Duration::from_millis(250)).await;
-            unsafe { libc::kill(process::id() as i32, libc::SIGINT); }
+            unsafe {
+                libc::kill(process::id() as i32, libc::SIGINT);
+            }
         };
 
         join!(t1, t2);
     }
+
+    #[tokio::test]
+    async fn test_stops_when_input_ends() {
+        let tmp_dir = TempDir::new("apps").expect("create tempdir failed!");
+        let config = serde_json::json!({
+            "appdir": tmp_dir.path().to_str()
+        });
+        let input: &[u8] = b"+1555> listapps\n";
+        let (control, recv, send) = Console::from_reader(input).unwrap();
+        let (_, alerts) = mpsc::unbounded_channel();
+        let main = main_loop::<MockApp, _, _, _>(
+            control, recv, send, alerts, None, config,
+        );
+        tokio::time::timeout(Duration::from_secs(5), main)
+            .await
+            .expect("main_loop kept running after its input ended");
+    }
+
+    #[tokio::test]
+    async fn test_alert_while_sender_down() {
+        let tmp_dir = TempDir::new("alerts").expect("create tempdir failed!");
+        let out = tmp_dir.path().join("alerts");
+        let config = serde_json::json!({
+            "appdir": tmp_dir.path().to_str(),
+            "admin": "+15555555",
+            "alertcommand": [
+                "sh",
+                "-c",
+                format!("echo \"$1\" >> {:?}", out),
+                "alert"
+            ]
+        });
+        let command = get_alert_command(
+            &config,
+            &SignalCliConfig::default(),
+            "+16666666",
+        );
+
+        let (mut state, state_queue) =
+            AppState::<MockApp, _>::new(config, DownSender);
+        tokio::spawn(async move { state.process_queue().await });
+        let (alerts, recv) = mpsc::unbounded_channel();
+        tokio::spawn(forward_alerts(recv, command, state_queue));
+
+        alerts.send(Alert::Down("it's down".into())).unwrap();
+        alerts.send(Alert::Up("it's up".into())).unwrap();
+        loop {
+            let sent = fs::read_to_string(&out).unwrap_or_default();
+            if sent == "it's down\nit's up\n" {
+                break;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+    }
 }
@@ -0,0 +1,341 @@
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::io;
+use std::mem;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use tokio::io::{split, AsyncRead, AsyncWrite};
+use tokio::sync::{mpsc, Mutex};
+
+use crate::app::{forward_msg, UnixStreamApp};
+use crate::appstate::{AppMsg, EndReason};
+use crate::frame::{FrameCodec, FrameReader};
+use crate::protocol::{self, FromApp, ToApp};
+
+/// Keeps track of which apps asked for their sessions to be multiplexed over a
+/// single connection, and the connections to those apps. Also holds the codec
+/// for every connection to apps of its transport.
+#[derive(Default)]
+pub struct MuxRegistry {
+    apps: Mutex<HashSet<String>>,
+    connections: Mutex<HashMap<String, Arc<MuxConnection>>>,
+    codec: FrameCodec,
+}
+
+impl MuxRegistry {
+    pub fn from_config(config: &serde_json::Value) -> Self {
+        MuxRegistry {
+            codec: FrameCodec::from_config(config),
+            ..Default::default()
+        }
+    }
+
+    pub fn codec(&self) -> FrameCodec {
+        self.codec
+    }
+
+    pub async fn set_multiplexed(&self, name: &str, multiplex: bool) {
+        let mut apps = self.apps.lock().await;
+        if multiplex {
+            apps.insert(name.into());
+        } else {
+            apps.remove(name);
+        }
+    }
+
+    pub async fn is_multiplexed(&self, name: &str) -> bool {
+        self.apps.lock().await.contains(name)
+    }
+
+    /// Get the connection to the socket of `name`, reconnecting if the app
+    /// went away.
+    pub async fn connect(
+        &self,
+        app_dir: &str,
+        name: &str,
+    ) -> io::Result<Arc<MuxConnection>> {
+        self.connect_with(name, || async {
+            let stream = UnixStreamApp::open_app_socket(app_dir, name).await?;
+            let (sr, sw) = split(stream);
+            Ok(MuxConnection::new(name, sr, sw, self.codec))
+        })
+        .await
+    }
+
+    /// Get the connection to `name`, opening a new one with `open` if there
+    /// isn't one or the app went away.
+    pub async fn connect_with<F, Fut>(
+        &self,
+        name: &str,
+        open: F,
+    ) -> io::Result<Arc<MuxConnection>>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = io::Result<Arc<MuxConnection>>>,
+    {
+        let mut connections = self.connections.lock().await;
+        if let Some(conn) = connections.get(name) {
+            if !conn.closed.load(Ordering::SeqCst) {
+                return Ok(conn.clone());
+            }
+        }
+
+        eprintln!("Opening multiplexed connection to {}", name);
+        let conn = open().await?;
+        connections.insert(name.into(), conn.clone());
+        Ok(conn)
+    }
+}
+
+struct Session {
+    user: String,
+    control: mpsc::Sender<AppMsg>,
+}
+
+/// A single connection to an app carrying many sessions. Each session is
+/// identified by its app id, and every frame is tagged with it.
+pub struct MuxConnection {
+    name: String,
+    writer: Mutex<Box<dyn AsyncWrite + Send + Unpin>>,
+    codec: FrameCodec,
+    sessions: Mutex<HashMap<u64, Session>>,
+    closed: AtomicBool,
+}
+
+impl MuxConnection {
+    /// Start multiplexing sessions over a connection to `name`
+    pub fn new<R, W>(
+        name: &str,
+        reader: R,
+        writer: W,
+        codec: FrameCodec,
+    ) -> Arc<Self>
+    where
+        R: AsyncRead + Send + Unpin + 'static,
+        W: AsyncWrite + Send + Unpin + 'static,
+    {
+        let conn = Arc::new(MuxConnection {
+            name: name.into(),
+            writer: Mutex::new(Box::new(writer)),
+            codec,
+            sessions: Mutex::new(HashMap::new()),
+            closed: AtomicBool::new(false),
+        });
+        tokio::spawn(conn.clone().read_loop(reader));
+        conn
+    }
+
+    pub async fn open(
+        &self,
+        id: u64,
+        user: &str,
+        control: mpsc::Sender<AppMsg>,
+    ) {
+        let session = Session {
+            user: user.into(),
+            control,
+        };
+        self.sessions.lock().await.insert(id, session);
+    }
+
+    pub async fn send(&self, msg: &ToApp) -> io::Result<()> {
+        let mut writer = self.writer.lock().await;
+        self.codec
+            .write(&mut *writer, &msg.encode())
+            .await
+            .map_err(io::Error::from)
+    }
+
+    pub async fn close(&self, id: u64) {
+        if self.sessions.lock().await.remove(&id).is_some() {
+            let _ = self.send(&ToApp::Close { session: id }).await;
+        }
+    }
+
+    async fn read_loop<R: AsyncRead + Unpin>(self: Arc<Self>, sr: R) {
+        // Unless the app tells us otherwise, the connection going away means
+        // that every session on it crashed.
+        let mut reason = EndReason::Crashed;
+        let mut reader = FrameReader::new(sr, self.codec);
+        loop {
+            let msg: FromApp = match reader.read_msg().await {
+                Ok(Some(msg)) => msg,
+                Ok(None) => break,
+                Err(e) => {
+                    eprintln!("App {} sent a bad frame: {}", self.name, e);
+                    break;
+                }
+            };
+
+            let id = match msg.session() {
+                Some(id) => id,
+                None => {
+                    if let FromApp::Hello { version, .. } = msg {
+                        if let Err(e) = protocol::check_version(version) {
+                            eprintln!(
+                                "App {} is incompatible: {}",
+                                self.name, e
+                            );
+                            reason = EndReason::Incompatible(version);
+                            break;
+                        }
+                    } else {
+                        eprintln!("App {} sent untagged {:?}", self.name, msg);
+                    }
+                    continue;
+                }
+            };
+
+            let session = self
+                .sessions
+                .lock()
+                .await
+                .get(&id)
+                .map(|s| (s.user.clone(), s.control.clone()));
+            let (user, control) = match session {
+                Some(session) => session,
+                None => {
+                    eprintln!(
+                        "App {} sent msg for unknown session {}",
+                        self.name, id
+                    );
+                    continue;
+                }
+            };
+
+            if let Some(end) =
+                forward_msg(msg, id, &self.name, &user, &control).await
+            {
+                self.sessions.lock().await.remove(&id);
+                control
+                    .send(AppMsg::EndMsg(user, id, end))
+                    .await
+                    .expect("Sending control msg failed!");
+            }
+        }
+
+        eprintln!("Closed multiplexed connection to {}", self.name);
+        self.closed.store(true, Ordering::SeqCst);
+        let sessions = mem::take(&mut *self.sessions.lock().await);
+        for (id, session) in sessions {
+            session
+                .control
+                .send(AppMsg::EndMsg(session.user, id, reason.clone()))
+                .await
+                .expect("Sending control msg failed!");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tempdir::TempDir;
+    use tokio::io::{ReadHalf, WriteHalf};
+    use tokio::net::{UnixListener, UnixStream};
+
+    use super::*;
+    use crate::app::App;
+    use crate::frame;
+
+    async fn read_msg(
+        reader: &mut FrameReader<ReadHalf<UnixStream>>,
+    ) -> serde_json::Value {
+        reader
+            .read_msg()
+            .await
+            .expect("read failed!")
+            .expect("stream closed!")
+    }
+
+    async fn write_msg(sw: &mut WriteHalf<UnixStream>, msg: serde_json::Value) {
+        frame::write_frame(sw, &msg.to_string())
+            .await
+            .expect("write failed!");
+    }
+
+    #[tokio::test]
+    async fn test_sessions_share_connection() {
+        let tmp_dir = TempDir::new("apps").expect("create tempdir failed!");
+        let listener = UnixListener::bind(tmp_dir.path().join("app"))
+            .expect("bind failed!");
+        let app_dir = tmp_dir.path().to_str().unwrap().to_string();
+        let shared = MuxRegistry::default();
+        let (control, mut recv) = mpsc::channel(100);
+
+        let query = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (sr, mut sw) = split(stream);
+            let mut sr = FrameReader::new(sr, FrameCodec::default());
+            read_msg(&mut sr).await;
+            write_msg(
+                &mut sw,
+                serde_json::json!({
+                    "type": "hello",
+                    "version": protocol::VERSION,
+                    "multiplex": true
+                }),
+            )
+            .await;
+            write_msg(
+                &mut sw,
+                serde_json::json!({"type": "response", "value": "desc"}),
+            )
+            .await;
+            listener
+        });
+        UnixStreamApp::get_description(&shared, &app_dir, "app")
+            .await
+            .expect("query failed!");
+        let listener = query.await.unwrap();
+        assert!(shared.is_multiplexed("app").await);
+
+        let mut apps = vec![];
+        for (id, user) in [(0, "+1"), (1, "+2")] {
+            let mut app = UnixStreamApp::new(id, "app", user, control.clone());
+            app.start(&shared, &app_dir, "app")
+                .await
+                .expect("start failed!");
+            app.send(&ToApp::start(user, false)).await;
+            apps.push(app);
+        }
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let (sr, mut sw) = split(stream);
+        let mut sr = FrameReader::new(sr, FrameCodec::default());
+        for (id, user) in [(0, "+1"), (1, "+2")] {
+            let msg = read_msg(&mut sr).await;
+            assert_eq!("start", msg["type"]);
+            assert_eq!(user, msg["user"]);
+            assert_eq!(id, msg["session"]);
+        }
+
+        write_msg(
+            &mut sw,
+            serde_json::json!({"type": "response", "value": "hi", "session": 1}),
+        )
+        .await;
+        match recv.recv().await {
+            Some(AppMsg::OutMsg(user, 1, msg, _)) => {
+                assert_eq!("+2", user);
+                assert_eq!("hi", msg);
+            }
+            msg => panic!("Unexpected control msg {:?}", msg),
+        }
+
+        apps[0].stop().await;
+        let msg = read_msg(&mut sr).await;
+        assert_eq!(serde_json::json!({"type": "close", "session": 0}), msg);
+
+        // Dropping the connection ends the remaining session
+        drop(sw);
+        drop(sr);
+        match recv.recv().await {
+            Some(AppMsg::EndMsg(user, 1, reason)) => {
+                assert_eq!("+2", user);
+                assert_eq!(EndReason::Crashed, reason);
+            }
+            msg => panic!("Unexpected control msg {:?}", msg),
+        }
+    }
+}
@@ -0,0 +1,89 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use tokio::sync::mpsc;
+
+use crate::app::forward_replies;
+use crate::appstate::AppMsg;
+use crate::protocol::{FromApp, ToApp};
+
+/// An app compiled into the server. It's listed and started like any other
+/// app, but runs inside the server instead of behind a socket.
+pub trait NativeApp: Send + Sync {
+    /// What `listapps` shows for the app
+    fn description(&self) -> String;
+
+    /// The state of a new session, which is sent `start` right away
+    fn session(&self) -> Box<dyn NativeSession>;
+}
+
+/// One session of a native app. It's sent the same messages as an app behind
+/// a socket, and answers with the messages such an app would send back.
+#[async_trait]
+pub trait NativeSession: Send + Sync {
+    async fn handle(&mut self, msg: &ToApp) -> Vec<FromApp>;
+}
+
+/// The native apps of a server, by name. A native app hides any app of the
+/// same name in the app directory or the config.
+#[derive(Clone, Default)]
+pub struct NativeApps {
+    apps: HashMap<String, Arc<dyn NativeApp>>,
+}
+
+impl NativeApps {
+    pub fn register<A: NativeApp + 'static>(&mut self, name: &str, app: A) {
+        self.apps.insert(name.into(), Arc::new(app));
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Arc<dyn NativeApp>> {
+        self.apps.get(name)
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.apps.keys().cloned().collect()
+    }
+}
+
+/// A running session of a native app
+pub struct NativeRunner {
+    id: u64,
+    name: String,
+    user: String,
+    control: mpsc::Sender<AppMsg>,
+    session: Box<dyn NativeSession>,
+}
+
+impl NativeRunner {
+    pub fn new(
+        app: &dyn NativeApp,
+        id: u64,
+        name: &str,
+        user: &str,
+        control: mpsc::Sender<AppMsg>,
+    ) -> Self {
+        NativeRunner {
+            id,
+            name: name.into(),
+            user: user.into(),
+            control,
+            session: app.session(),
+        }
+    }
+
+    pub fn get_id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    /// Hand a message to the app, and its replies to the server
+    pub async fn send(&mut self, msg: &ToApp) {
+        let replies = self.session.handle(msg).await;
+        let (id, name, user) = (self.id, &self.name, &self.user);
+        forward_replies(replies, id, name, user, &self.control).await;
+    }
+}
@@ -0,0 +1,313 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// The protocol version spoken by this server.
+pub const VERSION: u32 = 7;
+
+/// The oldest protocol version the server can still talk to. Version 1 is the
+/// original protocol without a handshake, any app that doesn't send `hello`
+/// is assumed to speak it.
+pub const MIN_VERSION: u32 = 1;
+
+/// A file received from a user. `path` is where signal-cli saved it locally.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Attachment {
+    pub content_type: String,
+    pub filename: Option<String>,
+    pub size: Option<u64>,
+    pub path: String,
+}
+
+/// A file an app wants to send to a user, either already on disk or inline as
+/// base64 encoded `data`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum OutAttachment {
+    Path {
+        path: String,
+    },
+    Inline {
+        data: String,
+        #[serde(default)]
+        filename: Option<String>,
+        #[serde(default)]
+        content_type: Option<String>,
+    },
+}
+
+/// Messages sent from the server to an app. On a multiplexed connection every
+/// session message is tagged with the id of the session it belongs to.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ToApp {
+    Query {
+        version: u32,
+    },
+    Start {
+        version: u32,
+        user: String,
+        #[serde(default)]
+        group: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        session: Option<u64>,
+    },
+    Msg {
+        data: String,
+        author: String,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        attachments: Vec<Attachment>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        session: Option<u64>,
+    },
+    Close {
+        session: u64,
+    },
+    Undelivered {
+        to: String,
+        error: String,
+        reason: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        session: Option<u64>,
+    },
+}
+
+/// Messages sent from an app to the server
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum FromApp {
+    Hello {
+        version: u32,
+        #[serde(default)]
+        multiplex: bool,
+    },
+    Response {
+        value: String,
+        #[serde(default)]
+        attachments: Vec<OutAttachment>,
+        #[serde(default)]
+        session: Option<u64>,
+    },
+    Send {
+        to: String,
+        value: String,
+        #[serde(default)]
+        session: Option<u64>,
+    },
+    Terminate {
+        #[serde(default)]
+        reason: String,
+        #[serde(default)]
+        session: Option<u64>,
+    },
+}
+
+#[derive(Debug)]
+pub enum ProtocolError {
+    /// The app announced a version this server can't talk to
+    Incompatible(u32),
+    /// The app sent something that isn't a valid message
+    Malformed(serde_json::Error),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProtocolError::Incompatible(version) => write!(
+                f,
+                "app speaks protocol version {}, server supports {}-{}",
+                version, MIN_VERSION, VERSION
+            ),
+            ProtocolError::Malformed(e) => {
+                write!(f, "malformed message: {}", e)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl ToApp {
+    pub fn query() -> Self {
+        ToApp::Query { version: VERSION }
+    }
+
+    pub fn start(user: &str, group: bool) -> Self {
+        ToApp::Start {
+            version: VERSION,
+            user: user.into(),
+            group,
+            session: None,
+        }
+    }
+
+    pub fn msg(author: &str, data: &str) -> Self {
+        ToApp::Msg {
+            data: data.into(),
+            author: author.into(),
+            attachments: vec![],
+            session: None,
+        }
+    }
+
+    /// Tag a session message with the given session id
+    pub fn with_session(&self, id: u64) -> Self {
+        let mut msg = self.clone();
+        match &mut msg {
+            ToApp::Start { session, .. }
+            | ToApp::Msg { session, .. }
+            | ToApp::Undelivered { session, .. } => *session = Some(id),
+            ToApp::Query { .. } | ToApp::Close { .. } => {}
+        }
+        msg
+    }
+
+    pub fn encode(&self) -> String {
+        serde_json::to_string(self).expect("Serializing msg failed!")
+    }
+}
+
+impl FromApp {
+    pub fn decode(msg: &str) -> Result<Self, ProtocolError> {
+        serde_json::from_str(msg).map_err(ProtocolError::Malformed)
+    }
+
+    /// The session a message is tagged with, if any
+    pub fn session(&self) -> Option<u64> {
+        match self {
+            FromApp::Hello { .. } => None,
+            FromApp::Response { session, .. }
+            | FromApp::Send { session, .. }
+            | FromApp::Terminate { session, .. } => *session,
+        }
+    }
+}
+
+pub fn check_version(version: u32) -> Result<(), ProtocolError> {
+    if (MIN_VERSION..=VERSION).contains(&version) {
+        Ok(())
+    } else {
+        Err(ProtocolError::Incompatible(version))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_encode_to_app() {
+        assert_eq!(
+            serde_json::json!({"type": "query", "version": VERSION}),
+            serde_json::from_str::<serde_json::Value>(&ToApp::query().encode())
+                .unwrap()
+        );
+        assert_eq!(
+            serde_json::json!({
+                "type": "start",
+                "version": VERSION,
+                "user": "+1555",
+                "group": false
+            }),
+            serde_json::from_str::<serde_json::Value>(
+                &ToApp::start("+1555", false).encode()
+            )
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_decode_from_app() {
+        assert_eq!(
+            FromApp::Hello {
+                version: 2,
+                multiplex: false
+            },
+            FromApp::decode(r#"{"type": "hello", "version": 2}"#).unwrap()
+        );
+        assert_eq!(
+            FromApp::Response {
+                value: "hi".into(),
+                attachments: vec![],
+                session: None
+            },
+            FromApp::decode(r#"{"type": "response", "value": "hi"}"#).unwrap()
+        );
+        assert_eq!(
+            FromApp::Response {
+                value: "".into(),
+                attachments: vec![
+                    OutAttachment::Path {
+                        path: "/tmp/a.png".into()
+                    },
+                    OutAttachment::Inline {
+                        data: "aGk=".into(),
+                        filename: Some("hi.txt".into()),
+                        content_type: None
+                    },
+                ],
+                session: None
+            },
+            FromApp::decode(
+                r#"{"type": "response", "value": "", "attachments": [
+                    {"path": "/tmp/a.png"},
+                    {"data": "aGk=", "filename": "hi.txt"}
+                ]}"#
+            )
+            .unwrap()
+        );
+        assert_eq!(
+            FromApp::Send {
+                to: "+1555".into(),
+                value: "hi".into(),
+                session: None
+            },
+            FromApp::decode(
+                r#"{"type": "send", "to": "+1555", "value": "hi"}"#
+            )
+            .unwrap()
+        );
+        assert_eq!(
+            FromApp::Terminate {
+                reason: "".into(),
+                session: None
+            },
+            FromApp::decode(r#"{"type": "terminate"}"#).unwrap()
+        );
+        assert_eq!(
+            Some(3),
+            FromApp::decode(
+                r#"{"type": "response", "value": "", "session": 3}"#
+            )
+            .unwrap()
+            .session()
+        );
+        assert!(FromApp::decode(r#"{"type": "bogus"}"#).is_err());
+        assert!(FromApp::decode(r#"{"type": "response"}"#).is_err());
+    }
+
+    #[test]
+    fn test_with_session() {
+        assert_eq!(
+            serde_json::json!({
+                "type": "msg",
+                "data": "hi",
+                "author": "+1555",
+                "session": 3
+            }),
+            serde_json::from_str::<serde_json::Value>(
+                &ToApp::msg("+1555", "hi").with_session(3).encode()
+            )
+            .unwrap()
+        );
+        assert_eq!(ToApp::query(), ToApp::query().with_session(3));
+    }
+
+    #[test]
+    fn test_check_version() {
+        assert!(check_version(MIN_VERSION).is_ok());
+        assert!(check_version(VERSION).is_ok());
+        assert!(check_version(0).is_err());
+        assert!(check_version(VERSION + 1).is_err());
+    }
+}
@@ -1,17 +1,204 @@
-use std::io::Result;
+use std::env;
+use std::fs;
+use std::io::{self, Result};
+use std::path::{Path, PathBuf};
+use std::process;
 use std::str;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use std::process;
 
-use async_process::{Child, Command, Stdio};
+use async_process::{ChildStdin, ChildStdout, Command, Stdio};
 use async_trait::async_trait;
-use futures_lite::{io::BufReader, prelude::*};
+use futures_lite::{future, io::BufReader, prelude::*};
 use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::comm::{self, Control, OutFile, Receiver, SendError, Sender};
+use crate::protocol::Attachment;
+use crate::supervisor::{
+    supervise, AlertCommand, Backoff, LinkMonitor, Service,
+};
+
+/// How to run signal-cli, from the `signalcli` section of the config:
+///
+/// ```json
+/// "signalcli": {
+///     "path": "/opt/signal-cli/bin/signal-cli",
+///     "datadir": "/var/lib/signal-apps/signal-cli",
+///     "args": ["--trust-new-identities", "always"],
+///     "daemonargs": [],
+///     "receiveargs": ["--ignore-attachments"]
+/// }
+/// ```
+///
+/// Every key is optional. `path` defaults to `signal-cli` on the `PATH`, and
+/// `datadir` is passed as signal-cli's `--config`. `args` are given to every
+/// invocation, before the subcommand. `daemonargs` are appended to the
+/// long-running `daemon` or `jsonRpc` process and `receiveargs` to `receive`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SignalCliConfig {
+    pub path: PathBuf,
+    pub data_dir: Option<PathBuf>,
+    pub args: Vec<String>,
+    pub daemon_args: Vec<String>,
+    pub receive_args: Vec<String>,
+}
+
+impl Default for SignalCliConfig {
+    fn default() -> Self {
+        SignalCliConfig {
+            path: PathBuf::from("signal-cli"),
+            data_dir: None,
+            args: vec![],
+            daemon_args: vec![],
+            receive_args: vec![],
+        }
+    }
+}
+
+impl SignalCliConfig {
+    pub fn from_config(config: &serde_json::Value) -> Self {
+        let config = &config["signalcli"];
+        let args = |key: &str| -> Vec<String> {
+            config[key]
+                .as_array()
+                .map(|args| {
+                    args.iter()
+                        .filter_map(|arg| arg.as_str().map(String::from))
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
 
-use crate::comm::{Control, Receiver, Sender};
+        let default = SignalCliConfig::default();
+        SignalCliConfig {
+            path: config["path"]
+                .as_str()
+                .map(PathBuf::from)
+                .unwrap_or(default.path),
+            data_dir: config["datadir"].as_str().map(PathBuf::from),
+            args: args("args"),
+            daemon_args: args("daemonargs"),
+            receive_args: args("receiveargs"),
+        }
+    }
 
-static SIGNALCLI_PATH: &str =
-    "../signal-cli/build/install/signal-cli/bin/signal-cli";
+    /// A signal-cli command with the global arguments already set, ready for
+    /// a subcommand.
+    pub fn command(&self) -> Command {
+        let mut cmd = Command::new(&self.path);
+        if let Some(data_dir) = &self.data_dir {
+            cmd.arg("--config").arg(data_dir);
+        }
+        cmd.args(&self.args);
+        cmd
+    }
+
+    /// A one-off `send` of alerts to `admin` as `user`, which works while
+    /// the long-running process is down. signal-cli can't run next to it, so
+    /// once it's back up alerts go through it instead.
+    pub fn alert_command(&self, user: &str, admin: &str) -> AlertCommand {
+        let mut args = vec![];
+        if let Some(data_dir) = &self.data_dir {
+            args.push("--config".into());
+            args.push(data_dir.to_string_lossy().into());
+        }
+        args.extend(self.args.iter().cloned());
+        for arg in ["-u", user, "send", admin, "-m"] {
+            args.push(arg.into());
+        }
+        AlertCommand {
+            program: self.path.clone(),
+            args,
+            when_up: false,
+        }
+    }
+
+    /// Make sure signal-cli can be run and that `user` is registered with it,
+    /// so that a broken setup fails at startup with an explanation rather than
+    /// with the first message.
+    pub async fn check(&self, user: &str) -> Result<()> {
+        let output = self
+            .command()
+            .args(["-u", user, "listIdentities"])
+            .stdin(Stdio::null())
+            .output()
+            .await
+            .map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!(
+                        "could not run signal-cli at {:?}: {} (set \
+                         signalcli.path in the config)",
+                        self.path, e
+                    ),
+                )
+            })?;
+        if output.status.success() {
+            return Ok(());
+        }
+
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let stderr = stderr.trim();
+        let lower = stderr.to_lowercase();
+        if lower.contains("not registered") || lower.contains("not found") {
+            Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "{} is not registered with signal-cli{}: {}",
+                    user,
+                    match &self.data_dir {
+                        Some(dir) => format!(" in {:?}", dir),
+                        None => "".into(),
+                    },
+                    stderr
+                ),
+            ))
+        } else {
+            Err(io::Error::other(format!(
+                "signal-cli failed ({}): {}",
+                output.status, stderr
+            )))
+        }
+    }
+}
+
+/// An inline attachment written out to a temporary file so that it can be
+/// handed to signal-cli. The file is removed once this is dropped.
+struct InlineFile {
+    dir: PathBuf,
+    path: PathBuf,
+}
+
+impl InlineFile {
+    fn new(data: &[u8], filename: Option<&str>) -> Result<Self> {
+        static COUNT: AtomicU64 = AtomicU64::new(0);
+
+        // Keep the name the app asked for (signal shows it to the user), but
+        // don't let it escape the directory.
+        let filename = filename
+            .and_then(|f| Path::new(f).file_name())
+            .unwrap_or_else(|| "attachment".as_ref());
+        let dir = env::temp_dir().join(format!(
+            "signal-apps-{}-{}",
+            process::id(),
+            COUNT.fetch_add(1, Ordering::SeqCst)
+        ));
+        fs::create_dir_all(&dir)?;
+        let file = InlineFile {
+            path: dir.join(filename),
+            dir,
+        };
+        fs::write(&file.path, data)?;
+        Ok(file)
+    }
+}
+
+impl Drop for InlineFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
 
 pub struct SignalCliReciever {
     recv_chan: mpsc::Receiver<String>,
@@ -19,71 +206,135 @@ pub struct SignalCliReciever {
 
 pub struct SignalCliSender {
     user: String,
+    signalcli: SignalCliConfig,
 }
 
 pub struct SignalCliDaemon {
-    _daemon: Child,
-    _recvproc: Child,
+    tasks: Vec<JoinHandle<()>>,
     send_chan: Arc<mpsc::Sender<String>>,
 }
 
-impl SignalCliDaemon {
-    pub fn new(
-        user: &str,
-    ) -> Result<(Self, SignalCliReciever, SignalCliSender)> {
-        let _daemon = Command::new(SIGNALCLI_PATH)
-            .arg("daemon")
-            .stdout(Stdio::null())
-            .kill_on_drop(true)
-            .spawn()?;
+/// `signal-cli daemon`, which owns the account and is used over dbus by
+/// everything else.
+struct DaemonService {
+    signalcli: SignalCliConfig,
+}
 
-        let (tx, rx) = mpsc::channel(100);
+#[async_trait]
+impl Service for DaemonService {
+    fn command(&self) -> Command {
+        let mut cmd = self.signalcli.command();
+        cmd.arg("daemon")
+            .args(&self.signalcli.daemon_args)
+            .stdout(Stdio::null());
+        cmd
+    }
 
-        let recv_chan = rx;
-        let send_chan = Arc::new(tx);
+    async fn run(&mut self, _: Option<ChildStdin>, _: Option<ChildStdout>) {
+        future::pending::<()>().await;
+    }
+}
+
+/// `signal-cli receive`, printing every incoming message as a line of json
+struct ReceiveService {
+    signalcli: SignalCliConfig,
+    user: String,
+    send_chan: Arc<mpsc::Sender<String>>,
+}
 
-        let mut _recvproc = Command::new(SIGNALCLI_PATH)
-            .arg("--dbus")
+#[async_trait]
+impl Service for ReceiveService {
+    fn command(&self) -> Command {
+        let mut cmd = self.signalcli.command();
+        cmd.arg("--dbus")
             .arg("--output=json")
             .arg("-u")
-            .arg(user)
+            .arg(&self.user)
             .arg("receive")
             .arg("--timeout")
             .arg("-1") /* disable timeout */
-            .stdout(Stdio::piped())
-            .kill_on_drop(true)
-            .spawn()?;
-        let recvout = _recvproc.stdout.take().unwrap();
-
-        {
-            let send_chan = send_chan.clone();
-            tokio::spawn(async move {
-                let mut lines = BufReader::new(recvout).lines();
-                // TODO max size for line?
-                while let Some(Ok(line)) = lines.next().await {
-                    if line.len() == 0 {
-                        // weird empty lines sometimes.
-                        continue;
-                    }
-                    send_chan.send(line).await.expect("Sending line failed!");
-                }
-            });
+            .args(&self.signalcli.receive_args)
+            .stdout(Stdio::piped());
+        cmd
+    }
+
+    async fn run(
+        &mut self,
+        _: Option<ChildStdin>,
+        stdout: Option<ChildStdout>,
+    ) {
+        let mut lines = match stdout {
+            Some(stdout) => BufReader::new(stdout).lines(),
+            None => return,
+        };
+        // TODO max size for line?
+        while let Some(Ok(line)) = lines.next().await {
+            if line.is_empty() {
+                // weird empty lines sometimes.
+                continue;
+            }
+            self.send_chan
+                .send(line)
+                .await
+                .expect("Sending line failed!");
         }
+    }
+}
+
+impl SignalCliDaemon {
+    pub fn new(
+        signalcli: &SignalCliConfig,
+        user: &str,
+        monitor: Arc<LinkMonitor>,
+    ) -> Result<(Self, SignalCliReciever, SignalCliSender)> {
+        let (tx, rx) = mpsc::channel(100);
+
+        let recv_chan = rx;
+        let send_chan = Arc::new(tx);
+
+        let daemon = DaemonService {
+            signalcli: signalcli.clone(),
+        };
+        let receive = ReceiveService {
+            signalcli: signalcli.clone(),
+            user: user.into(),
+            send_chan: send_chan.clone(),
+        };
+        let tasks = vec![
+            tokio::spawn(supervise(
+                "signal-cli daemon".into(),
+                daemon,
+                monitor.clone(),
+                Backoff::default(),
+            )),
+            tokio::spawn(supervise(
+                "signal-cli receive".into(),
+                receive,
+                monitor,
+                Backoff::default(),
+            )),
+        ];
 
         Ok((
-            SignalCliDaemon {
-                _daemon,
-                _recvproc,
-                send_chan,
-            },
+            SignalCliDaemon { tasks, send_chan },
             SignalCliReciever { recv_chan },
             SignalCliSender {
                 user: user.to_string(),
+                signalcli: signalcli.clone(),
             },
         ))
     }
 }
 
+impl Drop for SignalCliDaemon {
+    fn drop(&mut self) {
+        // Dropping the supervisors kills their processes
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
 #[async_trait]
 impl Control for SignalCliDaemon {
     async fn insert_msg(&self, msg: &str) {
@@ -101,16 +352,304 @@ impl Receiver for SignalCliReciever {
     }
 }
 
+/// Work out why signal-cli failed to send from its error output
+pub fn classify_error(msg: &str) -> SendError {
+    let lower = msg.to_lowercase();
+    let msg = msg.trim().to_string();
+    if lower.contains("unregistered") || lower.contains("not registered") {
+        SendError::Unregistered(msg)
+    } else if lower.contains("untrusted") {
+        SendError::UntrustedIdentity(msg)
+    } else if lower.contains("ratelimit")
+        || lower.contains("rate limit")
+        || lower.contains("413")
+        || lower.contains("429")
+    {
+        SendError::RateLimited(msg)
+    } else if lower.contains("network") || lower.contains("ioexception") {
+        SendError::Network(msg)
+    } else {
+        SendError::Other(msg)
+    }
+}
+
+#[async_trait]
 impl Sender for SignalCliSender {
-    fn send(&self, dest: &str, msg: &str) {
-        let dest = dest.to_string();
-        let msg = msg.to_string();
-        let user = self.user.clone();
+    async fn send(
+        &self,
+        dest: &str,
+        msg: &str,
+    ) -> std::result::Result<(), SendError> {
+        self.send_attachments(dest, msg, &[]).await
+    }
+
+    async fn send_attachments(
+        &self,
+        dest: &str,
+        msg: &str,
+        attachments: &[OutFile],
+    ) -> std::result::Result<(), SendError> {
+        // Inline files are removed when dropped, so keep them until sent
+        let mut inline = vec![];
+        let mut paths = vec![];
+        for attachment in attachments {
+            match attachment {
+                OutFile::Path(path) => paths.push(path.clone()),
+                OutFile::Inline { data, filename, .. } => {
+                    match InlineFile::new(data, filename.as_deref()) {
+                        Ok(file) => {
+                            paths.push(file.path.clone());
+                            inline.push(file);
+                        }
+                        Err(e) => {
+                            eprintln!("Dropping attachment for {}: {}", dest, e)
+                        }
+                    }
+                }
+            }
+        }
+
         eprintln!("Starting send proc");
-        process::Command::new(SIGNALCLI_PATH)
-            .args(&["--dbus", "-u", &user, "send", "-m", &msg, &dest])
+        let mut cmd = self.signalcli.command();
+        cmd.args(["--dbus", "-u", &self.user, "send", "-m", msg]);
+        match comm::group_id(dest) {
+            Some(group) => cmd.args(["-g", group]),
+            None => cmd.arg(dest),
+        };
+        // -a takes any number of files, so it has to come last
+        if !paths.is_empty() {
+            cmd.arg("-a").args(&paths);
+        }
+        let output = cmd
             .output()
-            .expect("Send failed!");
+            .await
+            .map_err(|e| SendError::Other(e.to_string()))?;
         eprintln!("Finished send proc");
+        drop(inline);
+
+        if output.status.success() {
+            Ok(())
+        } else {
+            Err(classify_error(&String::from_utf8_lossy(&output.stderr)))
+        }
+    }
+}
+
+pub type Msg<'a> = (String, &'a str, &'a str, Vec<Attachment>);
+
+/// Extract the session key, author, content and attachments of a message.
+/// Messages sent to a group belong to the group's session rather than the
+/// author's. Attachments are saved by signal-cli under `attachment_dir`.
+pub fn get_msg<'a>(
+    msg: &'a serde_json::Value,
+    attachment_dir: &Path,
+) -> Option<Msg<'a>> {
+    let envelope = &msg["envelope"];
+    let source = envelope["source"].as_str()?;
+    let data = &envelope["dataMessage"];
+
+    let attachments: Vec<_> = data["attachments"]
+        .as_array()
+        .map(|a| a.iter().filter_map(|a| get_attachment(a, attachment_dir)))
+        .into_iter()
+        .flatten()
+        .collect();
+    let content = match data["message"].as_str() {
+        Some(content) => content,
+        None if !attachments.is_empty() => "",
+        None => return None,
+    };
+
+    let session = match data["groupInfo"]["groupId"].as_str() {
+        Some(group) => comm::group_key(group),
+        None => source.to_string(),
+    };
+    Some((session, source, content, attachments))
+}
+
+fn get_attachment(
+    attachment: &serde_json::Value,
+    attachment_dir: &Path,
+) -> Option<Attachment> {
+    let id = match &attachment["id"] {
+        serde_json::Value::String(id) => id.clone(),
+        serde_json::Value::Number(id) => id.to_string(),
+        _ => return None,
+    };
+    Some(Attachment {
+        content_type: attachment["contentType"].as_str()?.into(),
+        filename: attachment["filename"].as_str().map(String::from),
+        size: attachment["size"].as_u64(),
+        path: attachment_dir.join(id).to_str()?.into(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::fs::PermissionsExt;
+
+    use tempdir::TempDir;
+
+    use super::*;
+
+    /// Write a shell script standing in for signal-cli
+    fn fake_signalcli(dir: &Path, script: &str) -> PathBuf {
+        let path = dir.join("signal-cli");
+        fs::write(&path, format!("#!/bin/sh\n{}\n", script))
+            .expect("write failed!");
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755))
+            .expect("chmod failed!");
+        path
+    }
+
+    #[test]
+    fn test_from_config() {
+        assert_eq!(
+            SignalCliConfig::default(),
+            SignalCliConfig::from_config(&serde_json::json!({}))
+        );
+        assert_eq!(
+            SignalCliConfig {
+                path: "/opt/signal-cli".into(),
+                data_dir: Some("/var/lib/signal-cli".into()),
+                args: vec!["--verbose".into()],
+                daemon_args: vec![],
+                receive_args: vec!["--ignore-attachments".into()],
+            },
+            SignalCliConfig::from_config(&serde_json::json!({
+                "signalcli": {
+                    "path": "/opt/signal-cli",
+                    "datadir": "/var/lib/signal-cli",
+                    "args": ["--verbose"],
+                    "receiveargs": ["--ignore-attachments"]
+                }
+            }))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_check() {
+        let tmp_dir =
+            TempDir::new("signalcli").expect("create tempdir failed!");
+        let mut signalcli = SignalCliConfig {
+            path: tmp_dir.path().join("missing"),
+            ..Default::default()
+        };
+        let e = signalcli.check("+1555").await.unwrap_err();
+        assert!(e.to_string().contains("could not run signal-cli"));
+
+        signalcli.path = fake_signalcli(
+            tmp_dir.path(),
+            r#"echo "User $2 is not registered." >&2; exit 1"#,
+        );
+        let e = signalcli.check("+1555").await.unwrap_err();
+        assert!(e.to_string().contains("+1555 is not registered"));
+
+        // Global arguments come before the subcommand
+        signalcli.data_dir = Some("/data".into());
+        signalcli.path = fake_signalcli(
+            tmp_dir.path(),
+            r#"[ "$*" = "--config /data -u +1555 listIdentities" ]"#,
+        );
+        signalcli.check("+1555").await.expect("check failed!");
+    }
+
+    #[tokio::test]
+    async fn test_alert_command() {
+        let tmp_dir =
+            TempDir::new("signalcli").expect("create tempdir failed!");
+        let out = tmp_dir.path().join("out");
+        let signalcli = SignalCliConfig {
+            path: fake_signalcli(
+                tmp_dir.path(),
+                &format!("echo \"$*\" > {:?}", out),
+            ),
+            data_dir: Some("/data".into()),
+            ..Default::default()
+        };
+
+        let command = signalcli.alert_command("+1555", "+1666");
+        assert!(!command.when_up);
+        command.run("it's down").await;
+        assert_eq!(
+            "--config /data -u +1555 send +1666 -m it's down\n",
+            fs::read_to_string(out).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_get_msg() {
+        let dir = Path::new("/attachments");
+        let msg = serde_json::json!({
+            "envelope": {
+                "source": "+1555",
+                "dataMessage": { "message": "hello" }
+            }
+        });
+        assert_eq!(
+            Some(("+1555".into(), "+1555", "hello", vec![])),
+            get_msg(&msg, dir)
+        );
+
+        let msg = serde_json::json!({
+            "envelope": {
+                "source": "+1555",
+                "dataMessage": {
+                    "message": "hello",
+                    "groupInfo": { "groupId": "abc=", "type": "DELIVER" }
+                }
+            }
+        });
+        assert_eq!(
+            Some((comm::group_key("abc="), "+1555", "hello", vec![])),
+            get_msg(&msg, dir)
+        );
+
+        let msg = serde_json::json!({
+            "envelope": { "source": "+1555", "receiptMessage": {} }
+        });
+        assert_eq!(None, get_msg(&msg, dir));
+    }
+
+    #[test]
+    fn test_get_msg_attachments() {
+        let dir = Path::new("/attachments");
+        let msg = serde_json::json!({
+            "envelope": {
+                "source": "+1555",
+                "dataMessage": {
+                    "message": null,
+                    "attachments": [{
+                        "contentType": "image/png",
+                        "filename": "cat.png",
+                        "id": "1234",
+                        "size": 3
+                    }, {
+                        "contentType": "audio/aac",
+                        "filename": null,
+                        "id": 5678,
+                        "size": null
+                    }]
+                }
+            }
+        });
+        let expected = vec![
+            Attachment {
+                content_type: "image/png".into(),
+                filename: Some("cat.png".into()),
+                size: Some(3),
+                path: "/attachments/1234".into(),
+            },
+            Attachment {
+                content_type: "audio/aac".into(),
+                filename: None,
+                size: None,
+                path: "/attachments/5678".into(),
+            },
+        ];
+        assert_eq!(
+            Some(("+1555".into(), "+1555", "", expected)),
+            get_msg(&msg, dir)
+        );
     }
 }
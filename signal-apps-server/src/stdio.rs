@@ -0,0 +1,341 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::PathBuf;
+
+use async_process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use async_trait::async_trait;
+use serde::Deserialize;
+use tokio::sync::mpsc;
+use tokio::time::{timeout, Duration};
+use tokio_util::compat::{
+    Compat, FuturesAsyncReadCompatExt, FuturesAsyncWriteCompatExt,
+};
+
+use crate::app::{query_app, App, StreamApp};
+use crate::appstate::AppMsg;
+use crate::mux::{MuxConnection, MuxRegistry};
+use crate::protocol::ToApp;
+use crate::supervisor::log_output;
+
+/// How long an app gets to exit once its stdin is closed, before it's killed
+const EXIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How to run an app that speaks the protocol on its stdin and stdout, set
+/// per app in the config as
+/// `"apps": { "<name>": { "transport": "stdio", "command": [...] } }`.
+/// `workdir` and `env` are optional.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct StdioCommand {
+    pub command: Vec<String>,
+    #[serde(default)]
+    pub workdir: Option<PathBuf>,
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+}
+
+impl StdioCommand {
+    /// Start the app, logging what it writes to stderr
+    fn spawn(
+        &self,
+        name: &str,
+    ) -> io::Result<(Child, Compat<ChildStdout>, Compat<ChildStdin>)> {
+        let mut cmd = Command::new(&self.command[0]);
+        cmd.args(&self.command[1..])
+            .envs(&self.env)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        if let Some(workdir) = &self.workdir {
+            cmd.current_dir(workdir);
+        }
+        let mut child = cmd.spawn()?;
+
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let stdin = child.stdin.take().expect("stdin is piped");
+        if let Some(stderr) = child.stderr.take() {
+            tokio::spawn(log_output(name.to_string(), stderr));
+        }
+        Ok((child, stdout.compat(), stdin.compat_write()))
+    }
+}
+
+/// The commands of every stdio app, and the processes of those that asked
+/// for their sessions to be multiplexed.
+#[derive(Default)]
+pub struct StdioShared {
+    commands: HashMap<String, StdioCommand>,
+    mux: MuxRegistry,
+}
+
+impl StdioShared {
+    pub fn from_config(config: &serde_json::Value) -> Self {
+        let mut commands = HashMap::new();
+        if let Some(apps) = config["apps"].as_object() {
+            for (name, app) in apps {
+                if app["transport"].as_str() != Some("stdio") {
+                    continue;
+                }
+                let command: StdioCommand = serde_json::from_value(app.clone())
+                    .unwrap_or_else(|e| {
+                        panic!("Invalid stdio app {}: {}", name, e)
+                    });
+                if command.command.is_empty() {
+                    panic!("Stdio app {} has an empty command", name);
+                }
+                commands.insert(name.clone(), command);
+            }
+        }
+        StdioShared {
+            commands,
+            mux: MuxRegistry::from_config(config),
+        }
+    }
+
+    fn command(&self, name: &str) -> io::Result<&StdioCommand> {
+        self.commands.get(name).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} is not a stdio app", name),
+            )
+        })
+    }
+}
+
+/// Wait for an app whose stdin was closed to exit, killing it if it doesn't
+async fn reap(name: &str, mut child: Child) {
+    match timeout(EXIT_TIMEOUT, child.status()).await {
+        Ok(status) => eprintln!("App {} exited with {:?}", name, status),
+        Err(_) => {
+            eprintln!("App {} did not exit, killing it", name);
+            let _ = child.kill();
+            let _ = child.status().await;
+        }
+    }
+}
+
+/// An app run as a child process, talking over its stdin and stdout. Every
+/// session gets its own process, unless the app asks for its sessions to be
+/// multiplexed, in which case they all share one.
+pub type StdioApp = StreamApp<Compat<ChildStdin>>;
+
+#[async_trait]
+impl App for StdioApp {
+    type Shared = StdioShared;
+
+    fn shared(config: &serde_json::Value) -> StdioShared {
+        StdioShared::from_config(config)
+    }
+
+    fn configured(shared: &StdioShared) -> Vec<String> {
+        shared.commands.keys().cloned().collect()
+    }
+
+    async fn get_description(
+        shared: &StdioShared,
+        _app_dir: &str,
+        name: &str,
+    ) -> io::Result<String> {
+        let (child, stdout, stdin) = shared.command(name)?.spawn(name)?;
+        // Closing stdin once the query is answered lets the app exit
+        let desc = query_app(&shared.mux, name, stdout, stdin).await;
+        tokio::spawn({
+            let name = name.to_string();
+            async move { reap(&name, child).await }
+        });
+        desc
+    }
+
+    fn new(
+        id: u64,
+        name: &str,
+        user: &str,
+        control: mpsc::Sender<AppMsg>,
+    ) -> Self {
+        StreamApp::new(id, name, user, control)
+    }
+
+    fn get_id(&self) -> u64 {
+        self.id()
+    }
+
+    fn get_name(&self) -> &str {
+        self.name()
+    }
+
+    async fn start(
+        &mut self,
+        shared: &StdioShared,
+        _app_dir: &str,
+        name: &str,
+    ) -> io::Result<()> {
+        let command = shared.command(name)?;
+        if shared.mux.is_multiplexed(name).await {
+            let mux = shared
+                .mux
+                .connect_with(name, || async {
+                    let (mut child, stdout, stdin) = command.spawn(name)?;
+                    let exited = name.to_string();
+                    tokio::spawn(async move {
+                        let status = child.status().await;
+                        eprintln!("App {} exited with {:?}", exited, status);
+                    });
+                    Ok(MuxConnection::new(
+                        name,
+                        stdout,
+                        stdin,
+                        shared.mux.codec(),
+                    ))
+                })
+                .await?;
+            self.open_mux(mux).await;
+            return Ok(());
+        }
+
+        let (child, stdout, stdin) = command.spawn(name)?;
+        let name = name.to_string();
+        self.open(stdout, stdin, shared.mux.codec(), async move {
+            reap(&name, child).await
+        });
+        Ok(())
+    }
+
+    async fn send(&mut self, msg: &ToApp) {
+        StreamApp::send(self, msg).await
+    }
+
+    async fn stop(&mut self) {
+        StreamApp::stop(self).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::*;
+    use crate::appstate::EndReason;
+
+    const SOURCE: &str = "+15555555";
+
+    /// A multiplexed app that answers every message with its pid
+    const MUX_APP: &str = r#"
+import json, os, struct, sys
+def write(msg):
+    msg = json.dumps(msg).encode()
+    sys.stdout.buffer.write(struct.pack("!I", len(msg)) + msg)
+    sys.stdout.buffer.flush()
+while len(length := sys.stdin.buffer.read(4)) == 4:
+    msg = json.loads(sys.stdin.buffer.read(struct.unpack("!I", length)[0]))
+    if msg["type"] == "query":
+        write({"type": "hello", "version": 7, "multiplex": True})
+    if msg["type"] in ("query", "msg"):
+        session = msg.get("session")
+        write({"type": "response", "value": str(os.getpid()),
+               "session": session})
+"#;
+
+    fn shared(command: serde_json::Value) -> StdioShared {
+        StdioApp::shared(&serde_json::json!({
+            "apps": {
+                "app": { "transport": "stdio", "command": command },
+                "other": { "send": "any" }
+            }
+        }))
+    }
+
+    async fn start_app(
+        shared: &StdioShared,
+        id: u64,
+        control: &mpsc::Sender<AppMsg>,
+    ) -> StdioApp {
+        let mut app = StdioApp::new(id, "app", SOURCE, control.clone());
+        app.start(shared, "", "app").await.expect("start failed!");
+        app
+    }
+
+    async fn expect_reply(
+        recv: &mut mpsc::Receiver<AppMsg>,
+        id: u64,
+    ) -> String {
+        match recv.recv().await {
+            Some(AppMsg::OutMsg(user, app, msg, _)) => {
+                assert_eq!((SOURCE, id), (user.as_str(), app));
+                msg
+            }
+            msg => panic!("Unexpected control msg {:?}", msg),
+        }
+    }
+
+    #[test]
+    fn test_config() {
+        let shared = shared(serde_json::json!(["python3", "app.py"]));
+        assert_eq!(vec!["app"], StdioApp::configured(&shared));
+        assert_eq!(
+            vec!["python3", "app.py"],
+            shared.command("app").unwrap().command
+        );
+        assert_eq!(
+            io::ErrorKind::NotFound,
+            shared.command("other").unwrap_err().kind()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_echo_over_stdio() {
+        let echo = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../signal-apps-clients/echo/echo_stdio.py");
+        let shared = shared(serde_json::json!(["python3", echo]));
+        let (control, mut recv) = mpsc::channel(100);
+
+        let desc = StdioApp::get_description(&shared, "", "app").await;
+        assert_eq!("A simple echo app", desc.expect("query failed!"));
+        assert!(!shared.mux.is_multiplexed("app").await);
+
+        let mut app = start_app(&shared, 0, &control).await;
+        app.send(&ToApp::start(SOURCE, false)).await;
+        assert_eq!("Started echo!", expect_reply(&mut recv, 0).await);
+        app.send(&ToApp::msg(SOURCE, "hi there")).await;
+        assert_eq!("hi there", expect_reply(&mut recv, 0).await);
+
+        // The server ended the session, so nothing is reported back
+        app.stop().await;
+        drop(app);
+        drop(control);
+        assert!(recv.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_exit_is_crash() {
+        let shared = shared(serde_json::json!(["sh", "-c", "exit 1"]));
+        let (control, mut recv) = mpsc::channel(100);
+        let _app = start_app(&shared, 0, &control).await;
+
+        match recv.recv().await {
+            Some(AppMsg::EndMsg(user, 0, reason)) => {
+                assert_eq!(SOURCE, user);
+                assert_eq!(EndReason::Crashed, reason);
+            }
+            msg => panic!("Unexpected control msg {:?}", msg),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_multiplexed_sessions_share_process() {
+        let shared = shared(serde_json::json!(["python3", "-c", MUX_APP]));
+        let (control, mut recv) = mpsc::channel(100);
+
+        let desc = StdioApp::get_description(&shared, "", "app").await;
+        let query_pid = desc.expect("query failed!");
+        assert!(shared.mux.is_multiplexed("app").await);
+
+        let mut pids = vec![];
+        for id in [0, 1] {
+            let mut app = start_app(&shared, id, &control).await;
+            app.send(&ToApp::msg(SOURCE, "hi")).await;
+            pids.push(expect_reply(&mut recv, id).await);
+        }
+        assert_eq!(pids[0], pids[1]);
+        assert_ne!(query_pid, pids[0]);
+    }
+}
@@ -0,0 +1,354 @@
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::process::ExitStatus;
+use std::sync::{Arc, Mutex};
+
+use async_process::{ChildStdin, ChildStdout, Command, Stdio};
+use async_trait::async_trait;
+use futures_lite::{
+    io::{AsyncRead, BufReader},
+    prelude::*,
+};
+use tokio::sync::mpsc;
+use tokio::time::{sleep, timeout, Duration, Instant};
+
+/// How long a process has to stay up before it counts as running again
+pub const STABLE_AFTER: Duration = Duration::from_secs(30);
+
+/// How long to keep reading the output of a process after it exited
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long an alert command may run before it's given up on
+const ALERT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A long running process that should be restarted whenever it exits
+#[async_trait]
+pub trait Service: Send + 'static {
+    /// The command to (re)start the process with. stderr is always captured
+    /// and logged.
+    fn command(&self) -> Command;
+
+    /// Talk to a freshly started process. This should only return once the
+    /// process closed its output, the process is restarted afterwards.
+    async fn run(
+        &mut self,
+        stdin: Option<ChildStdin>,
+        stdout: Option<ChildStdout>,
+    );
+
+    /// Called every time the process goes away
+    fn stopped(&mut self) {}
+}
+
+/// Delay between restarts, doubling with every restart in a row
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Backoff {
+            initial,
+            max,
+            current: initial,
+        }
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.current;
+        self.current = (self.current * 2).min(self.max);
+        delay
+    }
+
+    pub fn reset(&mut self) {
+        self.current = self.initial;
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff::new(Duration::from_secs(1), Duration::from_secs(60))
+    }
+}
+
+/// Something the admin should hear about, raised by a `LinkMonitor`
+#[derive(Clone, Debug, PartialEq)]
+pub enum Alert {
+    Down(String), // the link has been down too long, so it can't carry this
+    Up(String),   // the link is back after such an alert
+}
+
+/// A command that tells the admin about an alert without going through the
+/// link to Signal, run with the alert as its last argument
+#[derive(Clone, Debug, PartialEq)]
+pub struct AlertCommand {
+    pub program: PathBuf,
+    pub args: Vec<String>,
+    pub when_up: bool, // also run for `Alert::Up`, rather than using the link
+}
+
+impl AlertCommand {
+    pub async fn run(&self, alert: &str) {
+        let output = Command::new(&self.program)
+            .args(&self.args)
+            .arg(alert)
+            .stdin(Stdio::null())
+            .kill_on_drop(true)
+            .output();
+        match timeout(ALERT_TIMEOUT, output).await {
+            Ok(Ok(output)) if output.status.success() => {}
+            Ok(Ok(output)) => eprintln!(
+                "Alert command failed ({}): {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+            Ok(Err(e)) => eprintln!("Could not run alert command: {}", e),
+            Err(_) => eprintln!("Alert command timed out"),
+        }
+    }
+}
+
+struct LinkState {
+    down: HashSet<String>,
+    down_since: Option<Instant>,
+    alerted: bool,
+}
+
+/// Tracks whether every supervised process is up, and raises an alert once the
+/// link to Signal has been down for longer than `threshold`. Another alert is
+/// raised when it comes back after that.
+pub struct LinkMonitor {
+    threshold: Duration,
+    state: Mutex<LinkState>,
+    alerts: mpsc::UnboundedSender<Alert>,
+}
+
+impl LinkMonitor {
+    pub fn new(
+        threshold: Duration,
+    ) -> (Arc<Self>, mpsc::UnboundedReceiver<Alert>) {
+        let (alerts, recv) = mpsc::unbounded_channel();
+        let monitor = LinkMonitor {
+            threshold,
+            state: Mutex::new(LinkState {
+                down: HashSet::new(),
+                down_since: None,
+                alerted: false,
+            }),
+            alerts,
+        };
+        (Arc::new(monitor), recv)
+    }
+
+    fn down(self: &Arc<Self>, name: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.down.insert(name.into());
+        if state.down_since.is_some() {
+            return;
+        }
+
+        let since = Instant::now();
+        state.down_since = Some(since);
+        let monitor = self.clone();
+        tokio::spawn(async move {
+            sleep(monitor.threshold).await;
+            let mut state = monitor.state.lock().unwrap();
+            if state.down_since == Some(since) && !state.alerted {
+                state.alerted = true;
+                let down: Vec<_> = state.down.iter().cloned().collect();
+                let _ = monitor.alerts.send(Alert::Down(format!(
+                    "The link to Signal has been down for {}s ({} not \
+                     running)",
+                    since.elapsed().as_secs(),
+                    down.join(", ")
+                )));
+            }
+        });
+    }
+
+    fn up(&self, name: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.down.remove(name);
+        if !state.down.is_empty() {
+            return;
+        }
+
+        if let Some(since) = state.down_since.take() {
+            if state.alerted {
+                let _ = self.alerts.send(Alert::Up(format!(
+                    "The link to Signal is back up after {}s",
+                    since.elapsed().as_secs()
+                )));
+            }
+        }
+        state.alerted = false;
+    }
+}
+
+/// Log every line of a process' `output`, prefixed with its name
+pub async fn log_output<R: AsyncRead + Unpin>(name: String, output: R) {
+    let mut lines = BufReader::new(output).lines();
+    while let Some(Ok(line)) = lines.next().await {
+        eprintln!("[{}] {}", name, line);
+    }
+}
+
+/// Keep `service` running forever, restarting it with `backoff` whenever it
+/// exits or fails to start.
+pub async fn supervise<S: Service>(
+    name: String,
+    mut service: S,
+    monitor: Arc<LinkMonitor>,
+    mut backoff: Backoff,
+) {
+    loop {
+        let started = Instant::now();
+        let child = service
+            .command()
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn();
+        match child {
+            Ok(mut child) => {
+                eprintln!("Started {}", name);
+                if let Some(stderr) = child.stderr.take() {
+                    tokio::spawn(log_output(name.clone(), stderr));
+                }
+                let stdin = child.stdin.take();
+                let stdout = child.stdout.take();
+
+                let stable = {
+                    let monitor = monitor.clone();
+                    let name = name.clone();
+                    tokio::spawn(async move {
+                        sleep(STABLE_AFTER).await;
+                        monitor.up(&name);
+                    })
+                };
+                let run = service.run(stdin, stdout);
+                tokio::pin!(run);
+                let status: Option<ExitStatus> = tokio::select! {
+                    status = child.status() => {
+                        // Let run handle whatever output is left
+                        let _ = timeout(DRAIN_TIMEOUT, run).await;
+                        status.ok()
+                    }
+                    _ = &mut run => {
+                        let _ = child.kill();
+                        child.status().await.ok()
+                    }
+                };
+                stable.abort();
+                eprintln!("{} exited with {:?}", name, status);
+            }
+            Err(e) => eprintln!("Could not start {}: {}", name, e),
+        }
+
+        service.stopped();
+        monitor.down(&name);
+        if started.elapsed() >= STABLE_AFTER {
+            backoff.reset();
+        }
+        let delay = backoff.next_delay();
+        eprintln!("Restarting {} in {:?}", name, delay);
+        sleep(delay).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::FutureExt;
+
+    use super::*;
+
+    struct Echo {
+        lines: mpsc::UnboundedSender<String>,
+        stops: usize,
+    }
+
+    #[async_trait]
+    impl Service for Echo {
+        fn command(&self) -> Command {
+            let mut cmd = Command::new("sh");
+            cmd.args(["-c", "echo running; echo oops >&2"])
+                .stdout(Stdio::piped());
+            cmd
+        }
+
+        async fn run(
+            &mut self,
+            _: Option<ChildStdin>,
+            stdout: Option<ChildStdout>,
+        ) {
+            let mut lines = BufReader::new(stdout.unwrap()).lines();
+            while let Some(Ok(line)) = lines.next().await {
+                let _ = self.lines.send(format!("{} {}", line, self.stops));
+            }
+        }
+
+        fn stopped(&mut self) {
+            self.stops += 1;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_restarts_with_backoff() {
+        let (monitor, _) = LinkMonitor::new(Duration::from_secs(60));
+        let (lines, mut recv) = mpsc::unbounded_channel();
+        let service = Echo { lines, stops: 0 };
+        let backoff =
+            Backoff::new(Duration::from_millis(1), Duration::from_millis(4));
+        let task =
+            tokio::spawn(supervise("echo".into(), service, monitor, backoff));
+
+        for i in 0..3 {
+            assert_eq!(Some(format!("running {}", i)), recv.recv().await);
+        }
+        task.abort();
+    }
+
+    #[test]
+    fn test_backoff() {
+        let mut backoff =
+            Backoff::new(Duration::from_secs(1), Duration::from_secs(5));
+        let delays: Vec<_> =
+            (0..4).map(|_| backoff.next_delay().as_secs()).collect();
+        assert_eq!(vec![1, 2, 4, 5], delays);
+        backoff.reset();
+        assert_eq!(Duration::from_secs(1), backoff.next_delay());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_alert_after_threshold() {
+        let (monitor, mut alerts) = LinkMonitor::new(Duration::from_secs(60));
+
+        // Short outages aren't worth an alert
+        monitor.down("daemon");
+        sleep(Duration::from_secs(30)).await;
+        monitor.up("daemon");
+        sleep(Duration::from_secs(60)).await;
+        assert_eq!(None, alerts.recv().now_or_never());
+
+        monitor.down("daemon");
+        monitor.down("receive");
+        sleep(Duration::from_secs(30)).await;
+        monitor.up("daemon");
+        sleep(Duration::from_secs(31)).await;
+        let alert = match alerts.recv().await {
+            Some(Alert::Down(alert)) => alert,
+            alert => panic!("Unexpected alert {:?}", alert),
+        };
+        assert!(alert.contains("down for 60s"), "{}", alert);
+        assert!(alert.contains("receive not running"), "{}", alert);
+
+        monitor.up("receive");
+        let alert = match alerts.recv().await {
+            Some(Alert::Up(alert)) => alert,
+            alert => panic!("Unexpected alert {:?}", alert),
+        };
+        assert!(alert.contains("back up after 61s"), "{}", alert);
+    }
+}
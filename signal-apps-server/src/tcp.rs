@@ -0,0 +1,552 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use tokio::io::{split, AsyncRead, AsyncWrite, WriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::time::{timeout, Duration};
+use tokio_rustls::rustls::crypto::{ring, CryptoProvider};
+use tokio_rustls::rustls::pki_types::pem::{self, PemObject};
+use tokio_rustls::rustls::pki_types::{
+    CertificateDer, PrivateKeyDer, ServerName,
+};
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+
+use crate::app::{query_app, App, StreamApp};
+use crate::appstate::AppMsg;
+use crate::mux::{MuxConnection, MuxRegistry};
+use crate::protocol::ToApp;
+
+/// A connection to a remote app, with or without TLS
+pub trait AppStream: AsyncRead + AsyncWrite + Send + Sync + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Sync + Unpin> AppStream for T {}
+
+/// Where to reach an app over TCP, set per app in the config as
+/// `"apps": { "<name>": { "transport": "tcp", "address": "host:port" } }`.
+/// Adding `"tls": { "ca": ..., "cert": ..., "key": ..., "domain": ... }`
+/// connects with TLS, checking the app's certificate against the PEM file
+/// `ca`. `cert` and `key` are the PEM files of the certificate the server
+/// presents to the app, if it asks for one, and `domain` the name the app's
+/// certificate is checked for, by default the host of `address`.
+/// `timeout` is how many seconds connecting, and the TLS handshake, may
+/// each take.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct TcpAddress {
+    pub address: String,
+    #[serde(default)]
+    pub tls: Option<TlsOptions>,
+    #[serde(default)]
+    pub timeout: Option<u64>,
+}
+
+/// How long connecting to an app may take when the config doesn't say
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TlsOptions {
+    pub ca: String,
+    pub cert: Option<String>,
+    pub key: Option<String>,
+    pub domain: Option<String>,
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn timed_out(what: &str, address: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::TimedOut,
+        format!("{} to {} timed out", what, address),
+    )
+}
+
+fn pem_error(path: &str, e: pem::Error) -> io::Error {
+    match e {
+        pem::Error::Io(e) => {
+            io::Error::new(e.kind(), format!("{}: {}", path, e))
+        }
+        e => invalid(format!("{}: invalid PEM: {}", path, e)),
+    }
+}
+
+fn read_certs(path: &str) -> io::Result<Vec<CertificateDer<'static>>> {
+    let certs: Vec<_> = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect())
+        .map_err(|e| pem_error(path, e))?;
+    if certs.is_empty() {
+        return Err(invalid(format!("{}: no certificates", path)));
+    }
+    Ok(certs)
+}
+
+fn read_key(path: &str) -> io::Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path).map_err(|e| match e {
+        pem::Error::NoItemsFound => {
+            invalid(format!("{}: no private key", path))
+        }
+        e => pem_error(path, e),
+    })
+}
+
+/// The crypto TLS connections use, picked here rather than left to rustls
+/// in case more than one is compiled in
+pub fn crypto_provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+impl TlsOptions {
+    /// Load the certificates and key into a connector
+    pub fn connector(&self) -> io::Result<TlsConnector> {
+        let mut roots = RootCertStore::empty();
+        for cert in read_certs(&self.ca)? {
+            roots
+                .add(cert)
+                .map_err(|e| invalid(format!("{}: {}", self.ca, e)))?;
+        }
+        let config = ClientConfig::builder_with_provider(crypto_provider())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| invalid(e.to_string()))?
+            .with_root_certificates(roots);
+
+        let config = match (&self.cert, &self.key) {
+            (Some(cert), Some(key)) => config
+                .with_client_auth_cert(read_certs(cert)?, read_key(key)?)
+                .map_err(|e| invalid(format!("{}: {}", cert, e)))?,
+            (None, None) => config.with_no_client_auth(),
+            _ => {
+                return Err(invalid(
+                    "tls needs both or neither of cert and key".into(),
+                ))
+            }
+        };
+        Ok(TlsConnector::from(Arc::new(config)))
+    }
+}
+
+struct TlsTarget {
+    connector: TlsConnector,
+    domain: String,
+}
+
+struct TcpTarget {
+    address: String,
+    tls: Option<TlsTarget>,
+    timeout: Duration,
+}
+
+impl TcpTarget {
+    fn from_config(name: &str, app: &serde_json::Value) -> io::Result<Self> {
+        let address: TcpAddress = serde_json::from_value(app.clone())
+            .map_err(|e| invalid(e.to_string()))?;
+        let tls = match &address.tls {
+            Some(options) => {
+                let domain = match &options.domain {
+                    Some(domain) => domain.clone(),
+                    None => match address.address.rsplit_once(':') {
+                        Some((host, _)) => host.trim_matches(&['[', ']'][..]),
+                        None => &address.address,
+                    }
+                    .to_string(),
+                };
+                ServerName::try_from(domain.as_str()).map_err(|_| {
+                    invalid(format!("invalid domain {:?} for {}", domain, name))
+                })?;
+                Some(TlsTarget {
+                    connector: options.connector()?,
+                    domain,
+                })
+            }
+            None => None,
+        };
+        Ok(TcpTarget {
+            address: address.address,
+            tls,
+            timeout: address
+                .timeout
+                .map_or(CONNECT_TIMEOUT, Duration::from_secs),
+        })
+    }
+
+    async fn connect(&self) -> io::Result<Box<dyn AppStream>> {
+        let stream = timeout(self.timeout, TcpStream::connect(&self.address))
+            .await
+            .map_err(|_| timed_out("connecting", &self.address))??;
+        stream.set_nodelay(true)?;
+        let tls = match &self.tls {
+            Some(tls) => tls,
+            None => return Ok(Box::new(stream)),
+        };
+        let domain = ServerName::try_from(tls.domain.clone())
+            .expect("domain is checked when loading the config");
+        let stream =
+            timeout(self.timeout, tls.connector.connect(domain, stream))
+                .await
+                .map_err(|_| timed_out("TLS handshake", &self.address))??;
+        Ok(Box::new(stream))
+    }
+}
+
+/// The addresses of every TCP app, and the connections to those that asked
+/// for their sessions to be multiplexed.
+#[derive(Default)]
+pub struct TcpShared {
+    targets: HashMap<String, TcpTarget>,
+    mux: MuxRegistry,
+}
+
+impl TcpShared {
+    pub fn from_config(config: &serde_json::Value) -> Self {
+        let mut targets = HashMap::new();
+        if let Some(apps) = config["apps"].as_object() {
+            for (name, app) in apps {
+                if app["transport"].as_str() != Some("tcp") {
+                    continue;
+                }
+                let target =
+                    TcpTarget::from_config(name, app).unwrap_or_else(|e| {
+                        panic!("Invalid tcp app {}: {}", name, e)
+                    });
+                targets.insert(name.clone(), target);
+            }
+        }
+        TcpShared {
+            targets,
+            mux: MuxRegistry::from_config(config),
+        }
+    }
+
+    async fn connect(&self, name: &str) -> io::Result<Box<dyn AppStream>> {
+        match self.targets.get(name) {
+            Some(target) => target.connect().await,
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} is not a tcp app", name),
+            )),
+        }
+    }
+}
+
+/// An app on another host, reached over TCP and optionally TLS. Like apps on
+/// a Unix socket, every session gets its own connection unless the app asks
+/// for its sessions to be multiplexed.
+pub type TcpApp = StreamApp<WriteHalf<Box<dyn AppStream>>>;
+
+#[async_trait]
+impl App for TcpApp {
+    type Shared = TcpShared;
+
+    fn shared(config: &serde_json::Value) -> TcpShared {
+        TcpShared::from_config(config)
+    }
+
+    fn configured(shared: &TcpShared) -> Vec<String> {
+        shared.targets.keys().cloned().collect()
+    }
+
+    async fn get_description(
+        shared: &TcpShared,
+        _app_dir: &str,
+        name: &str,
+    ) -> io::Result<String> {
+        let (sr, sw) = split(shared.connect(name).await?);
+        query_app(&shared.mux, name, sr, sw).await
+    }
+
+    fn new(
+        id: u64,
+        name: &str,
+        user: &str,
+        control: mpsc::Sender<AppMsg>,
+    ) -> Self {
+        StreamApp::new(id, name, user, control)
+    }
+
+    fn get_id(&self) -> u64 {
+        self.id()
+    }
+
+    fn get_name(&self) -> &str {
+        self.name()
+    }
+
+    async fn start(
+        &mut self,
+        shared: &TcpShared,
+        _app_dir: &str,
+        name: &str,
+    ) -> io::Result<()> {
+        if shared.mux.is_multiplexed(name).await {
+            let mux = shared
+                .mux
+                .connect_with(name, || async {
+                    let (sr, sw) = split(shared.connect(name).await?);
+                    Ok(MuxConnection::new(name, sr, sw, shared.mux.codec()))
+                })
+                .await?;
+            self.open_mux(mux).await;
+            return Ok(());
+        }
+
+        let (sr, sw) = split(shared.connect(name).await?);
+        self.open(sr, sw, shared.mux.codec(), async {});
+        Ok(())
+    }
+
+    async fn send(&mut self, msg: &ToApp) {
+        StreamApp::send(self, msg).await
+    }
+
+    async fn stop(&mut self) {
+        StreamApp::stop(self).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa};
+    use tempdir::TempDir;
+    use tokio::net::TcpListener;
+    use tokio_rustls::rustls::pki_types::PrivatePkcs8KeyDer;
+    use tokio_rustls::rustls::server::WebPkiClientVerifier;
+    use tokio_rustls::rustls::ServerConfig;
+    use tokio_rustls::TlsAcceptor;
+
+    use super::*;
+    use crate::frame::{self, FrameCodec, FrameReader};
+
+    const SOURCE: &str = "+15555555";
+
+    /// A CA, and certificates it signed for the app and the server
+    struct Pki {
+        dir: TempDir,
+        ca: Certificate,
+        app: Certificate,
+    }
+
+    impl Pki {
+        fn new() -> Self {
+            let dir = TempDir::new("pki").expect("create tempdir failed!");
+            let mut params = CertificateParams::new(vec![]);
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            let ca = Certificate::from_params(params).unwrap();
+            let app = rcgen::generate_simple_self_signed(vec![
+                "localhost".to_string()
+            ])
+            .unwrap();
+            let server =
+                rcgen::generate_simple_self_signed(vec!["server".to_string()])
+                    .unwrap();
+
+            let write = |name: &str, contents: String| {
+                fs::write(dir.path().join(name), contents).unwrap()
+            };
+            write("ca.pem", ca.serialize_pem().unwrap());
+            write("server.pem", server.serialize_pem_with_signer(&ca).unwrap());
+            write("server.key", server.serialize_private_key_pem());
+            Pki { dir, ca, app }
+        }
+
+        fn path(&self, name: &str) -> String {
+            self.dir.path().join(name).to_str().unwrap().into()
+        }
+
+        /// An acceptor for the app, which only lets in clients with a
+        /// certificate signed by the CA
+        fn acceptor(&self) -> TlsAcceptor {
+            let mut roots = RootCertStore::empty();
+            roots
+                .add(CertificateDer::from(self.ca.serialize_der().unwrap()))
+                .unwrap();
+            let verifier = WebPkiClientVerifier::builder_with_provider(
+                Arc::new(roots),
+                crypto_provider(),
+            )
+            .build()
+            .unwrap();
+            let cert = self.app.serialize_der_with_signer(&self.ca).unwrap();
+            let key = self.app.serialize_private_key_der();
+            let config = ServerConfig::builder_with_provider(crypto_provider())
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_client_cert_verifier(verifier)
+                .with_single_cert(
+                    vec![CertificateDer::from(cert)],
+                    PrivatePkcs8KeyDer::from(key).into(),
+                )
+                .unwrap();
+            TlsAcceptor::from(Arc::new(config))
+        }
+    }
+
+    fn shared(app: serde_json::Value) -> TcpShared {
+        TcpApp::shared(&serde_json::json!({ "apps": { "app": app } }))
+    }
+
+    /// Answer a query, then echo every message back
+    async fn serve<S: AsyncRead + AsyncWrite + Unpin>(stream: S) {
+        let (sr, mut sw) = split(stream);
+        let mut reader = FrameReader::new(sr, FrameCodec::default());
+        while let Ok(Some(msg)) = reader.read_msg::<ToApp>().await {
+            let value = match msg {
+                ToApp::Query { .. } => "remote app".into(),
+                ToApp::Msg { data, .. } => data,
+                _ => continue,
+            };
+            let reply = serde_json::json!({"type": "response", "value": value});
+            frame::write_frame(&mut sw, &reply.to_string())
+                .await
+                .expect("write failed!");
+        }
+    }
+
+    async fn check_session(shared: &TcpShared) {
+        let desc = TcpApp::get_description(shared, "", "app").await;
+        assert_eq!("remote app", desc.expect("query failed!"));
+
+        let (control, mut recv) = mpsc::channel(100);
+        let mut app = TcpApp::new(0, "app", SOURCE, control);
+        app.start(shared, "", "app").await.expect("start failed!");
+        app.send(&ToApp::msg(SOURCE, "hello")).await;
+        match recv.recv().await {
+            Some(AppMsg::OutMsg(user, 0, msg, _)) => {
+                assert_eq!(SOURCE, user);
+                assert_eq!("hello", msg);
+            }
+            msg => panic!("Unexpected control msg {:?}", msg),
+        }
+        app.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_tcp_session() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            for _ in 0..2 {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(serve(stream));
+            }
+        });
+
+        let shared = shared(serde_json::json!({
+            "transport": "tcp",
+            "address": address
+        }));
+        assert_eq!(vec!["app"], TcpApp::configured(&shared));
+        check_session(&shared).await;
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_tls_with_client_certificate() {
+        let pki = Pki::new();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let acceptor = pki.acceptor();
+        let server = tokio::spawn(async move {
+            let mut accepted = vec![];
+            for _ in 0..3 {
+                let (stream, _) = listener.accept().await.unwrap();
+                match acceptor.accept(stream).await {
+                    Ok(stream) => {
+                        let (_, session) = stream.get_ref();
+                        let certs = session.peer_certificates();
+                        accepted.push(certs.map_or(0, |certs| certs.len()));
+                        tokio::spawn(serve(stream));
+                    }
+                    Err(_) => accepted.push(0),
+                }
+            }
+            accepted
+        });
+
+        let shared = shared(serde_json::json!({
+            "transport": "tcp",
+            "address": format!("localhost:{}", port),
+            "tls": {
+                "ca": pki.path("ca.pem"),
+                "cert": pki.path("server.pem"),
+                "key": pki.path("server.key")
+            }
+        }));
+        check_session(&shared).await;
+
+        // Without a certificate the app turns the server away
+        let anonymous = self::shared(serde_json::json!({
+            "transport": "tcp",
+            "address": format!("127.0.0.1:{}", port),
+            "tls": { "ca": pki.path("ca.pem"), "domain": "localhost" }
+        }));
+        let desc = TcpApp::get_description(&anonymous, "", "app").await;
+        assert_ne!(Some("remote app"), desc.ok().as_deref());
+
+        assert_eq!(vec![1, 1, 0], server.await.unwrap());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_tls_handshake_times_out() {
+        let pki = Pki::new();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        // Accept the connection but never answer the handshake
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            std::future::pending::<()>().await;
+            drop(stream);
+        });
+
+        let target = TcpTarget::from_config(
+            "app",
+            &serde_json::json!({
+                "transport": "tcp",
+                "address": format!("127.0.0.1:{}", port),
+                "tls": { "ca": pki.path("ca.pem"), "domain": "localhost" },
+                "timeout": 5
+            }),
+        )
+        .unwrap();
+        assert_eq!(Duration::from_secs(5), target.timeout);
+        match target.connect().await {
+            Err(e) => assert_eq!(io::ErrorKind::TimedOut, e.kind()),
+            Ok(_) => panic!("connected without a handshake"),
+        }
+        server.abort();
+    }
+
+    #[test]
+    fn test_invalid_tls_config() {
+        let pki = Pki::new();
+        let target = |tls: serde_json::Value| {
+            let app = serde_json::json!({
+                "transport": "tcp",
+                "address": "apps.example.com:4000",
+                "tls": tls
+            });
+            TcpTarget::from_config("app", &app).map(|t| t.tls.unwrap().domain)
+        };
+
+        let domain = target(serde_json::json!({ "ca": pki.path("ca.pem") }));
+        assert_eq!("apps.example.com", domain.unwrap());
+        for tls in [
+            serde_json::json!({ "ca": pki.path("missing.pem") }),
+            serde_json::json!({ "ca": pki.path("server.key") }),
+            serde_json::json!({
+                "ca": pki.path("ca.pem"),
+                "cert": pki.path("server.pem")
+            }),
+            serde_json::json!({ "ca": pki.path("ca.pem"), "ciphers": [] }),
+        ] {
+            assert!(target(tls.clone()).is_err(), "{}", tls);
+        }
+    }
+}
@@ -0,0 +1,242 @@
+use std::collections::HashMap;
+use std::io;
+
+use async_trait::async_trait;
+use tokio::sync::mpsc;
+
+use crate::app::{App, UnixStreamApp};
+use crate::appstate::AppMsg;
+use crate::mux::MuxRegistry;
+use crate::protocol::ToApp;
+use crate::stdio::{StdioApp, StdioShared};
+use crate::tcp::{TcpApp, TcpShared};
+use crate::wasm::{WasmApp, WasmShared};
+use crate::webhook::{WebhookApp, WebhookShared};
+
+/// How the server talks to an app, set per app in the config as
+/// `"apps": { "<name>": { "transport": ... } }`, one of `"unix"`, `"stdio"`,
+/// `"tcp"`, `"http"` or `"wasm"`. Apps without a transport are found as
+/// sockets in the app directory.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Transport {
+    Unix,  // a socket in the app directory
+    Stdio, // a process started by the server
+    Tcp,   // a server on another host
+    Http,  // an HTTP endpoint taking webhooks
+    Wasm,  // a WebAssembly module run by the server
+}
+
+/// The shared state of every transport, and which one each app uses
+#[derive(Default)]
+pub struct Transports {
+    transports: HashMap<String, Transport>,
+    unix: MuxRegistry,
+    stdio: StdioShared,
+    tcp: TcpShared,
+    http: WebhookShared,
+    wasm: WasmShared,
+}
+
+impl Transports {
+    pub fn transport(&self, name: &str) -> Transport {
+        self.transports
+            .get(name)
+            .copied()
+            .unwrap_or(Transport::Unix)
+    }
+}
+
+enum Inner {
+    Unix(UnixStreamApp),
+    Stdio(StdioApp),
+    Tcp(TcpApp),
+    Http(WebhookApp),
+    Wasm(WasmApp),
+}
+
+/// An app reached over whichever transport the config sets for it
+pub struct TransportApp {
+    id: u64,
+    name: String,
+    user: String,
+    control: mpsc::Sender<AppMsg>,
+    // Picked once the app is started and its transport is known
+    inner: Option<Inner>,
+}
+
+#[async_trait]
+impl App for TransportApp {
+    type Shared = Transports;
+
+    fn shared(config: &serde_json::Value) -> Transports {
+        let mut transports = HashMap::new();
+        if let Some(apps) = config["apps"].as_object() {
+            for (name, app) in apps {
+                let transport = match app["transport"].as_str() {
+                    None | Some("unix") => Transport::Unix,
+                    Some("stdio") => Transport::Stdio,
+                    Some("tcp") => Transport::Tcp,
+                    Some("http") => Transport::Http,
+                    Some("wasm") => Transport::Wasm,
+                    Some(transport) => {
+                        panic!("Invalid transport {:?} for {}", transport, name)
+                    }
+                };
+                transports.insert(name.clone(), transport);
+            }
+        }
+        Transports {
+            transports,
+            unix: UnixStreamApp::shared(config),
+            stdio: StdioApp::shared(config),
+            tcp: TcpApp::shared(config),
+            http: WebhookApp::shared(config),
+            wasm: WasmApp::shared(config),
+        }
+    }
+
+    fn configured(shared: &Transports) -> Vec<String> {
+        let mut apps = StdioApp::configured(&shared.stdio);
+        apps.extend(TcpApp::configured(&shared.tcp));
+        apps.extend(WebhookApp::configured(&shared.http));
+        apps.extend(WasmApp::configured(&shared.wasm));
+        apps
+    }
+
+    async fn get_description(
+        shared: &Transports,
+        app_dir: &str,
+        name: &str,
+    ) -> io::Result<String> {
+        match shared.transport(name) {
+            Transport::Unix => {
+                UnixStreamApp::get_description(&shared.unix, app_dir, name)
+                    .await
+            }
+            Transport::Stdio => {
+                StdioApp::get_description(&shared.stdio, app_dir, name).await
+            }
+            Transport::Tcp => {
+                TcpApp::get_description(&shared.tcp, app_dir, name).await
+            }
+            Transport::Http => {
+                WebhookApp::get_description(&shared.http, app_dir, name).await
+            }
+            Transport::Wasm => {
+                WasmApp::get_description(&shared.wasm, app_dir, name).await
+            }
+        }
+    }
+
+    fn new(
+        id: u64,
+        name: &str,
+        user: &str,
+        control: mpsc::Sender<AppMsg>,
+    ) -> Self {
+        TransportApp {
+            id,
+            name: name.into(),
+            user: user.into(),
+            control,
+            inner: None,
+        }
+    }
+
+    fn get_id(&self) -> u64 {
+        self.id
+    }
+
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    async fn start(
+        &mut self,
+        shared: &Transports,
+        app_dir: &str,
+        name: &str,
+    ) -> io::Result<()> {
+        let (id, user, control) = (self.id, &self.user, self.control.clone());
+        let inner = match shared.transport(name) {
+            Transport::Unix => {
+                let mut app = UnixStreamApp::new(id, name, user, control);
+                app.start(&shared.unix, app_dir, name).await?;
+                Inner::Unix(app)
+            }
+            Transport::Stdio => {
+                let mut app = StdioApp::new(id, name, user, control);
+                app.start(&shared.stdio, app_dir, name).await?;
+                Inner::Stdio(app)
+            }
+            Transport::Tcp => {
+                let mut app = TcpApp::new(id, name, user, control);
+                app.start(&shared.tcp, app_dir, name).await?;
+                Inner::Tcp(app)
+            }
+            Transport::Http => {
+                let mut app = WebhookApp::new(id, name, user, control);
+                app.start(&shared.http, app_dir, name).await?;
+                Inner::Http(app)
+            }
+            Transport::Wasm => {
+                let mut app = WasmApp::new(id, name, user, control);
+                app.start(&shared.wasm, app_dir, name).await?;
+                Inner::Wasm(app)
+            }
+        };
+        self.inner = Some(inner);
+        Ok(())
+    }
+
+    async fn send(&mut self, msg: &ToApp) {
+        match &mut self.inner {
+            Some(Inner::Unix(app)) => app.send(msg).await,
+            Some(Inner::Stdio(app)) => app.send(msg).await,
+            Some(Inner::Tcp(app)) => app.send(msg).await,
+            Some(Inner::Http(app)) => app.send(msg).await,
+            Some(Inner::Wasm(app)) => app.send(msg).await,
+            None => {}
+        }
+    }
+
+    async fn stop(&mut self) {
+        match &mut self.inner {
+            Some(Inner::Unix(app)) => app.stop().await,
+            Some(Inner::Stdio(app)) => app.stop().await,
+            Some(Inner::Tcp(app)) => app.stop().await,
+            Some(Inner::Http(app)) => app.stop().await,
+            Some(Inner::Wasm(app)) => app.stop().await,
+            None => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_transports_from_config() {
+        let shared = TransportApp::shared(&serde_json::json!({
+            "apps": {
+                "echo": { "transport": "stdio", "command": ["echo.py"] },
+                "game": { "transport": "unix" },
+                "chat": { "send": "any" }
+            }
+        }));
+        assert_eq!(Transport::Stdio, shared.transport("echo"));
+        assert_eq!(Transport::Unix, shared.transport("game"));
+        assert_eq!(Transport::Unix, shared.transport("chat"));
+        assert_eq!(Transport::Unix, shared.transport("unknown"));
+        assert_eq!(vec!["echo"], TransportApp::configured(&shared));
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid transport")]
+    fn test_invalid_transport() {
+        TransportApp::shared(&serde_json::json!({
+            "apps": { "echo": { "transport": "carrier pigeon" } }
+        }));
+    }
+}
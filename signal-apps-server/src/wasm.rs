@@ -0,0 +1,738 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use tokio::sync::mpsc;
+use tokio::task;
+use wasmi::core::Trap;
+use wasmi::{
+    Caller, Config, Engine, Extern, Instance, Linker, Module, Store,
+    StoreLimits, StoreLimitsBuilder,
+};
+
+use crate::app::{forward_replies, App};
+use crate::appstate::{AppMsg, EndReason};
+use crate::frame::FrameCodec;
+use crate::protocol::{FromApp, ToApp};
+
+/// The module the host functions are imported from
+const HOST_MODULE: &str = "signal_apps";
+
+/// How much memory a session may use, and how much work it may do for each
+/// message, unless the config says otherwise
+const DEFAULT_MEMORY: usize = 16 << 20;
+const DEFAULT_FUEL: u64 = 10_000_000;
+
+/// How many function references a session's table may hold
+const MAX_TABLE_ELEMENTS: u32 = 10_000;
+
+/// How many times a session may call the host functions for one message
+const MAX_REPLIES: usize = 100;
+
+fn default_memory() -> usize {
+    DEFAULT_MEMORY
+}
+
+fn default_fuel() -> u64 {
+    DEFAULT_FUEL
+}
+
+/// A WebAssembly app, set per app in the config as
+/// `"apps": { "<name>": { "transport": "wasm" } }`. The module is
+/// `<appdir>/<name>.wasm` unless `module` names another file. Every session
+/// runs in its own instance of it, whose memory may grow to `memory` bytes,
+/// and which may burn `fuel` units of work on each message. The module is
+/// compiled the first time it's needed, so changes to it are only picked up
+/// by restarting the server.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct WasmConfig {
+    pub module: Option<String>,
+    #[serde(default = "default_memory")]
+    pub memory: usize,
+    #[serde(default = "default_fuel")]
+    pub fuel: u64,
+}
+
+impl WasmConfig {
+    fn module_path(&self, app_dir: &str, name: &str) -> String {
+        let module = match &self.module {
+            Some(module) => Path::new(app_dir).join(module),
+            None => Path::new(app_dir).join(format!("{}.wasm", name)),
+        };
+        module.to_string_lossy().into()
+    }
+}
+
+/// What the host functions collect during a call into the module. The
+/// strings of its replies may hold `max_frame_size` bytes in all, as they
+/// have to fit in a frame anyway.
+struct Host {
+    limits: StoreLimits,
+    max_frame_size: usize,
+    replies: Vec<FromApp>,
+    reply_bytes: usize,
+}
+
+impl Host {
+    /// Keep a reply whose strings take `len` bytes, unless the message has
+    /// had too many or too much already
+    fn reply(&mut self, reply: FromApp, len: usize) -> Result<(), Trap> {
+        if self.replies.len() >= MAX_REPLIES {
+            return Err(Trap::new("too many replies"));
+        }
+        if len > self.max_frame_size - self.reply_bytes {
+            return Err(Trap::new("replies too large"));
+        }
+        self.reply_bytes += len;
+        self.replies.push(reply);
+        Ok(())
+    }
+}
+
+fn read_str(caller: &Caller<Host>, ptr: i32, len: i32) -> Result<String, Trap> {
+    let memory = caller
+        .get_export("memory")
+        .and_then(Extern::into_memory)
+        .ok_or_else(|| Trap::new("module exports no memory"))?;
+    let (ptr, len) = (ptr as u32 as usize, len as u32 as usize);
+    if len > caller.data().max_frame_size {
+        return Err(Trap::new("string too large"));
+    }
+    let bytes = memory
+        .data(caller)
+        .get(ptr..ptr + len)
+        .ok_or_else(|| Trap::new("string out of bounds"))?;
+    let s =
+        std::str::from_utf8(bytes).map_err(|_| Trap::new("invalid utf8"))?;
+    Ok(s.to_string())
+}
+
+fn linker(engine: &Engine) -> Linker<Host> {
+    let mut linker = Linker::new(engine);
+    linker
+        .func_wrap(
+            HOST_MODULE,
+            "response",
+            |mut caller: Caller<Host>, ptr: i32, len: i32| {
+                let value = read_str(&caller, ptr, len)?;
+                let len = value.len();
+                caller.data_mut().reply(
+                    FromApp::Response {
+                        value,
+                        attachments: vec![],
+                        session: None,
+                    },
+                    len,
+                )
+            },
+        )
+        .expect("Defining host function failed!")
+        .func_wrap(
+            HOST_MODULE,
+            "send",
+            |mut caller: Caller<Host>,
+             to: i32,
+             to_len: i32,
+             ptr: i32,
+             len: i32| {
+                let to = read_str(&caller, to, to_len)?;
+                let value = read_str(&caller, ptr, len)?;
+                let len = to.len() + value.len();
+                caller.data_mut().reply(
+                    FromApp::Send {
+                        to,
+                        value,
+                        session: None,
+                    },
+                    len,
+                )
+            },
+        )
+        .expect("Defining host function failed!")
+        .func_wrap(
+            HOST_MODULE,
+            "terminate",
+            |mut caller: Caller<Host>, ptr: i32, len: i32| {
+                let reason = read_str(&caller, ptr, len)?;
+                let len = reason.len();
+                caller.data_mut().reply(
+                    FromApp::Terminate {
+                        reason,
+                        session: None,
+                    },
+                    len,
+                )
+            },
+        )
+        .expect("Defining host function failed!");
+    linker
+}
+
+/// One instance of a module, with the limits of a session
+struct Sandbox {
+    store: Store<Host>,
+    instance: Instance,
+    fuel: u64,
+    // All the fuel ever added to the store
+    added: u64,
+}
+
+impl Sandbox {
+    fn new(
+        engine: &Engine,
+        module: &Module,
+        config: &WasmConfig,
+        max_frame_size: usize,
+    ) -> Result<Self, wasmi::Error> {
+        let limits = StoreLimitsBuilder::new()
+            .memory_size(config.memory)
+            .table_elements(MAX_TABLE_ELEMENTS)
+            .instances(1)
+            .tables(1)
+            .memories(1)
+            .build();
+        let host = Host {
+            limits,
+            max_frame_size,
+            replies: vec![],
+            reply_bytes: 0,
+        };
+        let mut store = Store::new(engine, host);
+        store.limiter(|host| &mut host.limits);
+        // The module's start function runs on the first message's fuel
+        store.add_fuel(config.fuel)?;
+        let instance = linker(engine)
+            .instantiate(&mut store, module)?
+            .start(&mut store)?;
+        Ok(Sandbox {
+            store,
+            instance,
+            fuel: config.fuel,
+            added: config.fuel,
+        })
+    }
+
+    /// Top the fuel back up to the limit for a message
+    fn refuel(&mut self) -> Result<(), wasmi::Error> {
+        let consumed = self.store.fuel_consumed().unwrap_or_default();
+        let remaining = self.added - consumed;
+        self.store.add_fuel(self.fuel - remaining)?;
+        self.added += self.fuel - remaining;
+        Ok(())
+    }
+
+    /// Copy a string into memory the module allocated for it
+    fn write_str(&mut self, s: &str) -> Result<(i32, i32), wasmi::Error> {
+        let alloc = self
+            .instance
+            .get_typed_func::<i32, i32>(&self.store, "alloc")?;
+        let len = s.len() as i32;
+        let ptr = alloc.call(&mut self.store, len)?;
+        let memory = self
+            .instance
+            .get_memory(&self.store, "memory")
+            .ok_or_else(|| Trap::new("module exports no memory"))?;
+        memory.write(&mut self.store, ptr as u32 as usize, s.as_bytes())?;
+        Ok((ptr, len))
+    }
+
+    /// Hand a message to the module, and return what it answered with
+    fn handle(&mut self, msg: &ToApp) -> Result<Vec<FromApp>, wasmi::Error> {
+        self.refuel()?;
+        match msg {
+            ToApp::Query { .. } => {
+                // Modules without a description don't need to export this
+                if let Ok(query) =
+                    self.instance.get_typed_func::<(), ()>(&self.store, "query")
+                {
+                    query.call(&mut self.store, ())?;
+                }
+            }
+            ToApp::Start { user, group, .. } => {
+                let (user, user_len) = self.write_str(user)?;
+                self.instance
+                    .get_typed_func::<(i32, i32, i32), ()>(
+                        &self.store,
+                        "start",
+                    )?
+                    .call(&mut self.store, (user, user_len, *group as i32))?;
+            }
+            ToApp::Msg { author, data, .. } => {
+                let (author, author_len) = self.write_str(author)?;
+                let (data, data_len) = self.write_str(data)?;
+                self.instance
+                    .get_typed_func::<(i32, i32, i32, i32), ()>(
+                        &self.store,
+                        "msg",
+                    )?
+                    .call(
+                        &mut self.store,
+                        (author, author_len, data, data_len),
+                    )?;
+            }
+            ToApp::Close { .. } | ToApp::Undelivered { .. } => {}
+        }
+        let host = self.store.data_mut();
+        host.reply_bytes = 0;
+        Ok(std::mem::take(&mut host.replies))
+    }
+}
+
+/// The engine shared by every module, the settings of every wasm app, and
+/// the modules compiled so far
+pub struct WasmShared {
+    engine: Engine,
+    apps: HashMap<String, WasmConfig>,
+    max_frame_size: usize,
+    modules: Mutex<HashMap<String, Arc<Module>>>,
+}
+
+impl Default for WasmShared {
+    fn default() -> Self {
+        WasmShared::from_config(&serde_json::Value::Null)
+    }
+}
+
+impl WasmShared {
+    pub fn from_config(config: &serde_json::Value) -> Self {
+        let mut apps = HashMap::new();
+        if let Some(configs) = config["apps"].as_object() {
+            for (name, app) in configs {
+                if app["transport"].as_str() != Some("wasm") {
+                    continue;
+                }
+                let app =
+                    serde_json::from_value(app.clone()).unwrap_or_else(|e| {
+                        panic!("Invalid wasm app {}: {}", name, e)
+                    });
+                apps.insert(name.clone(), app);
+            }
+        }
+        let max_frame_size = FrameCodec::from_config(config).max_frame_size();
+        let mut config = Config::default();
+        config.consume_fuel(true);
+        WasmShared {
+            engine: Engine::new(&config),
+            apps,
+            max_frame_size,
+            modules: Mutex::default(),
+        }
+    }
+
+    /// The compiled module of app `name`, compiling it from `path` if this
+    /// is the first time it's needed
+    async fn module(&self, name: &str, path: &str) -> io::Result<Arc<Module>> {
+        if let Some(module) = self.modules.lock().unwrap().get(name) {
+            return Ok(module.clone());
+        }
+        let engine = self.engine.clone();
+        let path = path.to_string();
+        let module = task::spawn_blocking(move || {
+            let module = fs::read(&path)?;
+            Module::new(&engine, &module[..])
+                .map(Arc::new)
+                .map_err(|e| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("{}: {}", path, e),
+                    )
+                })
+        })
+        .await
+        .expect("Compiling wasm module panicked!")?;
+        self.modules
+            .lock()
+            .unwrap()
+            .insert(name.to_string(), module.clone());
+        Ok(module)
+    }
+
+    /// Load the module of app `name` into a sandbox for a new session
+    async fn sandbox(&self, app_dir: &str, name: &str) -> io::Result<Sandbox> {
+        let config = match self.apps.get(name) {
+            Some(config) => config.clone(),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{} is not a wasm app", name),
+                ))
+            }
+        };
+        let path = config.module_path(app_dir, name);
+        let module = self.module(name, &path).await?;
+        let engine = self.engine.clone();
+        let max_frame_size = self.max_frame_size;
+        task::spawn_blocking(move || {
+            Sandbox::new(&engine, &module, &config, max_frame_size).map_err(
+                |e| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("{}: {}", path, e),
+                    )
+                },
+            )
+        })
+        .await
+        .expect("Loading wasm module panicked!")
+    }
+}
+
+/// Run a message through a sandbox without holding up the runtime
+async fn call(
+    mut sandbox: Sandbox,
+    msg: ToApp,
+) -> (Sandbox, Result<Vec<FromApp>, wasmi::Error>) {
+    task::spawn_blocking(move || {
+        let result = sandbox.handle(&msg);
+        (sandbox, result)
+    })
+    .await
+    .expect("Running wasm module panicked!")
+}
+
+/// An app compiled to WebAssembly, run inside the server. Messages are
+/// handed to functions the module exports, and it answers by calling the
+/// host functions `response`, `send` and `terminate`. A module that traps,
+/// runs out of fuel or fails to allocate crashes the session.
+pub struct WasmApp {
+    id: u64,
+    name: String,
+    user: String,
+    control: mpsc::Sender<AppMsg>,
+    sandbox: Option<Sandbox>,
+}
+
+#[async_trait]
+impl App for WasmApp {
+    type Shared = WasmShared;
+
+    fn shared(config: &serde_json::Value) -> WasmShared {
+        WasmShared::from_config(config)
+    }
+
+    fn configured(shared: &WasmShared) -> Vec<String> {
+        shared.apps.keys().cloned().collect()
+    }
+
+    async fn get_description(
+        shared: &WasmShared,
+        app_dir: &str,
+        name: &str,
+    ) -> io::Result<String> {
+        let sandbox = shared.sandbox(app_dir, name).await?;
+        let (_, replies) = call(sandbox, ToApp::query()).await;
+        let replies = replies
+            .map_err(|e| io::Error::other(format!("{}: {}", name, e)))?;
+        for reply in replies {
+            if let FromApp::Response { value, .. } = reply {
+                return Ok(value);
+            }
+        }
+        Ok("".into())
+    }
+
+    fn new(
+        id: u64,
+        name: &str,
+        user: &str,
+        control: mpsc::Sender<AppMsg>,
+    ) -> Self {
+        WasmApp {
+            id,
+            name: name.into(),
+            user: user.into(),
+            control,
+            sandbox: None,
+        }
+    }
+
+    fn get_id(&self) -> u64 {
+        self.id
+    }
+
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    async fn start(
+        &mut self,
+        shared: &WasmShared,
+        app_dir: &str,
+        name: &str,
+    ) -> io::Result<()> {
+        self.sandbox = Some(shared.sandbox(app_dir, name).await?);
+        Ok(())
+    }
+
+    async fn send(&mut self, msg: &ToApp) {
+        let sandbox = match self.sandbox.take() {
+            Some(sandbox) => sandbox,
+            None => return,
+        };
+        let (id, name, user) = (self.id, &self.name, &self.user);
+        match call(sandbox, msg.clone()).await {
+            (sandbox, Ok(replies)) => {
+                self.sandbox = Some(sandbox);
+                forward_replies(replies, id, name, user, &self.control).await;
+            }
+            (_, Err(e)) => {
+                eprintln!("App {} crashed in session {}: {}", name, id, e);
+                self.control
+                    .send(AppMsg::EndMsg(user.clone(), id, EndReason::Crashed))
+                    .await
+                    .expect("Sending control msg failed!");
+            }
+        }
+    }
+
+    async fn stop(&mut self) {
+        self.sandbox = None;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tempdir::TempDir;
+
+    use super::*;
+
+    const SOURCE: &str = "+15555555";
+
+    /// Answers a query, echoes messages, and ends the session on "bye". A
+    /// message starting with "s" spins forever, and one starting with "g"
+    /// grows memory by a megabyte, trapping if it can't.
+    const ECHO: &str = r#"
+        (module
+          (import "signal_apps" "response" (func $response (param i32 i32)))
+          (import "signal_apps" "terminate" (func $terminate (param i32 i32)))
+          (memory (export "memory") 1)
+          (global $next (mut i32) (i32.const 1024))
+          (data (i32.const 0) "wasm echo")
+          (data (i32.const 16) "bye")
+          (func (export "alloc") (param $len i32) (result i32)
+            (local $ptr i32)
+            (local.set $ptr (global.get $next))
+            (global.set $next (i32.add (global.get $next) (local.get $len)))
+            (local.get $ptr))
+          (func (export "query")
+            (call $response (i32.const 0) (i32.const 9)))
+          (func (export "start") (param i32 i32 i32))
+          (func (export "msg") (param i32 i32) (param $data i32) (param $len i32)
+            (if (i32.eq (i32.load8_u (local.get $data)) (i32.const 115))
+              (then (loop $spin (br $spin))))
+            (if (i32.eq (i32.load8_u (local.get $data)) (i32.const 103))
+              (then
+                (if (i32.eq (memory.grow (i32.const 16)) (i32.const -1))
+                  (then unreachable))))
+            (if (i32.eq (i32.load8_u (local.get $data)) (i32.const 98))
+              (then
+                (call $terminate (local.get $data) (local.get $len))
+                (return)))
+            (call $response (local.get $data) (local.get $len))))
+    "#;
+
+    fn setup(app: serde_json::Value) -> (TempDir, WasmShared) {
+        let dir = TempDir::new("apps").expect("create tempdir failed!");
+        let module = wat::parse_str(ECHO).expect("invalid wat!");
+        fs::write(dir.path().join("echo.wasm"), module).unwrap();
+        let shared = WasmApp::shared(&serde_json::json!({
+            "apps": { "echo": app }
+        }));
+        (dir, shared)
+    }
+
+    async fn start(
+        shared: &WasmShared,
+        dir: &TempDir,
+    ) -> (WasmApp, mpsc::Receiver<AppMsg>) {
+        let (control, recv) = mpsc::channel(100);
+        let mut app = WasmApp::new(0, "echo", SOURCE, control);
+        let app_dir = dir.path().to_str().unwrap();
+        app.start(shared, app_dir, "echo")
+            .await
+            .expect("start failed!");
+        app.send(&ToApp::start(SOURCE, false)).await;
+        (app, recv)
+    }
+
+    #[tokio::test]
+    async fn test_wasm_session() {
+        let (dir, shared) = setup(serde_json::json!({ "transport": "wasm" }));
+        assert_eq!(vec!["echo"], WasmApp::configured(&shared));
+        let app_dir = dir.path().to_str().unwrap();
+        let desc = WasmApp::get_description(&shared, app_dir, "echo").await;
+        assert_eq!("wasm echo", desc.expect("query failed!"));
+
+        let (mut app, mut recv) = start(&shared, &dir).await;
+        for msg in ["hello", "again"] {
+            app.send(&ToApp::msg(SOURCE, msg)).await;
+            match recv.recv().await {
+                Some(AppMsg::OutMsg(user, 0, reply, _)) => {
+                    assert_eq!(SOURCE, user);
+                    assert_eq!(msg, reply);
+                }
+                msg => panic!("Unexpected control msg {:?}", msg),
+            }
+        }
+
+        app.send(&ToApp::msg(SOURCE, "bye")).await;
+        match recv.recv().await {
+            Some(AppMsg::EndMsg(_, 0, EndReason::Terminated(reason))) => {
+                assert_eq!("bye", reason)
+            }
+            msg => panic!("Unexpected control msg {:?}", msg),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_wasm_limits() {
+        let (dir, shared) = setup(serde_json::json!({
+            "transport": "wasm",
+            "memory": 1 << 20,
+            "fuel": 100_000
+        }));
+
+        // Every message gets the same fuel, so a session doesn't run out
+        let (mut app, mut recv) = start(&shared, &dir).await;
+        for _ in 0..1000 {
+            app.send(&ToApp::msg(SOURCE, "hello")).await;
+            assert!(matches!(recv.recv().await, Some(AppMsg::OutMsg(..))));
+        }
+
+        for msg in ["spin", "grow"] {
+            let (mut app, mut recv) = start(&shared, &dir).await;
+            app.send(&ToApp::msg(SOURCE, msg)).await;
+            match recv.recv().await {
+                Some(AppMsg::EndMsg(_, 0, EndReason::Crashed)) => {}
+                msg => panic!("Unexpected control msg {:?}", msg),
+            }
+            // The session is over, nothing is left to run
+            app.send(&ToApp::msg(SOURCE, "hello")).await;
+            app.stop().await;
+            drop(app);
+            assert!(recv.recv().await.is_none());
+        }
+
+        // Within the limit memory can grow
+        let (dir, shared) = setup(serde_json::json!({ "transport": "wasm" }));
+        let (mut app, mut recv) = start(&shared, &dir).await;
+        app.send(&ToApp::msg(SOURCE, "grow")).await;
+        assert!(matches!(recv.recv().await, Some(AppMsg::OutMsg(..))));
+    }
+
+    #[tokio::test]
+    async fn test_reply_limits() {
+        let (dir, shared) = setup(serde_json::json!({
+            "transport": "wasm",
+            "module": "replies.wasm",
+            "memory": 32 << 20
+        }));
+        let path = dir.path().join("replies.wasm");
+        // Each module answers a message by calling response with `len`
+        // bytes at `ptr`, `count` times over
+        for (ptr, len, count, crashes) in [
+            (0, 9, 100, false),
+            (0, 9, 101, true),
+            (0, 1 << 20, 16, false),
+            (0, 1 << 20, 17, true),
+            (0, (16 << 20) + 1, 1, true),
+            (0, -1, 1, true),
+            (-1, 9, 1, true),
+            ((32 << 20) - 9, 10, 1, true),
+        ] {
+            let module = format!(
+                r#"(module
+                  (import "signal_apps" "response"
+                    (func $response (param i32 i32)))
+                  (memory (export "memory") 512)
+                  (func (export "alloc") (param i32) (result i32)
+                    (i32.const 0))
+                  (func (export "start") (param i32 i32 i32))
+                  (func (export "msg") (param i32 i32 i32 i32)
+                    (local $i i32)
+                    (loop $more
+                      (call $response (i32.const {}) (i32.const {}))
+                      (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                      (br_if $more (i32.lt_u (local.get $i) (i32.const {}))))))"#,
+                ptr, len, count
+            );
+            fs::write(&path, wat::parse_str(&module).unwrap()).unwrap();
+            shared.modules.lock().unwrap().clear();
+
+            let (mut app, mut recv) = start(&shared, &dir).await;
+            app.send(&ToApp::msg(SOURCE, "hello")).await;
+            if crashes {
+                match recv.recv().await {
+                    Some(AppMsg::EndMsg(_, 0, EndReason::Crashed)) => {}
+                    msg => panic!("Unexpected control msg {:?}", msg),
+                }
+                continue;
+            }
+            for _ in 0..count {
+                match recv.recv().await {
+                    Some(AppMsg::OutMsg(_, 0, reply, _)) => {
+                        assert_eq!(len as usize, reply.len())
+                    }
+                    msg => panic!("Unexpected control msg {:?}", msg),
+                }
+            }
+            // The limits are for each message, not the whole session
+            app.send(&ToApp::msg(SOURCE, "again")).await;
+            let reply = recv.recv().await;
+            assert!(matches!(reply, Some(AppMsg::OutMsg(..))), "{:?}", reply);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_table_limits() {
+        let (dir, shared) = setup(serde_json::json!({
+            "transport": "wasm",
+            "module": "tables.wasm"
+        }));
+        let app_dir = dir.path().to_str().unwrap();
+        let path = dir.path().join("tables.wasm");
+        for tables in [
+            "(table 1 funcref) (table 1 funcref)",
+            "(table 10001 funcref)",
+        ] {
+            let module = format!("(module {})", tables);
+            fs::write(&path, wat::parse_str(&module).unwrap()).unwrap();
+            shared.modules.lock().unwrap().clear();
+            let desc = WasmApp::get_description(&shared, app_dir, "echo").await;
+            assert_eq!(io::ErrorKind::InvalidData, desc.unwrap_err().kind());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_module_is_compiled_once() {
+        let (dir, shared) = setup(serde_json::json!({ "transport": "wasm" }));
+        let app_dir = dir.path().to_str().unwrap();
+        let desc = WasmApp::get_description(&shared, app_dir, "echo").await;
+        assert_eq!("wasm echo", desc.expect("query failed!"));
+
+        // Later sessions don't read the module again
+        fs::remove_file(dir.path().join("echo.wasm")).unwrap();
+        let (mut app, mut recv) = start(&shared, &dir).await;
+        app.send(&ToApp::msg(SOURCE, "hello")).await;
+        assert!(matches!(recv.recv().await, Some(AppMsg::OutMsg(..))));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_module() {
+        let (dir, shared) = setup(serde_json::json!({
+            "transport": "wasm",
+            "module": "echo.wat"
+        }));
+        let app_dir = dir.path().to_str().unwrap();
+        let desc = WasmApp::get_description(&shared, app_dir, "echo").await;
+        assert_eq!(io::ErrorKind::NotFound, desc.unwrap_err().kind());
+
+        fs::write(dir.path().join("echo.wat"), ECHO).unwrap();
+        let desc = WasmApp::get_description(&shared, app_dir, "echo").await;
+        assert_eq!(io::ErrorKind::InvalidData, desc.unwrap_err().kind());
+    }
+}
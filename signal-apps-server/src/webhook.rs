@@ -0,0 +1,742 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::fs::File;
+use std::io::{self, Read};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use hyper::body::HttpBody;
+use hyper::client::HttpConnector;
+use hyper::header::{HeaderMap, CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Client, Method, Request, Response, Server, StatusCode};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use serde::Deserialize;
+use tokio::sync::{mpsc, Mutex};
+
+use crate::app::{forward_replies, App};
+use crate::appstate::{AppMsg, EndReason};
+use crate::frame::FrameCodec;
+use crate::protocol::{self, FromApp, ToApp};
+
+/// The header telling an app where to post messages for a session
+pub const CALLBACK_HEADER: &str = "X-Signal-Apps-Callback";
+
+/// How long an app has to answer a request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+type HttpsClient = Client<HttpsConnector<HttpConnector>>;
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn too_large(max: usize) -> io::Error {
+    invalid(format!("body is larger than the maximum of {}", max))
+}
+
+/// Read a body of at most `max` bytes, the same limit as frames from apps
+/// on other transports. Bodies that announce a larger `Content-Length` are
+/// turned away without reading any of them. Only a body that is too large
+/// fails with `InvalidData`.
+async fn read_body(
+    headers: &HeaderMap,
+    mut body: Body,
+    max: usize,
+) -> io::Result<Vec<u8>> {
+    let length = headers
+        .get(CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok())
+        .and_then(|length| length.parse::<u64>().ok());
+    if length.is_some_and(|length| length > max as u64) {
+        return Err(too_large(max));
+    }
+
+    let mut bytes = Vec::with_capacity(length.unwrap_or(0) as usize);
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(io::Error::other)?;
+        if bytes.len() + chunk.len() > max {
+            return Err(too_large(max));
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(bytes)
+}
+
+/// Where the server listens for messages apps post outside of a reply, set
+/// in the config as `"webhooks": { "listen": "host:port", "url": ... }`.
+/// `url` is the address apps reach the listener at, by default
+/// `http://<listen>`.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct CallbackConfig {
+    pub listen: String,
+    pub url: Option<String>,
+}
+
+/// Where to send the messages of a session
+#[derive(Clone)]
+struct Route {
+    id: u64,
+    name: String,
+    user: String,
+    control: mpsc::Sender<AppMsg>,
+}
+
+impl Route {
+    /// Forward messages from the app, and end the session if one of them
+    /// says so. Returns whether the session ended.
+    async fn forward(&self, msgs: Vec<FromApp>) -> bool {
+        let (id, name, user) = (self.id, &self.name, &self.user);
+        forward_replies(msgs, id, name, user, &self.control).await
+    }
+}
+
+type Routes = Arc<StdMutex<HashMap<String, Route>>>;
+
+/// The listener for messages apps post to a session's callback URL, started
+/// when the first session needs it.
+struct Callbacks {
+    config: CallbackConfig,
+    routes: Routes,
+    max_body: usize,
+    // The URL of the running listener
+    url: Mutex<Option<String>>,
+}
+
+impl Callbacks {
+    async fn url(&self) -> io::Result<String> {
+        let mut url = self.url.lock().await;
+        if let Some(url) = &*url {
+            return Ok(url.clone());
+        }
+
+        let listen: SocketAddr = self.config.listen.parse().map_err(|_| {
+            invalid(format!("invalid listen address {}", self.config.listen))
+        })?;
+        let routes = self.routes.clone();
+        let max_body = self.max_body;
+        let make_service = make_service_fn(move |_| {
+            let routes = routes.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    handle_callback(routes.clone(), req, max_body)
+                }))
+            }
+        });
+        let server = Server::try_bind(&listen)
+            .map_err(io::Error::other)?
+            .serve(make_service);
+        let local = server.local_addr();
+        eprintln!("Listening for app callbacks on {}", local);
+        tokio::spawn(async move {
+            if let Err(e) = server.await {
+                eprintln!("Callback listener failed: {}", e);
+            }
+        });
+
+        let base = match &self.config.url {
+            Some(base) => base.trim_end_matches('/').to_string(),
+            None => format!("http://{}", local),
+        };
+        *url = Some(base.clone());
+        Ok(base)
+    }
+
+    async fn register(&self, route: Route) -> io::Result<(String, String)> {
+        let token = random_token()?;
+        let url = format!("{}/callback/{}", self.url().await?, token);
+        self.routes.lock().unwrap().insert(token.clone(), route);
+        Ok((token, url))
+    }
+
+    fn unregister(&self, token: &str) {
+        self.routes.lock().unwrap().remove(token);
+    }
+}
+
+fn random_token() -> io::Result<String> {
+    let mut bytes = [0u8; 16];
+    File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+fn status(code: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = code;
+    response
+}
+
+async fn handle_callback(
+    routes: Routes,
+    req: Request<Body>,
+    max_body: usize,
+) -> Result<Response<Body>, Infallible> {
+    if req.method() != Method::POST {
+        return Ok(status(StatusCode::METHOD_NOT_ALLOWED));
+    }
+    let token = match req.uri().path().strip_prefix("/callback/") {
+        Some(token) => token.to_string(),
+        None => return Ok(status(StatusCode::NOT_FOUND)),
+    };
+    let route = match routes.lock().unwrap().get(&token) {
+        Some(route) => route.clone(),
+        None => return Ok(status(StatusCode::NOT_FOUND)),
+    };
+    let (parts, body) = req.into_parts();
+    let msgs = match read_body(&parts.headers, body, max_body).await {
+        Ok(body) => match parse_messages(&body) {
+            Ok(msgs) => msgs,
+            Err(e) => {
+                eprintln!("App {} posted a bad message: {}", route.name, e);
+                return Ok(status(StatusCode::BAD_REQUEST));
+            }
+        },
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+            eprintln!("App {} posted a message: {}", route.name, e);
+            return Ok(status(StatusCode::PAYLOAD_TOO_LARGE));
+        }
+        Err(_) => return Ok(status(StatusCode::BAD_REQUEST)),
+    };
+    if route.forward(msgs).await {
+        routes.lock().unwrap().remove(&token);
+    }
+    Ok(status(StatusCode::NO_CONTENT))
+}
+
+/// Parse a body from an app, which holds nothing, one message or an array of
+/// them
+pub fn parse_messages(body: &[u8]) -> serde_json::Result<Vec<FromApp>> {
+    if body.iter().all(u8::is_ascii_whitespace) {
+        return Ok(vec![]);
+    }
+    match serde_json::from_slice(body)? {
+        serde_json::Value::Array(msgs) => msgs
+            .into_iter()
+            .map(serde_json::from_value)
+            .collect::<serde_json::Result<_>>(),
+        msg => Ok(vec![serde_json::from_value(msg)?]),
+    }
+}
+
+/// The client for, and URL of, every HTTP app
+pub struct WebhookShared {
+    urls: HashMap<String, String>,
+    client: HttpsClient,
+    callbacks: Option<Arc<Callbacks>>,
+    // The largest body read from an app, as for frames on other transports
+    max_body: usize,
+}
+
+impl Default for WebhookShared {
+    fn default() -> Self {
+        WebhookShared::from_config(&serde_json::Value::Null)
+    }
+}
+
+impl WebhookShared {
+    pub fn from_config(config: &serde_json::Value) -> Self {
+        let mut urls = HashMap::new();
+        if let Some(apps) = config["apps"].as_object() {
+            for (name, app) in apps {
+                if app["transport"].as_str() != Some("http") {
+                    continue;
+                }
+                match app["url"].as_str() {
+                    Some(url) if url.parse::<hyper::Uri>().is_ok() => {
+                        urls.insert(name.clone(), url.to_string());
+                    }
+                    url => panic!("Invalid url {:?} for {}", url, name),
+                }
+            }
+        }
+        let max_body = FrameCodec::from_config(config).max_frame_size();
+        let callbacks = match config.get("webhooks") {
+            Some(webhooks) => {
+                let config = serde_json::from_value(webhooks.clone())
+                    .unwrap_or_else(|e| panic!("Invalid webhooks: {}", e));
+                Some(Arc::new(Callbacks {
+                    config,
+                    routes: Arc::default(),
+                    max_body,
+                    url: Mutex::new(None),
+                }))
+            }
+            None => None,
+        };
+        let https = HttpsConnectorBuilder::new()
+            .with_webpki_roots()
+            .https_or_http()
+            .enable_http1()
+            .build();
+        let client = Client::builder().build(https);
+        WebhookShared {
+            urls,
+            client,
+            callbacks,
+            max_body,
+        }
+    }
+
+    fn url(&self, name: &str) -> io::Result<&str> {
+        match self.urls.get(name) {
+            Some(url) => Ok(url),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} is not an http app", name),
+            )),
+        }
+    }
+}
+
+/// Post a message to an app and parse the messages in its reply, which may be
+/// at most `max_body` bytes
+async fn post(
+    client: &HttpsClient,
+    url: &str,
+    callback: Option<&str>,
+    msg: &ToApp,
+    max_body: usize,
+) -> io::Result<Vec<FromApp>> {
+    let mut req = Request::builder()
+        .method(Method::POST)
+        .uri(url)
+        .header(CONTENT_TYPE, "application/json");
+    if let Some(callback) = callback {
+        req = req.header(CALLBACK_HEADER, callback);
+    }
+    let req = req
+        .body(Body::from(msg.encode()))
+        .map_err(|e| invalid(e.to_string()))?;
+
+    let other = io::Error::other::<hyper::Error>;
+    let send = async {
+        let response = client.request(req).await.map_err(other)?;
+        if !response.status().is_success() {
+            return Err(io::Error::other(format!(
+                "{} answered {}",
+                url,
+                response.status()
+            )));
+        }
+        let (parts, body) = response.into_parts();
+        read_body(&parts.headers, body, max_body).await
+    };
+    let body = tokio::time::timeout(REQUEST_TIMEOUT, send)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, url))??;
+    parse_messages(&body).map_err(|e| invalid(format!("{}: {}", url, e)))
+}
+
+/// An app behind an HTTP endpoint. Every message of a session is posted to
+/// the app as JSON, and the messages in the reply are handled as if the app
+/// had sent them. With `webhooks` configured, each request also carries a
+/// URL where the app can post messages for the session at any time.
+pub struct WebhookApp {
+    id: u64,
+    name: String,
+    user: String,
+    control: mpsc::Sender<AppMsg>,
+    client: Option<HttpsClient>,
+    url: String,
+    max_body: usize,
+    callbacks: Option<Arc<Callbacks>>,
+    // The session's token and callback URL
+    callback: Option<(String, String)>,
+}
+
+impl WebhookApp {
+    fn route(&self) -> Route {
+        Route {
+            id: self.id,
+            name: self.name.clone(),
+            user: self.user.clone(),
+            control: self.control.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl App for WebhookApp {
+    type Shared = WebhookShared;
+
+    fn shared(config: &serde_json::Value) -> WebhookShared {
+        WebhookShared::from_config(config)
+    }
+
+    fn configured(shared: &WebhookShared) -> Vec<String> {
+        shared.urls.keys().cloned().collect()
+    }
+
+    async fn get_description(
+        shared: &WebhookShared,
+        _app_dir: &str,
+        name: &str,
+    ) -> io::Result<String> {
+        let url = shared.url(name)?;
+        let query = ToApp::query();
+        let msgs =
+            post(&shared.client, url, None, &query, shared.max_body).await?;
+        eprintln!("queried app {}", name);
+        for msg in msgs {
+            match msg {
+                FromApp::Hello { version, .. } => {
+                    protocol::check_version(version)
+                        .map_err(|e| invalid(e.to_string()))?;
+                }
+                FromApp::Response { value, .. } => return Ok(value),
+                _ => {}
+            }
+        }
+        Ok("".into())
+    }
+
+    fn new(
+        id: u64,
+        name: &str,
+        user: &str,
+        control: mpsc::Sender<AppMsg>,
+    ) -> Self {
+        WebhookApp {
+            id,
+            name: name.into(),
+            user: user.into(),
+            control,
+            client: None,
+            url: String::new(),
+            max_body: 0,
+            callbacks: None,
+            callback: None,
+        }
+    }
+
+    fn get_id(&self) -> u64 {
+        self.id
+    }
+
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    async fn start(
+        &mut self,
+        shared: &WebhookShared,
+        _app_dir: &str,
+        name: &str,
+    ) -> io::Result<()> {
+        self.url = shared.url(name)?.into();
+        self.client = Some(shared.client.clone());
+        self.max_body = shared.max_body;
+        if let Some(callbacks) = &shared.callbacks {
+            self.callback = Some(callbacks.register(self.route()).await?);
+            self.callbacks = Some(callbacks.clone());
+        }
+        Ok(())
+    }
+
+    async fn send(&mut self, msg: &ToApp) {
+        let client = match &self.client {
+            Some(client) => client,
+            None => return,
+        };
+        let callback = self.callback.as_ref().map(|(_, url)| url.as_str());
+        let msg = msg.with_session(self.id);
+        match post(client, &self.url, callback, &msg, self.max_body).await {
+            Ok(msgs) => {
+                if self.route().forward(msgs).await {
+                    self.stop().await;
+                }
+            }
+            Err(e) => {
+                eprintln!("Could not send to app {}: {}", self.name, e);
+                self.stop().await;
+                self.control
+                    .send(AppMsg::EndMsg(
+                        self.user.clone(),
+                        self.id,
+                        EndReason::Crashed,
+                    ))
+                    .await
+                    .expect("Sending control msg failed!");
+            }
+        }
+    }
+
+    async fn stop(&mut self) {
+        if let (Some(callbacks), Some((token, _))) =
+            (self.callbacks.take(), self.callback.take())
+        {
+            callbacks.unregister(&token);
+        }
+        // Let the app know the session is over, without waiting for it
+        if let Some(client) = self.client.take() {
+            let url = self.url.clone();
+            let msg = ToApp::Close { session: self.id };
+            let max_body = self.max_body;
+            tokio::spawn(async move {
+                let _ = post(&client, &url, None, &msg, max_body).await;
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SOURCE: &str = "+15555555";
+
+    type Requests = Arc<StdMutex<Vec<(Option<String>, serde_json::Value)>>>;
+
+    /// A stub app, which records what it was sent and answers with `reply`
+    async fn stub<F>(reply: F) -> (String, Requests)
+    where
+        F: Fn(&serde_json::Value) -> (StatusCode, String)
+            + Send
+            + Sync
+            + 'static,
+    {
+        let requests = Requests::default();
+        let reply = Arc::new(reply);
+        let recorded = requests.clone();
+        let make_service = make_service_fn(move |_| {
+            let (reply, recorded) = (reply.clone(), recorded.clone());
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let (reply, recorded) = (reply.clone(), recorded.clone());
+                    async move {
+                        let callback = req
+                            .headers()
+                            .get(CALLBACK_HEADER)
+                            .map(|url| url.to_str().unwrap().to_string());
+                        let body = hyper::body::to_bytes(req.into_body())
+                            .await
+                            .unwrap();
+                        let msg = serde_json::from_slice(&body).unwrap();
+                        let (code, body) = reply(&msg);
+                        recorded.lock().unwrap().push((callback, msg));
+                        let mut response = Response::new(Body::from(body));
+                        *response.status_mut() = code;
+                        Ok::<_, Infallible>(response)
+                    }
+                }))
+            }
+        });
+        let server =
+            Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service);
+        let url = format!("http://{}/hook", server.local_addr());
+        tokio::spawn(server);
+        (url, requests)
+    }
+
+    fn shared(url: &str, webhooks: Option<serde_json::Value>) -> WebhookShared {
+        let mut config = serde_json::json!({
+            "apps": { "app": { "transport": "http", "url": url } }
+        });
+        if let Some(webhooks) = webhooks {
+            config["webhooks"] = webhooks;
+        }
+        WebhookApp::shared(&config)
+    }
+
+    /// Answer a query, and echo every message back twice
+    fn echo(msg: &serde_json::Value) -> (StatusCode, String) {
+        let reply = match msg["type"].as_str() {
+            Some("query") => serde_json::json!([
+                { "type": "hello", "version": protocol::VERSION },
+                { "type": "response", "value": "http app" }
+            ]),
+            Some("msg") => {
+                let value = &msg["data"];
+                serde_json::json!([
+                    { "type": "response", "value": value },
+                    { "type": "response", "value": value }
+                ])
+            }
+            _ => return (StatusCode::OK, "".into()),
+        };
+        (StatusCode::OK, reply.to_string())
+    }
+
+    #[test]
+    fn test_parse_messages() {
+        assert_eq!(Vec::<FromApp>::new(), parse_messages(b" \n").unwrap());
+        let one = parse_messages(br#"{"type": "terminate"}"#).unwrap();
+        assert_eq!(1, one.len());
+        let two = br#"[{"type": "response", "value": "a"},
+                       {"type": "send", "to": "b", "value": "c"}]"#;
+        assert_eq!(2, parse_messages(two).unwrap().len());
+        assert!(parse_messages(br#"{"type": "response"}"#).is_err());
+        assert!(parse_messages(b"<html>").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_webhook_session() {
+        let (url, requests) = stub(echo).await;
+        let shared = shared(&url, None);
+        assert_eq!(vec!["app"], WebhookApp::configured(&shared));
+        let desc = WebhookApp::get_description(&shared, "", "app").await;
+        assert_eq!("http app", desc.expect("query failed!"));
+
+        let (control, mut recv) = mpsc::channel(100);
+        let mut app = WebhookApp::new(3, "app", SOURCE, control);
+        app.start(&shared, "", "app").await.expect("start failed!");
+        app.send(&ToApp::start(SOURCE, false)).await;
+        app.send(&ToApp::msg(SOURCE, "hello")).await;
+        for _ in 0..2 {
+            match recv.recv().await {
+                Some(AppMsg::OutMsg(user, 3, msg, _)) => {
+                    assert_eq!(SOURCE, user);
+                    assert_eq!("hello", msg);
+                }
+                msg => panic!("Unexpected control msg {:?}", msg),
+            }
+        }
+        app.stop().await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let requests = requests.lock().unwrap();
+        let types: Vec<_> = requests
+            .iter()
+            .map(|(_, msg)| msg["type"].as_str().unwrap())
+            .collect();
+        assert_eq!(vec!["query", "start", "msg", "close"], types);
+        assert_eq!(3, requests[2].1["session"]);
+        assert!(requests.iter().all(|(callback, _)| callback.is_none()));
+    }
+
+    #[tokio::test]
+    async fn test_webhook_callback() {
+        let (url, requests) = stub(|_| (StatusCode::OK, "".into())).await;
+        let shared =
+            shared(&url, Some(serde_json::json!({ "listen": "127.0.0.1:0" })));
+
+        let (control, mut recv) = mpsc::channel(100);
+        let mut app = WebhookApp::new(5, "app", SOURCE, control);
+        app.start(&shared, "", "app").await.expect("start failed!");
+        app.send(&ToApp::msg(SOURCE, "later")).await;
+        let callback = requests.lock().unwrap()[0].0.clone();
+        let callback = callback.expect("no callback url");
+
+        let client = Client::new();
+        let post = |url: String, body: serde_json::Value| {
+            let req = Request::post(url)
+                .body(Body::from(body.to_string()))
+                .unwrap();
+            client.request(req)
+        };
+        let reply = serde_json::json!({"type": "response", "value": "done"});
+        let response = post(callback.clone(), reply).await.unwrap();
+        assert_eq!(StatusCode::NO_CONTENT, response.status());
+        match recv.recv().await {
+            Some(AppMsg::OutMsg(_, 5, msg, _)) => assert_eq!("done", msg),
+            msg => panic!("Unexpected control msg {:?}", msg),
+        }
+
+        let bad = serde_json::json!({"type": "response"});
+        let response = post(callback.clone(), bad).await.unwrap();
+        assert_eq!(StatusCode::BAD_REQUEST, response.status());
+
+        let terminate =
+            serde_json::json!({"type": "terminate", "reason": "bye"});
+        post(callback.clone(), terminate).await.unwrap();
+        match recv.recv().await {
+            Some(AppMsg::EndMsg(_, 5, EndReason::Terminated(reason))) => {
+                assert_eq!("bye", reason)
+            }
+            msg => panic!("Unexpected control msg {:?}", msg),
+        }
+
+        // The session is over, so its callback is gone
+        let reply = serde_json::json!({"type": "response", "value": "late"});
+        let response = post(callback, reply).await.unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+    }
+
+    #[tokio::test]
+    async fn test_webhook_error_crashes_session() {
+        let (url, _) =
+            stub(|_| (StatusCode::INTERNAL_SERVER_ERROR, "".into())).await;
+        let shared = shared(&url, None);
+        assert!(WebhookApp::get_description(&shared, "", "app")
+            .await
+            .is_err());
+
+        let (control, mut recv) = mpsc::channel(100);
+        let mut app = WebhookApp::new(0, "app", SOURCE, control);
+        app.start(&shared, "", "app").await.expect("start failed!");
+        app.send(&ToApp::msg(SOURCE, "hello")).await;
+        match recv.recv().await {
+            Some(AppMsg::EndMsg(_, 0, EndReason::Crashed)) => {}
+            msg => panic!("Unexpected control msg {:?}", msg),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_body_size_limit() {
+        let shared = |url: &str| {
+            WebhookApp::shared(&serde_json::json!({
+                "apps": { "app": { "transport": "http", "url": url } },
+                "webhooks": { "listen": "127.0.0.1:0" },
+                "maxframesize": 64
+            }))
+        };
+
+        // Replies are held to the limit on frames
+        let (url, _) = stub(echo).await;
+        let desc = WebhookApp::get_description(&shared(&url), "", "app").await;
+        assert_eq!(io::ErrorKind::InvalidData, desc.unwrap_err().kind());
+
+        let (url, requests) = stub(|_| (StatusCode::OK, "".into())).await;
+        let shared = shared(&url);
+        let (control, mut recv) = mpsc::channel(100);
+        let mut app = WebhookApp::new(0, "app", SOURCE, control);
+        app.start(&shared, "", "app").await.expect("start failed!");
+        app.send(&ToApp::msg(SOURCE, "hi")).await;
+        let callback = requests.lock().unwrap()[0].0.clone().unwrap();
+
+        // So are callbacks, whether or not they say how long they are
+        let client = Client::new();
+        let post = |body: Body| {
+            let req = Request::post(&callback).body(body).unwrap();
+            client.request(req)
+        };
+        let long =
+            serde_json::json!({"type": "response", "value": "x".repeat(64)});
+        let response = post(Body::from(long.to_string())).await.unwrap();
+        assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, response.status());
+
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            for chunk in [
+                r#"{"type": "response", "value": ""#,
+                &"x".repeat(64),
+                r#""}"#,
+            ] {
+                if sender.send_data(chunk.to_string().into()).await.is_err() {
+                    break;
+                }
+            }
+        });
+        let response = post(body).await.unwrap();
+        assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, response.status());
+
+        let short = serde_json::json!({"type": "response", "value": "ok"});
+        let response = post(Body::from(short.to_string())).await.unwrap();
+        assert_eq!(StatusCode::NO_CONTENT, response.status());
+        match recv.recv().await {
+            Some(AppMsg::OutMsg(_, 0, msg, _)) => assert_eq!("ok", msg),
+            msg => panic!("Unexpected control msg {:?}", msg),
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid url")]
+    fn test_invalid_url() {
+        WebhookApp::shared(&serde_json::json!({
+            "apps": { "app": { "transport": "http" } }
+        }));
+    }
+}
@@ -0,0 +1,209 @@
+//! Runs the server binary against `fake-signal-cli`, with the echo sample app
+//! listening on a unix socket. Needs `--features test-tools` and python3.
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tempdir::TempDir;
+
+const USER: &str = "+15550000000";
+
+/// The echo sample app, from `signal-apps-clients/echo`
+const ECHO: &str = concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/../signal-apps-clients/echo/echo.py"
+);
+
+struct Server {
+    dir: TempDir,
+    process: Child,
+    _echo: App,
+}
+
+/// A sample app, run for as long as this is kept
+struct App(Child);
+
+impl App {
+    fn start(config: &Path, app_dir: &Path) -> Self {
+        let process = Command::new("python3")
+            .arg(ECHO)
+            .arg(config)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("starting echo.py failed!");
+        let app = App(process);
+
+        let start = Instant::now();
+        while UnixStream::connect(app_dir.join("echo")).is_err() {
+            if start.elapsed() > Duration::from_secs(10) {
+                panic!("echo.py never listened on its socket");
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        app
+    }
+}
+
+impl Drop for App {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+impl Server {
+    fn config(dir: &Path, username: &str) -> PathBuf {
+        let app_dir = dir.join("apps");
+        let data_dir = dir.join("signal-cli");
+        fs::create_dir_all(&app_dir).expect("create appdir failed!");
+        fs::create_dir_all(&data_dir).expect("create datadir failed!");
+
+        let config = dir.join("config.json");
+        let json = serde_json::json!({
+            "username": username,
+            "appdir": app_dir,
+            "signalcli": {
+                "path": env!("CARGO_BIN_EXE_fake-signal-cli"),
+                "datadir": data_dir,
+            }
+        });
+        fs::write(&config, json.to_string()).expect("write config failed!");
+        config
+    }
+
+    fn start(backend: &str) -> Self {
+        let dir = TempDir::new("e2e").expect("create tempdir failed!");
+        let config = Self::config(dir.path(), USER);
+        let echo = App::start(&config, &dir.path().join("apps"));
+        let process = Command::new(env!("CARGO_BIN_EXE_signal-apps"))
+            .arg("--config")
+            .arg(config)
+            .arg("--backend")
+            .arg(backend)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("starting server failed!");
+        Server {
+            dir,
+            process,
+            _echo: echo,
+        }
+    }
+
+    /// Have signal-cli hand the server a message from `source`
+    fn receive(&self, source: &str, group: Option<&str>, msg: &str) {
+        let mut data = serde_json::json!({ "message": msg });
+        if let Some(group) = group {
+            data["groupInfo"] = serde_json::json!({ "groupId": group });
+        }
+        let envelope = serde_json::json!({
+            "envelope": { "source": source, "dataMessage": data }
+        });
+
+        let mut inbox = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.dir.path().join("signal-cli/inbox"))
+            .expect("open inbox failed!");
+        writeln!(inbox, "{}", envelope).expect("write inbox failed!");
+    }
+
+    /// Wait until the server sent `count` messages in total, and return them
+    fn sent(&self, count: usize) -> Vec<serde_json::Value> {
+        let path = self.dir.path().join("signal-cli/sent");
+        let start = Instant::now();
+        loop {
+            let sent: Vec<serde_json::Value> = fs::read_to_string(&path)
+                .unwrap_or_default()
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect();
+            if sent.len() >= count {
+                return sent;
+            }
+            if start.elapsed() > Duration::from_secs(10) {
+                panic!("Expected {} sent messages, got {:?}", count, sent);
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+    }
+}
+
+fn check_echo_session(server: &Server) {
+    server.receive("+1", None, "startapp echo");
+    assert_eq!(
+        serde_json::json!({
+            "recipient": "+1",
+            "message": "Started echo!",
+            "attachments": []
+        }),
+        server.sent(1)[0]
+    );
+
+    server.receive("+1", None, "hello there");
+    assert_eq!("hello there", server.sent(2)[1]["message"]);
+
+    server.receive("+1", None, "endapp");
+    assert_eq!("Stopped app", server.sent(3)[2]["message"]);
+}
+
+#[test]
+fn test_jsonrpc_backend() {
+    let server = Server::start("jsonrpc");
+    check_echo_session(&server);
+}
+
+#[test]
+fn test_dbus_backend() {
+    let server = Server::start("dbus");
+    check_echo_session(&server);
+}
+
+#[test]
+fn test_listapps() {
+    let server = Server::start("jsonrpc");
+    server.receive("+1", None, "listapps");
+    let msg = server.sent(1)[0]["message"].as_str().unwrap().to_string();
+    assert!(msg.contains("echo"), "{}", msg);
+    assert!(msg.contains("A simple echo app"), "{}", msg);
+}
+
+#[test]
+fn test_group_session() {
+    let server = Server::start("jsonrpc");
+    server.receive("+1", Some("abc="), "startapp echo");
+    server.receive("+2", Some("abc="), "hi all");
+    let sent = server.sent(2);
+    for msg in &sent {
+        assert_eq!("abc=", msg["groupId"]);
+    }
+    assert_eq!("hi all", sent[1]["message"]);
+}
+
+#[test]
+fn test_unregistered_account() {
+    let dir = TempDir::new("e2e").expect("create tempdir failed!");
+    let config = Server::config(dir.path(), "+0123");
+    let output = Command::new(env!("CARGO_BIN_EXE_signal-apps"))
+        .arg("--config")
+        .arg(config)
+        .output()
+        .expect("running server failed!");
+
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("+0123 is not registered"), "{}", stderr);
+}